@@ -0,0 +1,222 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::cmdline::DeviceSpec;
+
+/// How long to wait for a device to show up before giving up.
+/// This needs to be generous, because USB disks can take a while to enumerate.
+pub const DEVICE_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+
+/// The magic bytes at the start of a LUKS1 or LUKS2 header.
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+
+/// The parts of a LUKS header that can be used to identify a device.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LuksHeader {
+    pub version: u16,
+    pub uuid: String,
+    /// Only LUKS2 has labels.
+    pub label: Option<String>,
+}
+
+fn nul_terminated(buf: &[u8]) -> String {
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).to_string()
+}
+
+impl LuksHeader {
+    /// How many bytes from the start of the device are needed to parse the header.
+    pub const LENGTH: usize = 208;
+
+    /// Parse the binary header at the start of a LUKS device.
+    /// The UUID is at the same place for LUKS1 and LUKS2.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::LENGTH || !buf.starts_with(LUKS_MAGIC) {
+            return None;
+        }
+        let version = u16::from_be_bytes([buf[6], buf[7]]);
+        let uuid = nul_terminated(&buf[168..208]).to_lowercase();
+        let label = match version {
+            2 => Some(nul_terminated(&buf[24..72])).filter(|l| !l.is_empty()),
+            _ => None,
+        };
+        Some(Self {
+            version,
+            uuid,
+            label,
+        })
+    }
+
+    pub fn read(device: &Path) -> Option<Self> {
+        let mut buf = vec![0; Self::LENGTH];
+        std::fs::File::open(device)
+            .and_then(|mut f| f.read_exact(&mut buf))
+            .ok()?;
+        Self::parse(&buf)
+    }
+}
+
+/// Read a `KEY=value` line from the device's uevent file in sysfs.
+fn uevent_value(sysfs_dir: &Path, key: &str) -> Option<String> {
+    let uevent = std::fs::read_to_string(sysfs_dir.join("uevent")).ok()?;
+    uevent
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(str::to_string)
+}
+
+/// Look through every block device the kernel knows about, for when udev hasn't made the symlinks.
+fn scan_sysfs(spec: &DeviceSpec) -> Option<PathBuf> {
+    for entry in std::fs::read_dir(SYS_CLASS_BLOCK).ok()?.flatten() {
+        let name = entry.file_name();
+        let device = Path::new("/dev").join(&name);
+        let found = match spec {
+            DeviceSpec::Path(_) => false,
+            DeviceSpec::Uuid(uuid) => LuksHeader::read(&device).is_some_and(|h| &h.uuid == uuid),
+            DeviceSpec::Label(label) => {
+                LuksHeader::read(&device).is_some_and(|h| h.label.as_ref() == Some(label))
+            }
+            DeviceSpec::PartUuid(uuid) => {
+                uevent_value(&entry.path(), "PARTUUID").is_some_and(|u| u.to_lowercase() == *uuid)
+            }
+            DeviceSpec::PartLabel(label) => {
+                uevent_value(&entry.path(), "PARTNAME").is_some_and(|l| l == *label)
+            }
+        };
+        if found && device.exists() {
+            return Some(device);
+        }
+    }
+    None
+}
+
+/// A partition, as the kernel sees it.
+#[derive(Clone, Debug)]
+pub struct Partition {
+    /// The name of the device, like `nvme0n1p1`.
+    pub name: String,
+    /// The number of the partition in the partition table, starting at 1.
+    pub number: u32,
+    pub partuuid: Option<String>,
+    pub partname: Option<String>,
+    /// Where the partition starts, in logical blocks of the disk.
+    pub start: u64,
+    /// How long the partition is, in logical blocks of the disk.
+    pub size: u64,
+    /// How big a logical block of the disk is, in bytes.
+    pub block_size: u64,
+}
+
+fn read_sysfs_number(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+impl Partition {
+    /// Read the details of a partition from its directory in sysfs.
+    fn read(sysfs_dir: &Path) -> Option<Self> {
+        let number = read_sysfs_number(&sysfs_dir.join("partition"))? as u32;
+        // The start and size are always in 512-byte sectors,
+        // but the EFI device path needs them in the disk's logical blocks.
+        let block_size =
+            read_sysfs_number(&sysfs_dir.join("../queue/logical_block_size")).unwrap_or(512);
+        let sectors_per_block = block_size / 512;
+        Some(Self {
+            name: sysfs_dir.file_name()?.to_string_lossy().to_string(),
+            number,
+            partuuid: uevent_value(sysfs_dir, "PARTUUID").map(|u| u.to_lowercase()),
+            partname: uevent_value(sysfs_dir, "PARTNAME"),
+            start: read_sysfs_number(&sysfs_dir.join("start"))? / sectors_per_block,
+            size: read_sysfs_number(&sysfs_dir.join("size"))? / sectors_per_block,
+            block_size,
+        })
+    }
+}
+
+/// List all of the partitions on all disks.
+pub fn list_partitions() -> Vec<Partition> {
+    let Ok(entries) = std::fs::read_dir(SYS_CLASS_BLOCK) else {
+        return vec![];
+    };
+    let mut partitions: Vec<Partition> = entries
+        .flatten()
+        .filter_map(|entry| entry.path().canonicalize().ok())
+        .filter_map(|path| Partition::read(&path))
+        .collect();
+    partitions.sort_by(|a, b| a.name.cmp(&b.name));
+    partitions
+}
+
+/// Find the device node for this spec, if it exists right now.
+///
+/// The udev symlinks in `/dev/disk/by-*` are tried first.
+/// If those aren't there, the block devices in sysfs are checked directly,
+/// which only finds LUKS devices when looking for a UUID or label.
+pub fn resolve(spec: &DeviceSpec) -> Option<PathBuf> {
+    let path = spec.path();
+    if path.exists() {
+        return Some(path);
+    }
+    scan_sysfs(spec)
+}
+
+/// Wait until the device for this spec appears, or the timeout runs out.
+pub fn wait_for(spec: &DeviceSpec, timeout: Duration) -> Result<PathBuf, String> {
+    let start = Instant::now();
+    loop {
+        if let Some(path) = resolve(spec) {
+            return Ok(path);
+        }
+        if start.elapsed() > timeout {
+            return Err(format!(
+                "Device {spec} did not appear within {} seconds",
+                timeout.as_secs()
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LuksHeader;
+
+    fn header(version: u16, uuid: &str, label: &str) -> Vec<u8> {
+        let mut buf = vec![0; 4096];
+        buf[0..6].copy_from_slice(b"LUKS\xba\xbe");
+        buf[6..8].copy_from_slice(&version.to_be_bytes());
+        buf[24..24 + label.len()].copy_from_slice(label.as_bytes());
+        buf[168..168 + uuid.len()].copy_from_slice(uuid.as_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_luks2_header() {
+        let buf = header(2, "7537B139-51C6-48ED-A548-F035E0728638", "system");
+        assert_eq!(
+            LuksHeader::parse(&buf),
+            Some(LuksHeader {
+                version: 2,
+                uuid: "7537b139-51c6-48ed-a548-f035e0728638".to_string(),
+                label: Some("system".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_luks1_header_has_no_label() {
+        // In LUKS1, these bytes are part of the cipher name.
+        let buf = header(1, "7537b139-51c6-48ed-a548-f035e0728638", "aes");
+        assert_eq!(LuksHeader::parse(&buf).unwrap().label, None);
+    }
+
+    #[test]
+    fn test_not_luks() {
+        let buf = vec![0; 4096];
+        assert_eq!(LuksHeader::parse(&buf), None);
+    }
+}
@@ -0,0 +1,478 @@
+//! Boot entries described by the Boot Loader Specification on the ESP, which can be booted with kexec.
+//! See https://uapi-group.org/specifications/specs/boot_loader_specification/
+//!
+//! Type #1 entries are the `.conf` files in `/loader/entries`, and Type #2 entries are the UKIs in `/EFI/Linux`.
+//! Only the ESP is looked at, not an XBOOTLDR partition.
+
+use std::{cmp::Ordering, path::Path};
+
+use crate::{
+    esp,
+    kexec::{self, KernelKind, KexecTarget},
+    menu_config::{MenuAction, MenuEntry},
+};
+
+/// Where the Type #1 entries are, relative to the root of the ESP.
+const ENTRIES_DIR: &str = "loader/entries";
+
+/// Where the Type #2 entries are, relative to the root of the ESP.
+const UKI_DIR: &str = "EFI/Linux";
+
+/// The boot counter in the name of an entry file, like `arch+3-1.conf`.
+/// See https://uapi-group.org/specifications/specs/boot_loader_specification/#boot-counting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootCounter {
+    pub tries_left: u32,
+    pub tries_done: Option<u32>,
+}
+
+/// The keys of a Type #1 entry file that we use.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct EntryFile {
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub machine_id: Option<String>,
+    pub sort_key: Option<String>,
+    pub linux: Option<String>,
+    /// There can be several of these, which are loaded one after the other.
+    pub initrds: Vec<String>,
+    /// There can be several of these too, which are joined with spaces.
+    pub options: Vec<String>,
+    /// An EFI program to start instead of a kernel, which can be booted if it is a UKI.
+    pub efi: Option<String>,
+}
+
+/// A boot entry, of either type.
+#[derive(Clone, Debug)]
+pub struct BootLoaderEntry {
+    /// The file name without the boot counter and extension.
+    pub id: String,
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub machine_id: Option<String>,
+    pub sort_key: Option<String>,
+    pub counter: Option<BootCounter>,
+    pub target: KexecTarget,
+}
+
+/// Split the boot counter off the name of an entry file without its extension.
+pub fn split_boot_counter(stem: &str) -> (&str, Option<BootCounter>) {
+    let Some((id, counter)) = stem.rsplit_once('+') else {
+        return (stem, None);
+    };
+    let (left, done) = match counter.split_once('-') {
+        Some((left, done)) => (left, Some(done)),
+        None => (counter, None),
+    };
+    let tries_left = left.parse().ok();
+    let tries_done = done.map(|done| done.parse().ok());
+    match (tries_left, tries_done) {
+        (Some(tries_left), None) => (
+            id,
+            Some(BootCounter {
+                tries_left,
+                tries_done: None,
+            }),
+        ),
+        (Some(tries_left), Some(Some(tries_done))) => (
+            id,
+            Some(BootCounter {
+                tries_left,
+                tries_done: Some(tries_done),
+            }),
+        ),
+        // A `+` that isn't followed by numbers is just part of the name.
+        _ => (stem, None),
+    }
+}
+
+/// Parse the content of a Type #1 entry file.
+/// Each line is a key, whitespace, and a value; comments start with `#`.
+pub fn parse_entry(text: &str) -> EntryFile {
+    let mut entry = EntryFile::default();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once(|c: char| c.is_whitespace())
+            .unwrap_or((line, ""));
+        let value = value.trim().to_string();
+        match key {
+            "title" => entry.title = Some(value),
+            "version" => entry.version = Some(value),
+            "machine-id" => entry.machine_id = Some(value),
+            "sort-key" => entry.sort_key = Some(value),
+            "linux" => entry.linux = Some(value),
+            "initrd" => entry.initrds.push(value),
+            "options" => entry.options.push(value),
+            "efi" => entry.efi = Some(value),
+            _ => {}
+        }
+    }
+    entry
+}
+
+/// Compare two versions like `6.6.10-arch1-1` and `6.6.9-arch1-1`, with numbers compared as numbers.
+/// This is simpler than the comparison systemd uses, but it orders kernel versions the same way.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn chunks(text: &str) -> Vec<&str> {
+        let mut chunks = vec![];
+        let mut start = 0;
+        for (index, c) in text.char_indices().skip(1) {
+            let previous = text[..index].chars().next_back().unwrap();
+            if c.is_ascii_digit() != previous.is_ascii_digit() {
+                chunks.push(&text[start..index]);
+                start = index;
+            }
+        }
+        if start < text.len() {
+            chunks.push(&text[start..]);
+        }
+        chunks
+    }
+    for (a, b) in chunks(a).into_iter().zip(chunks(b)) {
+        let order = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    chunks(a).len().cmp(&chunks(b).len())
+}
+
+impl BootLoaderEntry {
+    /// The text shown in the menu.
+    pub fn label(&self) -> String {
+        let mut label = self.title.clone().unwrap_or_else(|| self.id.clone());
+        if let Some(version) = &self.version {
+            label.push_str(&format!(" ({version})"));
+        }
+        if self.is_bad() {
+            label.push_str(" [no tries left]");
+        }
+        label
+    }
+
+    /// Whether the boot counter of this entry has run out, meaning that it failed to boot every time it was tried.
+    pub fn is_bad(&self) -> bool {
+        self.counter.is_some_and(|counter| counter.tries_left == 0)
+    }
+
+    /// Build an entry from a Type #1 entry file.
+    /// Returns None if it doesn't say which kernel to start.
+    pub fn from_entry_file(
+        id: &str,
+        counter: Option<BootCounter>,
+        file: EntryFile,
+    ) -> Option<Self> {
+        let target = match (file.linux, file.efi) {
+            (Some(linux), _) => KexecTarget {
+                path: esp::esp_relative_path(&linux).to_string_lossy().to_string(),
+                kind: KernelKind::Entry {
+                    initrds: file.initrds,
+                    options: file.options.join(" "),
+                },
+                os_name: file.title.clone(),
+                uname: file.version.clone(),
+                cmdline: None,
+            },
+            (None, Some(efi)) => KexecTarget {
+                path: esp::esp_relative_path(&efi).to_string_lossy().to_string(),
+                kind: KernelKind::Uki,
+                os_name: file.title.clone(),
+                uname: file.version.clone(),
+                cmdline: None,
+            },
+            (None, None) => return None,
+        };
+        Some(Self {
+            id: id.to_string(),
+            title: file.title,
+            version: file.version,
+            machine_id: file.machine_id,
+            sort_key: file.sort_key,
+            counter,
+            target,
+        })
+    }
+}
+
+/// Sort the entries the way the specification says boot loaders should show them:
+/// the ones with a sort key first, ordered by sort key, machine ID and then newest version first,
+/// then the others, newest first by their ID. The ones with no tries left go last.
+pub fn sort_entries(entries: &mut [BootLoaderEntry]) {
+    entries.sort_by(|a, b| {
+        a.is_bad()
+            .cmp(&b.is_bad())
+            .then_with(|| match (&a.sort_key, &b.sort_key) {
+                (Some(a_key), Some(b_key)) => a_key
+                    .cmp(b_key)
+                    .then_with(|| a.machine_id.cmp(&b.machine_id))
+                    .then_with(|| {
+                        compare_versions(
+                            b.version.as_deref().unwrap_or(""),
+                            a.version.as_deref().unwrap_or(""),
+                        )
+                    }),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+            .then_with(|| compare_versions(&b.id, &a.id))
+    });
+}
+
+fn list_dir(dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+fn type1_entries(root: &Path) -> Vec<BootLoaderEntry> {
+    let dir = root.join(ENTRIES_DIR);
+    let mut entries = vec![];
+    for name in list_dir(&dir) {
+        let Some(stem) = name.strip_suffix(".conf") else {
+            continue;
+        };
+        let Ok(text) = std::fs::read_to_string(dir.join(&name)) else {
+            continue;
+        };
+        let (id, counter) = split_boot_counter(stem);
+        entries.extend(BootLoaderEntry::from_entry_file(
+            id,
+            counter,
+            parse_entry(&text),
+        ));
+    }
+    entries
+}
+
+fn type2_entries(root: &Path) -> Vec<BootLoaderEntry> {
+    let dir = root.join(UKI_DIR);
+    let mut entries = vec![];
+    for name in list_dir(&dir) {
+        if !name.to_lowercase().ends_with(".efi") {
+            continue;
+        }
+        let Some(info) = kexec::read_uki_info(&dir.join(&name)) else {
+            continue;
+        };
+        let (id, counter) = split_boot_counter(&name[..name.len() - 4]);
+        let os_release = info.os_release.unwrap_or_default();
+        let title = kexec::parse_os_release(&os_release);
+        // This is what systemd-boot uses for these.
+        let sort_key = kexec::os_release_value(&os_release, "IMAGE_ID")
+            .or_else(|| kexec::os_release_value(&os_release, "ID"));
+        let version = info
+            .uname
+            .clone()
+            .or_else(|| kexec::os_release_value(&os_release, "VERSION_ID"));
+        entries.push(BootLoaderEntry {
+            id: id.to_string(),
+            title: title.clone(),
+            version: version.clone(),
+            machine_id: None,
+            sort_key,
+            counter,
+            target: KexecTarget {
+                path: Path::new(UKI_DIR).join(&name).to_string_lossy().to_string(),
+                kind: KernelKind::Uki,
+                os_name: title,
+                uname: version,
+                cmdline: None,
+            },
+        });
+    }
+    entries
+}
+
+/// Find all the entries on the ESP, in the order they should be shown.
+pub fn scan() -> Result<Vec<BootLoaderEntry>, String> {
+    esp::with_esp(|root| {
+        let mut entries = type1_entries(root);
+        entries.extend(type2_entries(root));
+        sort_entries(&mut entries);
+        entries
+    })
+}
+
+/// Replace the `boot_loader_entries` placeholders in the menu with these entries, including in submenus.
+/// The placeholders are kept if there are no entries, so that choosing them can say so.
+pub fn replace_placeholders(menu: &mut Vec<MenuEntry>, found: &[BootLoaderEntry]) {
+    let mut index = 0;
+    while index < menu.len() {
+        match &mut menu[index].action {
+            MenuAction::BootLoaderEntries if !found.is_empty() => {
+                let replacement = found.iter().map(|entry| MenuEntry {
+                    label: entry.label(),
+                    action: MenuAction::BootLoaderEntry {
+                        target: entry.target.clone(),
+                    },
+                    requires_auth: true,
+                });
+                menu.splice(index..index + 1, replacement);
+                index += found.len();
+            }
+            MenuAction::Submenu { entries } => {
+                replace_placeholders(entries, found);
+                index += 1;
+            }
+            _ => index += 1,
+        }
+    }
+}
+
+/// Whether the menu has a `boot_loader_entries` placeholder anywhere, so that the ESP needs to be scanned.
+pub fn has_placeholders(menu: &[MenuEntry]) -> bool {
+    menu.iter().any(|entry| match &entry.action {
+        MenuAction::BootLoaderEntries => true,
+        MenuAction::Submenu { entries } => has_placeholders(entries),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        compare_versions, parse_entry, replace_placeholders, sort_entries, split_boot_counter,
+        BootCounter, BootLoaderEntry,
+    };
+    use crate::{kexec::KernelKind, menu_config::MenuConfig};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn test_split_boot_counter() {
+        assert_eq!(split_boot_counter("arch"), ("arch", None));
+        assert_eq!(
+            split_boot_counter("arch+3"),
+            (
+                "arch",
+                Some(BootCounter {
+                    tries_left: 3,
+                    tries_done: None
+                })
+            )
+        );
+        assert_eq!(
+            split_boot_counter("arch+0-2"),
+            (
+                "arch",
+                Some(BootCounter {
+                    tries_left: 0,
+                    tries_done: Some(2)
+                })
+            )
+        );
+        assert_eq!(split_boot_counter("c++"), ("c++", None));
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let file = parse_entry(
+            "# Written by kernel-install\n\
+             title      Arch Linux\n\
+             version    6.6.10-arch1-1\n\
+             sort-key   arch\n\
+             linux      /vmlinuz-linux\n\
+             initrd     /intel-ucode.img\n\
+             initrd     /initramfs-linux.img\n\
+             options    root=UUID=1234 rw\n\
+             options    quiet\n",
+        );
+        assert_eq!(file.title.as_deref(), Some("Arch Linux"));
+        assert_eq!(file.sort_key.as_deref(), Some("arch"));
+        assert_eq!(
+            file.initrds,
+            vec!["/intel-ucode.img", "/initramfs-linux.img"]
+        );
+
+        let entry = BootLoaderEntry::from_entry_file("arch", None, file).unwrap();
+        assert_eq!(entry.label(), "Arch Linux (6.6.10-arch1-1)");
+        assert_eq!(entry.target.path, "vmlinuz-linux");
+        assert_eq!(
+            entry.target.kind,
+            KernelKind::Entry {
+                initrds: vec![
+                    "/intel-ucode.img".to_string(),
+                    "/initramfs-linux.img".to_string()
+                ],
+                options: "root=UUID=1234 rw quiet".to_string()
+            }
+        );
+
+        assert!(BootLoaderEntry::from_entry_file("empty", None, parse_entry("title x")).is_none());
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(
+            compare_versions("6.6.10-arch1-1", "6.6.9-arch1-1"),
+            Ordering::Greater
+        );
+        assert_eq!(compare_versions("6.1", "6.1.1"), Ordering::Less);
+        assert_eq!(compare_versions("lts", "lts"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_sort_entries() {
+        let entry = |id: &str, sort_key: Option<&str>, version: &str, tries_left: Option<u32>| {
+            let mut entry = BootLoaderEntry::from_entry_file(
+                id,
+                tries_left.map(|tries_left| BootCounter {
+                    tries_left,
+                    tries_done: None,
+                }),
+                parse_entry("linux /vmlinuz"),
+            )
+            .unwrap();
+            entry.sort_key = sort_key.map(str::to_string);
+            entry.version = Some(version.to_string());
+            entry
+        };
+        let mut entries = vec![
+            entry("other-1", None, "1", None),
+            entry("arch-old", Some("arch"), "6.6.9", None),
+            entry("arch-bad", Some("arch"), "6.7.0", Some(0)),
+            entry("other-2", None, "1", None),
+            entry("arch-new", Some("arch"), "6.6.10", Some(2)),
+        ];
+        sort_entries(&mut entries);
+        let ids: Vec<&str> = entries.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec!["arch-new", "arch-old", "other-2", "other-1", "arch-bad"]
+        );
+    }
+
+    #[test]
+    fn test_replace_placeholders() {
+        let mut menu = MenuConfig::builtin().entries;
+        let found = vec![BootLoaderEntry::from_entry_file(
+            "arch",
+            None,
+            parse_entry("title Arch Linux\nlinux /vmlinuz-linux"),
+        )
+        .unwrap()];
+
+        let before = menu.len();
+        replace_placeholders(&mut menu, &[]);
+        assert_eq!(menu.len(), before);
+
+        replace_placeholders(&mut menu, &found);
+        assert_eq!(menu.len(), before);
+        assert!(menu
+            .iter()
+            .any(|entry| entry.label == "Arch Linux" && entry.requires_auth));
+    }
+}
@@ -0,0 +1,533 @@
+use std::{fmt::Display, io::Write};
+
+use serde::Deserialize;
+
+use crate::{
+    block_device::Partition,
+    device_path::{
+        encode_file_path_list, parse_file_path_list, parse_guid, DeviceNode, DevicePath,
+        PartitionSignature,
+    },
+    efivarfs::{self, DEFAULT_ATTRIBUTES, EFI_GLOBAL_VARIABLE},
+};
+
+/// The load option should be shown and booted by the firmware.
+pub const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+/// One of the `Boot####` load options.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootOption {
+    /// The `####` in the variable name.
+    pub id: u16,
+    pub attributes: u32,
+    pub description: String,
+    /// Where the firmware loads this option from.
+    /// This is usually a single path, like `HD(...)/File(\EFI\...)`.
+    pub file_path_list: Vec<DevicePath>,
+}
+
+fn utf16_nul_terminated(buf: &[u8]) -> Option<(String, &[u8])> {
+    let mut chars = vec![];
+    for (index, c) in buf.chunks_exact(2).enumerate() {
+        match u16::from_le_bytes([c[0], c[1]]) {
+            0 => return Some((String::from_utf16_lossy(&chars), &buf[(index + 1) * 2..])),
+            c => chars.push(c),
+        }
+    }
+    None
+}
+
+impl BootOption {
+    pub fn is_active(&self) -> bool {
+        self.attributes & LOAD_OPTION_ACTIVE != 0
+    }
+
+    /// The device paths of this option as text, one per line.
+    pub fn paths_text(&self) -> String {
+        self.file_path_list
+            .iter()
+            .map(DevicePath::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Encode an EFI_LOAD_OPTION with no optional data.
+    pub fn encode(&self) -> Vec<u8> {
+        let file_path_list = encode_file_path_list(&self.file_path_list);
+        let mut buf = self.attributes.to_le_bytes().to_vec();
+        buf.extend_from_slice(&(file_path_list.len() as u16).to_le_bytes());
+        for c in self.description.encode_utf16().chain([0]) {
+            buf.extend_from_slice(&c.to_le_bytes());
+        }
+        buf.extend(file_path_list);
+        buf
+    }
+
+    /// Parse an EFI_LOAD_OPTION.
+    /// The optional data after the file path list is ignored.
+    /// See https://uefi.org/specs/UEFI/2.10/03_Boot_Manager.html#load-options
+    pub fn parse(id: u16, buf: &[u8]) -> Result<Self, String> {
+        if buf.len() < 6 {
+            return Err(format!("Boot{id:04X} is too short"));
+        }
+        let attributes = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let file_path_list_length = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+        let (description, rest) = utf16_nul_terminated(&buf[6..])
+            .ok_or(format!("Boot{id:04X} has an unterminated description"))?;
+        let file_path_list = rest
+            .get(..file_path_list_length)
+            .ok_or(format!("Boot{id:04X} has a truncated file path list"))?;
+        let file_path_list =
+            parse_file_path_list(file_path_list).map_err(|why| format!("Boot{id:04X}: {why}"))?;
+        Ok(Self {
+            id,
+            attributes,
+            description,
+            file_path_list,
+        })
+    }
+}
+
+/// A description of which EFI boot entry to pick. Every field that is set has to match.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct BootEntryMatch {
+    /// The description of the entry, exactly.
+    /// This changes with the firmware's language and when the entry is renamed,
+    /// so `loader` is more reliable.
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// The path of the EFI program the entry loads, like `\EFI\Microsoft\Boot\bootmgfw.efi`.
+    /// This is compared ignoring case, since the ESP is FAT.
+    #[serde(default)]
+    pub loader: Option<String>,
+
+    /// The unique GUID of the GPT partition the loader is on, which is its PARTUUID.
+    /// This tells apart entries with the same loader on different disks.
+    #[serde(default)]
+    pub partition_guid: Option<String>,
+}
+
+/// Make EFI paths comparable: backslashes, a leading backslash, and no case.
+fn normalize_efi_path(path: &str) -> String {
+    let path = path.replace('/', "\\").to_lowercase();
+    if path.starts_with('\\') {
+        path
+    } else {
+        format!("\\{path}")
+    }
+}
+
+impl BootEntryMatch {
+    pub fn matches(&self, option: &BootOption) -> bool {
+        if let Some(description) = &self.description {
+            if &option.description != description {
+                return false;
+            }
+        }
+        if self.loader.is_none() && self.partition_guid.is_none() {
+            return true;
+        }
+
+        // Both have to match the same device path, not just any of them.
+        let path_matches = |path: &DevicePath| {
+            let loader_matches = self.loader.as_ref().is_none_or(|loader| {
+                path.file_path()
+                    .is_some_and(|file| normalize_efi_path(&file) == normalize_efi_path(loader))
+            });
+            let partition_matches = self.partition_guid.as_ref().is_none_or(|guid| {
+                path.partition_guid()
+                    .is_some_and(|g| g.eq_ignore_ascii_case(guid))
+            });
+            loader_matches && partition_matches
+        };
+        option.file_path_list.iter().any(path_matches)
+    }
+}
+
+impl Display for BootEntryMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(description) = &self.description {
+            parts.push(format!("\"{description}\""));
+        }
+        if let Some(loader) = &self.loader {
+            parts.push(loader.clone());
+        }
+        if let Some(guid) = &self.partition_guid {
+            parts.push(format!("on partition {guid}"));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Get the number out of a `Boot####` variable name.
+fn boot_option_id(name: &str) -> Option<u16> {
+    let hex = name.strip_prefix("Boot")?;
+    if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u16::from_str_radix(hex, 16).ok()
+}
+
+/// Parse the BootOrder variable into the IDs of the load options.
+pub fn parse_boot_order(buf: &[u8]) -> Result<Vec<u16>, String> {
+    if buf.len() % 2 == 1 {
+        return Err(format!(
+            "EFI boot entries list has length {}, which is not allowed.",
+            buf.len()
+        ));
+    }
+    Ok(buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect())
+}
+
+/// List all of the `Boot####` load options.
+/// The ones in BootOrder come first, in that order, then the rest by their number.
+pub fn list_boot_options() -> Result<Vec<BootOption>, String> {
+    let mut ids = read_boot_order()?;
+    let mut others: Vec<u16> = efivarfs::list(EFI_GLOBAL_VARIABLE)?
+        .iter()
+        .filter_map(|name| boot_option_id(name))
+        .filter(|id| !ids.contains(id))
+        .collect();
+    others.sort();
+    ids.extend(others);
+
+    let values = ids
+        .into_iter()
+        .map(|id| {
+            let value = efivarfs::read(&format!("Boot{id:04X}"), EFI_GLOBAL_VARIABLE)
+                .map(|(_, value)| value);
+            (id, value)
+        })
+        .collect();
+    let (options, skipped) = parse_boot_options(values);
+    // The menu is on the screen, so this goes to the kernel log instead of stdout.
+    if let Ok(mut kmsg) = std::fs::OpenOptions::new().write(true).open("/dev/kmsg") {
+        for why in skipped {
+            let _ = writeln!(kmsg, "<5>boot-menu: {why}");
+        }
+    }
+    Ok(options)
+}
+
+/// Parse the values of the `Boot####` variables.
+/// The ones that can't be read or parsed, like some vendor-specific entries, are skipped,
+/// so that they don't hide all of the others; why they were skipped is returned too.
+pub fn parse_boot_options(
+    values: Vec<(u16, Result<Vec<u8>, String>)>,
+) -> (Vec<BootOption>, Vec<String>) {
+    let mut options = vec![];
+    let mut skipped = vec![];
+    for (id, value) in values {
+        match value.and_then(|value| BootOption::parse(id, &value)) {
+            Ok(option) => options.push(option),
+            Err(why) => skipped.push(format!("Skipping Boot{id:04X}: {why}")),
+        }
+    }
+    (options, skipped)
+}
+
+/// Check whether the description matches any of these patterns.
+/// A pattern matches if it is contained in the description, ignoring case.
+fn matches_any(description: &str, patterns: &[String]) -> bool {
+    let description = description.to_lowercase();
+    patterns
+        .iter()
+        .any(|pattern| description.contains(&pattern.to_lowercase()))
+}
+
+/// Keep only the active load options that should be offered as boot targets.
+/// If `include` is empty, everything is included; then, everything that matches `exclude` is removed.
+pub fn filter_boot_options(
+    options: Vec<BootOption>,
+    include: &[String],
+    exclude: &[String],
+) -> Vec<BootOption> {
+    options
+        .into_iter()
+        .filter(BootOption::is_active)
+        .filter(|o| include.is_empty() || matches_any(&o.description, include))
+        .filter(|o| !matches_any(&o.description, exclude))
+        .collect()
+}
+
+/// Make the firmware boot this load option on the next boot only.
+pub fn set_boot_next(id: u16) -> Result<(), String> {
+    efivarfs::write(
+        "BootNext",
+        EFI_GLOBAL_VARIABLE,
+        DEFAULT_ATTRIBUTES,
+        &id.to_le_bytes(),
+    )
+}
+
+pub fn read_boot_order() -> Result<Vec<u16>, String> {
+    let (_, boot_order) = efivarfs::read("BootOrder", EFI_GLOBAL_VARIABLE)?;
+    parse_boot_order(&boot_order)
+}
+
+pub fn write_boot_order(order: &[u16]) -> Result<(), String> {
+    let value: Vec<u8> = order.iter().flat_map(|id| id.to_le_bytes()).collect();
+    efivarfs::write("BootOrder", EFI_GLOBAL_VARIABLE, DEFAULT_ATTRIBUTES, &value)
+}
+
+/// Move an entry one place up or down in the boot order.
+/// An entry that isn't in the boot order yet is added to the end.
+pub fn move_in_order(mut order: Vec<u16>, id: u16, up: bool) -> Vec<u16> {
+    let Some(index) = order.iter().position(|i| *i == id) else {
+        order.push(id);
+        return order;
+    };
+    if up && index > 0 {
+        order.swap(index, index - 1);
+    } else if !up && index + 1 < order.len() {
+        order.swap(index, index + 1);
+    }
+    order
+}
+
+/// Set or clear LOAD_OPTION_ACTIVE of an entry.
+/// Only the attributes are changed, so that the rest of the entry, including its optional data, stays as it was.
+pub fn set_active(id: u16, active: bool) -> Result<(), String> {
+    let name = format!("Boot{id:04X}");
+    let (var_attributes, mut value) = efivarfs::read(&name, EFI_GLOBAL_VARIABLE)?;
+    if value.len() < 4 {
+        return Err(format!("{name} is too short"));
+    }
+    let mut attributes = u32::from_le_bytes(value[..4].try_into().unwrap());
+    if active {
+        attributes |= LOAD_OPTION_ACTIVE;
+    } else {
+        attributes &= !LOAD_OPTION_ACTIVE;
+    }
+    value[..4].copy_from_slice(&attributes.to_le_bytes());
+    efivarfs::write(&name, EFI_GLOBAL_VARIABLE, var_attributes, &value)
+}
+
+/// Delete an entry, and remove it from the boot order.
+pub fn delete_boot_option(id: u16) -> Result<(), String> {
+    let order: Vec<u16> = read_boot_order()?
+        .into_iter()
+        .filter(|i| *i != id)
+        .collect();
+    write_boot_order(&order)?;
+    efivarfs::delete(&format!("Boot{id:04X}"), EFI_GLOBAL_VARIABLE)
+}
+
+/// Create an active entry that loads this file from a GPT partition, without adding it to the boot order.
+/// Returns the number of the new entry.
+pub fn write_new_boot_option(
+    description: &str,
+    partition: &Partition,
+    loader: &str,
+) -> Result<u16, String> {
+    let partuuid = partition
+        .partuuid
+        .clone()
+        .filter(|uuid| parse_guid(uuid).is_some())
+        .ok_or(format!("{} is not a GPT partition", partition.name))?;
+    let mut loader = loader.replace('/', "\\");
+    if !loader.starts_with('\\') {
+        loader.insert(0, '\\');
+    }
+
+    let existing = efivarfs::list(EFI_GLOBAL_VARIABLE)?;
+    let id = (0..=u16::MAX)
+        .find(|id| !existing.contains(&format!("Boot{id:04X}")))
+        .ok_or("There are no free boot entry numbers")?;
+
+    let option = BootOption {
+        id,
+        attributes: LOAD_OPTION_ACTIVE,
+        description: description.to_string(),
+        file_path_list: vec![DevicePath(vec![
+            DeviceNode::HardDrive {
+                partition: partition.number,
+                start: partition.start,
+                size: partition.size,
+                signature: PartitionSignature::Gpt(partuuid),
+            },
+            DeviceNode::FilePath(loader),
+        ])],
+    };
+    efivarfs::write(
+        &format!("Boot{id:04X}"),
+        EFI_GLOBAL_VARIABLE,
+        DEFAULT_ATTRIBUTES,
+        &option.encode(),
+    )?;
+    Ok(id)
+}
+
+/// Create an active entry that loads this file from a GPT partition, and add it to the end of the boot order.
+/// Returns the number of the new entry.
+pub fn create_boot_option(
+    description: &str,
+    partition: &Partition,
+    loader: &str,
+) -> Result<u16, String> {
+    let mut order = read_boot_order()?;
+    let id = write_new_boot_option(description, partition, loader)?;
+    order.push(id);
+    write_boot_order(&order)?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        boot_option_id, filter_boot_options, move_in_order, parse_boot_options, parse_boot_order,
+        BootEntryMatch, BootOption,
+    };
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// A hand-built Boot0000 in the shape Windows creates it, with a made-up partition GUID.
+    const WINDOWS_BOOT_MANAGER: &str = "010000007400570069006e0064006f0077007300200042006f006f00740020004d0061006e006100670065007200000004012a000100000000080000000000000020030000000000f8d3a1c45e2f8b4b9d3a5e3c1a7b2f100202040446005c004500460049005c004d006900630072006f0073006f00660074005c0042006f006f0074005c0062006f006f0074006d006700660077002e0065006600690000007fff040057494e444f57530001000000";
+
+    /// A hand-built entry for a removable USB disk, in the shape firmware adds it.
+    const USB_DISK: &str = "090100001c0055004500460049003a00200055005300420020004400690073006b00000002010c00d041030a000000000101060000140305060003007fff0400";
+
+    #[test]
+    fn test_parse_windows_boot_manager() {
+        let option = BootOption::parse(0, &unhex(WINDOWS_BOOT_MANAGER)).unwrap();
+        assert_eq!(option.description, "Windows Boot Manager");
+        assert!(option.is_active());
+        assert_eq!(
+            option.paths_text(),
+            "HD(1,GPT,c4a1d3f8-2f5e-4b8b-9d3a-5e3c1a7b2f10,0x800,0x32000)/File(\\EFI\\Microsoft\\Boot\\bootmgfw.efi)"
+        );
+    }
+
+    #[test]
+    fn test_parse_usb_disk() {
+        let option = BootOption::parse(7, &unhex(USB_DISK)).unwrap();
+        assert_eq!(option.description, "UEFI: USB Disk");
+        assert_eq!(option.attributes, 0x109);
+        assert_eq!(
+            option.paths_text(),
+            "PciRoot(0x0)/Pci(0x14,0x0)/USB(0x3,0x0)"
+        );
+    }
+
+    #[test]
+    fn test_match_by_loader() {
+        let option = BootOption::parse(0, &unhex(WINDOWS_BOOT_MANAGER)).unwrap();
+        let windows = BootEntryMatch {
+            loader: Some("/efi/microsoft/boot/BOOTMGFW.EFI".to_string()),
+            ..Default::default()
+        };
+        assert!(windows.matches(&option));
+
+        let pinned = BootEntryMatch {
+            partition_guid: Some("C4A1D3F8-2F5E-4B8B-9D3A-5E3C1A7B2F10".to_string()),
+            ..windows.clone()
+        };
+        assert!(pinned.matches(&option));
+
+        let other_disk = BootEntryMatch {
+            partition_guid: Some("00000000-2f5e-4b8b-9d3a-5e3c1a7b2f10".to_string()),
+            ..windows
+        };
+        assert!(!other_disk.matches(&option));
+
+        let usb = BootOption::parse(7, &unhex(USB_DISK)).unwrap();
+        let localized = BootEntryMatch {
+            description: Some("Gestionnaire de démarrage Windows".to_string()),
+            ..Default::default()
+        };
+        assert!(!localized.matches(&option));
+        assert!(!localized.matches(&usb));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let blob = unhex(USB_DISK);
+        let option = BootOption::parse(7, &blob).unwrap();
+        assert_eq!(option.encode(), blob);
+    }
+
+    #[test]
+    fn test_move_in_order() {
+        assert_eq!(move_in_order(vec![1, 2, 3], 2, true), vec![2, 1, 3]);
+        assert_eq!(move_in_order(vec![1, 2, 3], 1, true), vec![1, 2, 3]);
+        assert_eq!(move_in_order(vec![1, 2, 3], 2, false), vec![1, 3, 2]);
+        assert_eq!(move_in_order(vec![1, 2, 3], 3, false), vec![1, 2, 3]);
+        assert_eq!(move_in_order(vec![1, 2], 7, true), vec![1, 2, 7]);
+    }
+
+    #[test]
+    fn test_truncated_load_option() {
+        let blob = unhex(WINDOWS_BOOT_MANAGER);
+        assert!(BootOption::parse(0, &blob[..60]).is_err());
+    }
+
+    #[test]
+    fn test_skip_bad_options() {
+        let blob = unhex(WINDOWS_BOOT_MANAGER);
+        let (options, skipped) = parse_boot_options(vec![
+            (0, Ok(blob.clone())),
+            (1, Ok(blob[..60].to_vec())),
+            (2, Err("Permission denied".to_string())),
+            (7, Ok(unhex(USB_DISK))),
+        ]);
+        let ids: Vec<u16> = options.iter().map(|option| option.id).collect();
+        assert_eq!(ids, vec![0, 7]);
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped[0].starts_with("Skipping Boot0001: "));
+        assert_eq!(skipped[1], "Skipping Boot0002: Permission denied");
+    }
+
+    #[test]
+    fn test_boot_order_and_names() {
+        assert_eq!(parse_boot_order(&[3, 0, 0x10, 0]).unwrap(), vec![3, 0x10]);
+        assert!(parse_boot_order(&[3]).is_err());
+        assert_eq!(boot_option_id("Boot001A"), Some(0x1a));
+        assert_eq!(boot_option_id("BootOrder"), None);
+        assert_eq!(boot_option_id("BootNext"), None);
+    }
+
+    #[test]
+    fn test_filter() {
+        let options: Vec<BootOption> = [
+            (1, "Windows Boot Manager"),
+            (1, "Linux Boot Manager"),
+            (0, "Old Linux"),
+            (1, "UEFI PXEv4 (MAC:001122334455)"),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(id, (attributes, description))| BootOption {
+            id: id as u16,
+            attributes,
+            description: description.to_string(),
+            file_path_list: vec![],
+        })
+        .collect();
+
+        let names = |options: Vec<BootOption>| -> Vec<String> {
+            options.into_iter().map(|o| o.description).collect()
+        };
+        assert_eq!(
+            names(filter_boot_options(
+                options.clone(),
+                &[],
+                &["pxe".to_string()]
+            )),
+            vec!["Windows Boot Manager", "Linux Boot Manager"]
+        );
+        assert_eq!(
+            names(filter_boot_options(options, &["linux".to_string()], &[])),
+            vec!["Linux Boot Manager"]
+        );
+    }
+}
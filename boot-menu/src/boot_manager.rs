@@ -0,0 +1,228 @@
+use cursive::{view::Nameable, views, Cursive};
+
+use crate::{
+    block_device::{list_partitions, Partition},
+    boot_entries::{
+        create_boot_option, delete_boot_option, list_boot_options, move_in_order, read_boot_order,
+        set_active, write_boot_order, BootOption,
+    },
+    device_path::parse_guid,
+    esp,
+};
+
+/// Where new entries point by default, if the running image's path isn't known.
+const DEFAULT_LOADER: &str = "\\EFI\\Linux\\arch-linux.efi";
+
+/// This function shows the EFI boot entries, and lets the user change them.
+pub fn boot_manager(siv: &mut Cursive) {
+    show_entries(siv, None);
+}
+
+fn details_text(option: &BootOption, order: &[u16]) -> String {
+    let position = match order.iter().position(|id| *id == option.id) {
+        Some(index) => format!("position {} in BootOrder", index + 1),
+        None => "not in BootOrder".to_string(),
+    };
+    let state = if option.is_active() {
+        "active"
+    } else {
+        "inactive"
+    };
+    format!(
+        "Boot{:04X}, {state}, {position}\n{}",
+        option.id,
+        option.paths_text()
+    )
+}
+
+fn show_entries(siv: &mut Cursive, selected: Option<u16>) {
+    let options = match list_boot_options() {
+        Ok(o) => o,
+        Err(why) => {
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(format!(
+                    "Listing EFI boot entries failed: {why}"
+                )))
+                .dismiss_button("Return to menu"),
+            );
+            return;
+        }
+    };
+    let order = read_boot_order().unwrap_or_default();
+
+    let mut select = views::SelectView::new();
+    for option in &options {
+        let mark = if option.is_active() { "[x]" } else { "[ ]" };
+        select.add_item(
+            format!("{mark} Boot{:04X} {}", option.id, option.description),
+            option.clone(),
+        );
+    }
+    let selected_index = selected
+        .and_then(|id| options.iter().position(|o| o.id == id))
+        .unwrap_or(0);
+    select.set_selection(selected_index);
+    let details = options
+        .get(selected_index)
+        .map(|option| details_text(option, &order))
+        .unwrap_or_default();
+    select.set_on_select(move |siv, option| {
+        let text = details_text(option, &order);
+        siv.call_on_name("boot_manager_details", |view: &mut views::TextView| {
+            view.set_content(text)
+        });
+    });
+
+    siv.add_layer(
+        views::Dialog::around(
+            views::LinearLayout::vertical()
+                .child(select.with_name("boot_manager_list"))
+                .child(views::DummyView)
+                .child(views::TextView::new(details).with_name("boot_manager_details")),
+        )
+        .title("EFI boot entries")
+        .button("Up", |siv| {
+            change_selected(siv, |option| {
+                write_boot_order(&move_in_order(read_boot_order()?, option.id, true))
+            })
+        })
+        .button("Down", |siv| {
+            change_selected(siv, |option| {
+                write_boot_order(&move_in_order(read_boot_order()?, option.id, false))
+            })
+        })
+        .button("Toggle active", |siv| {
+            change_selected(siv, |option| set_active(option.id, !option.is_active()))
+        })
+        .button("Delete", confirm_delete)
+        .button("New", choose_partition)
+        .dismiss_button("Back"),
+    );
+}
+
+fn selected_option(siv: &mut Cursive) -> Option<BootOption> {
+    siv.call_on_name(
+        "boot_manager_list",
+        |view: &mut views::SelectView<BootOption>| view.selection(),
+    )
+    .flatten()
+    .map(|option| (*option).clone())
+}
+
+/// Apply a change to the selected entry, then show the entries again,
+/// so that the screen shows what is really in the variables now.
+fn change_selected(siv: &mut Cursive, change: impl FnOnce(&BootOption) -> Result<(), String>) {
+    let Some(option) = selected_option(siv) else {
+        return;
+    };
+    let result = change(&option);
+    siv.pop_layer();
+    show_entries(siv, Some(option.id));
+    if let Err(why) = result {
+        siv.add_layer(
+            views::Dialog::around(views::TextView::new(why))
+                .title("Error")
+                .dismiss_button("OK"),
+        );
+    }
+}
+
+fn confirm_delete(siv: &mut Cursive) {
+    let Some(option) = selected_option(siv) else {
+        return;
+    };
+    siv.add_layer(
+        views::Dialog::around(views::TextView::new(format!(
+            "Delete Boot{:04X} \"{}\"?\nThis cannot be undone.",
+            option.id, option.description
+        )))
+        .button("Delete", |siv| {
+            siv.pop_layer();
+            change_selected(siv, |option| delete_boot_option(option.id));
+        })
+        .dismiss_button("Cancel"),
+    );
+}
+
+/// The first step of creating an entry: choosing the partition that the loader is on.
+fn choose_partition(siv: &mut Cursive) {
+    let current_esp = esp::esp_partuuid().ok();
+    let mut select = views::SelectView::new();
+    for partition in list_partitions() {
+        // The HD node of a device path can only refer to a GPT partition by its GUID.
+        let Some(partuuid) = partition
+            .partuuid
+            .clone()
+            .filter(|uuid| parse_guid(uuid).is_some())
+        else {
+            continue;
+        };
+        let size_mib = partition.size * partition.block_size / (1024 * 1024);
+        let mut label = format!("{} ({size_mib} MiB)", partition.name);
+        if let Some(name) = &partition.partname {
+            label.push_str(&format!(" {name}"));
+        }
+        if current_esp.as_ref() == Some(&partuuid) {
+            label.push_str(" [current ESP]");
+        }
+        select.add_item(label, partition);
+    }
+    select.set_on_submit(|siv, partition: &Partition| {
+        siv.pop_layer();
+        enter_entry_details(siv, partition.clone());
+    });
+    siv.add_layer(
+        views::Dialog::around(select)
+            .title("Partition of the new entry")
+            .dismiss_button("Cancel"),
+    );
+}
+
+/// The second step of creating an entry: its description and the path of its loader.
+fn enter_entry_details(siv: &mut Cursive, partition: Partition) {
+    let loader = esp::running_image_path().unwrap_or(DEFAULT_LOADER.to_string());
+    siv.add_layer(
+        views::Dialog::around(
+            views::LinearLayout::vertical()
+                .child(views::TextView::new("Description"))
+                .child(
+                    views::EditView::new()
+                        .content("Arch Linux Boot Menu")
+                        .with_name("new_entry_description"),
+                )
+                .child(views::TextView::new("Loader path on the partition"))
+                .child(
+                    views::EditView::new()
+                        .content(loader)
+                        .with_name("new_entry_loader"),
+                ),
+        )
+        .title(format!("New entry on {}", partition.name))
+        .button("Create", move |siv| {
+            let description = siv
+                .call_on_name("new_entry_description", |view: &mut views::EditView| {
+                    view.get_content()
+                })
+                .unwrap();
+            let loader = siv
+                .call_on_name("new_entry_loader", |view: &mut views::EditView| {
+                    view.get_content()
+                })
+                .unwrap();
+            match create_boot_option(&description, &partition, &loader) {
+                Ok(id) => {
+                    // Pop this dialog and the outdated list of entries.
+                    siv.pop_layer();
+                    siv.pop_layer();
+                    show_entries(siv, Some(id));
+                }
+                Err(why) => siv.add_layer(
+                    views::Dialog::around(views::TextView::new(why))
+                        .title("Error")
+                        .dismiss_button("OK"),
+                ),
+            }
+        })
+        .dismiss_button("Cancel"),
+    );
+}
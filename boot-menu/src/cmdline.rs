@@ -0,0 +1,328 @@
+use std::{fmt::Display, path::PathBuf};
+
+/// This is where the kernel exposes the command line it was booted with.
+pub const PROC_CMDLINE: &str = "/proc/cmdline";
+
+/// A block device, written in the same syntax the kernel cmdline uses for `root=` and friends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSpec {
+    Path(PathBuf),
+    Uuid(String),
+    PartUuid(String),
+    Label(String),
+    PartLabel(String),
+}
+
+impl DeviceSpec {
+    pub fn parse(spec: &str) -> Self {
+        if let Some(uuid) = spec.strip_prefix("UUID=") {
+            Self::Uuid(uuid.to_lowercase())
+        } else if let Some(uuid) = spec.strip_prefix("PARTUUID=") {
+            Self::PartUuid(uuid.to_lowercase())
+        } else if let Some(label) = spec.strip_prefix("LABEL=") {
+            Self::Label(label.to_string())
+        } else if let Some(label) = spec.strip_prefix("PARTLABEL=") {
+            Self::PartLabel(label.to_string())
+        } else {
+            Self::Path(PathBuf::from(spec))
+        }
+    }
+
+    /// The path where udev puts a symlink to this device.
+    pub fn path(&self) -> PathBuf {
+        match self {
+            Self::Path(path) => path.clone(),
+            Self::Uuid(uuid) => PathBuf::from("/dev/disk/by-uuid").join(uuid),
+            Self::PartUuid(uuid) => PathBuf::from("/dev/disk/by-partuuid").join(uuid),
+            Self::Label(label) => PathBuf::from("/dev/disk/by-label").join(label),
+            Self::PartLabel(label) => PathBuf::from("/dev/disk/by-partlabel").join(label),
+        }
+    }
+}
+
+impl Display for DeviceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path.display()),
+            Self::Uuid(uuid) => write!(f, "UUID={uuid}"),
+            Self::PartUuid(uuid) => write!(f, "PARTUUID={uuid}"),
+            Self::Label(label) => write!(f, "LABEL={label}"),
+            Self::PartLabel(label) => write!(f, "PARTLABEL={label}"),
+        }
+    }
+}
+
+/// An option for opening a dm-crypt device that we know how to pass on to cryptsetup.
+/// Both the `encrypt` hook and the systemd spellings are accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CryptOption {
+    AllowDiscards,
+    NoReadWorkqueue,
+    NoWriteWorkqueue,
+    SameCpuCrypt,
+    SubmitFromCryptCpus,
+    ReadOnly,
+}
+
+impl CryptOption {
+    pub fn parse(option: &str) -> Option<Self> {
+        match option {
+            "allow-discards" | "discard" => Some(Self::AllowDiscards),
+            "no-read-workqueue" => Some(Self::NoReadWorkqueue),
+            "no-write-workqueue" => Some(Self::NoWriteWorkqueue),
+            "same-cpu-crypt" => Some(Self::SameCpuCrypt),
+            "submit-from-crypt-cpus" => Some(Self::SubmitFromCryptCpus),
+            "readonly" | "read-only" => Some(Self::ReadOnly),
+            _ => None,
+        }
+    }
+
+    pub fn cryptsetup_arg(&self) -> &'static str {
+        match self {
+            Self::AllowDiscards => "--allow-discards",
+            Self::NoReadWorkqueue => "--perf-no_read_workqueue",
+            Self::NoWriteWorkqueue => "--perf-no_write_workqueue",
+            Self::SameCpuCrypt => "--perf-same_cpu_crypt",
+            Self::SubmitFromCryptCpus => "--perf-submit_from_crypt_cpus",
+            Self::ReadOnly => "--readonly",
+        }
+    }
+}
+
+/// A LUKS device that the kernel cmdline asks to be unlocked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnlockTarget {
+    pub device: DeviceSpec,
+    /// The name of the device under `/dev/mapper`.
+    pub mapper_name: String,
+    pub options: Vec<CryptOption>,
+}
+
+impl UnlockTarget {
+    /// The arguments to put before `open` when calling cryptsetup for this target.
+    pub fn cryptsetup_args(&self) -> Vec<&'static str> {
+        self.options
+            .iter()
+            .map(CryptOption::cryptsetup_arg)
+            .collect()
+    }
+}
+
+/// Split the cmdline into parameters.
+/// Like the kernel, this splits on whitespace, except inside double quotes.
+pub fn split_params(cmdline: &str) -> Vec<String> {
+    let mut params = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for c in cmdline.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    params.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        params.push(current);
+    }
+    params
+}
+
+/// Find the value of a parameter like `root=`.
+/// If it's given more than once, the last one counts, like for the kernel and the initramfs.
+pub fn find_param(cmdline: &str, key: &str) -> Option<String> {
+    split_params(cmdline)
+        .into_iter()
+        .rev()
+        .find_map(|param| Some(param.strip_prefix(key)?.strip_prefix('=')?.to_string()))
+}
+
+/// Read `/proc/cmdline` and find the value of a parameter.
+pub fn read_param(key: &str) -> Result<Option<String>, String> {
+    let cmdline = std::fs::read_to_string(PROC_CMDLINE)
+        .map_err(|why| format!("Failed to read {PROC_CMDLINE}: {why}"))?;
+    Ok(find_param(&cmdline, key))
+}
+
+fn parse_options(options: &str) -> Vec<CryptOption> {
+    options.split(',').filter_map(CryptOption::parse).collect()
+}
+
+/// Find the LUKS devices requested by the cmdline.
+///
+/// This understands the `encrypt` hook's `cryptdevice=device:name[:options]`,
+/// and systemd's `rd.luks.uuid=`, `rd.luks.name=` and `rd.luks.options=`.
+pub fn parse_unlock_targets(cmdline: &str) -> Vec<UnlockTarget> {
+    let params = split_params(cmdline);
+    let mut targets: Vec<UnlockTarget> = vec![];
+
+    // `rd.luks.options=` and `rd.luks.name=` may come before or after the `rd.luks.uuid=` they apply to,
+    // so they need to be collected first.
+    let mut global_options = vec![];
+    let mut uuid_options: Vec<(String, Vec<CryptOption>)> = vec![];
+    let mut uuid_names: Vec<(String, String)> = vec![];
+    for param in &params {
+        if let Some((uuid, name)) = param
+            .strip_prefix("rd.luks.name=")
+            .and_then(|value| value.split_once('='))
+        {
+            uuid_names.push((normalize_luks_uuid(uuid), name.to_string()));
+        } else if let Some(value) = param.strip_prefix("rd.luks.options=") {
+            match value.split_once('=') {
+                Some((uuid, options)) => {
+                    uuid_options.push((normalize_luks_uuid(uuid), parse_options(options)))
+                }
+                None => global_options = parse_options(value),
+            }
+        }
+    }
+    let name_for = |uuid: &str| {
+        uuid_names
+            .iter()
+            .find(|(u, _)| u == uuid)
+            .map(|(_, name)| name.clone())
+            .unwrap_or_else(|| format!("luks-{uuid}"))
+    };
+    let options_for = |uuid: &str| {
+        uuid_options
+            .iter()
+            .find(|(u, _)| u == uuid)
+            .map(|(_, options)| options.clone())
+            .unwrap_or_else(|| global_options.clone())
+    };
+
+    let mut push = |target: UnlockTarget| {
+        if !targets.iter().any(|t| t.device == target.device) {
+            targets.push(target);
+        }
+    };
+
+    for param in &params {
+        if let Some(value) = param.strip_prefix("cryptdevice=") {
+            let mut parts = value.splitn(3, ':');
+            let device = parts.next().unwrap_or_default();
+            let Some(name) = parts.next() else {
+                continue;
+            };
+            push(UnlockTarget {
+                device: DeviceSpec::parse(device),
+                mapper_name: name.to_string(),
+                options: parts.next().map(parse_options).unwrap_or_default(),
+            });
+        } else if let Some(value) = param.strip_prefix("rd.luks.name=") {
+            let Some((uuid, _)) = value.split_once('=') else {
+                continue;
+            };
+            let uuid = normalize_luks_uuid(uuid);
+            push(UnlockTarget {
+                device: DeviceSpec::Uuid(uuid.clone()),
+                mapper_name: name_for(&uuid),
+                options: options_for(&uuid),
+            });
+        } else if let Some(value) = param.strip_prefix("rd.luks.uuid=") {
+            let uuid = normalize_luks_uuid(value);
+            push(UnlockTarget {
+                device: DeviceSpec::Uuid(uuid.clone()),
+                mapper_name: name_for(&uuid),
+                options: options_for(&uuid),
+            });
+        }
+    }
+
+    targets
+}
+
+/// systemd allows the UUIDs in `rd.luks.*` to be prefixed with `luks-`.
+fn normalize_luks_uuid(uuid: &str) -> String {
+    uuid.strip_prefix("luks-").unwrap_or(uuid).to_lowercase()
+}
+
+/// Read `/proc/cmdline` and find the LUKS devices it asks for.
+pub fn read_unlock_targets() -> Result<Vec<UnlockTarget>, String> {
+    let cmdline = std::fs::read_to_string(PROC_CMDLINE)
+        .map_err(|why| format!("Failed to read {PROC_CMDLINE}: {why}"))?;
+    Ok(parse_unlock_targets(&cmdline))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        find_param, parse_unlock_targets, split_params, CryptOption, DeviceSpec, UnlockTarget,
+    };
+
+    #[test]
+    fn test_cryptdevice() {
+        let cmdline = include_str!("../../cmdline").replace('\n', " ");
+        let targets = parse_unlock_targets(&cmdline);
+        assert_eq!(
+            targets,
+            vec![UnlockTarget {
+                device: DeviceSpec::Uuid("7537b139-51c6-48ed-a548-f035e0728638".to_string()),
+                mapper_name: "cryptlvm".to_string(),
+                options: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_cryptdevice_with_options() {
+        let targets = parse_unlock_targets(
+            "root=/dev/mapper/root cryptdevice=PARTLABEL=system:root:allow-discards,no-read-workqueue",
+        );
+        assert_eq!(
+            targets[0].device,
+            DeviceSpec::PartLabel("system".to_string())
+        );
+        assert_eq!(
+            targets[0].cryptsetup_args(),
+            vec!["--allow-discards", "--perf-no_read_workqueue"]
+        );
+    }
+
+    #[test]
+    fn test_rd_luks() {
+        let targets = parse_unlock_targets(
+            "rd.luks.options=discard rd.luks.uuid=luks-AAAA rd.luks.uuid=bbbb rd.luks.name=bbbb=data rd.luks.options=bbbb=read-only",
+        );
+        assert_eq!(
+            targets,
+            vec![
+                UnlockTarget {
+                    device: DeviceSpec::Uuid("aaaa".to_string()),
+                    mapper_name: "luks-aaaa".to_string(),
+                    options: vec![CryptOption::AllowDiscards],
+                },
+                UnlockTarget {
+                    device: DeviceSpec::Uuid("bbbb".to_string()),
+                    mapper_name: "data".to_string(),
+                    options: vec![CryptOption::ReadOnly],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_quotes() {
+        assert_eq!(
+            split_params("a \"b c\"  d=\"e f\"\n"),
+            vec!["a", "b c", "d=e f"]
+        );
+    }
+
+    #[test]
+    fn test_find_param() {
+        let cmdline = include_str!("../../cmdline").replace('\n', " ");
+        assert_eq!(
+            find_param(&cmdline, "root").as_deref(),
+            Some("/dev/mapper/arch_vg-root")
+        );
+        assert_eq!(find_param(&cmdline, "rootflags"), None);
+        assert_eq!(
+            find_param("rootflags=a rootfs=x rootflags=subvol=@", "rootflags").as_deref(),
+            Some("subvol=@")
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Editing the kernel cmdline, and booting the running UKI again with the edited one through kexec.
+
+use cursive::{
+    view::{Nameable, Resizable},
+    views, Cursive,
+};
+use serde::Deserialize;
+
+use crate::{
+    cmdline::{split_params, PROC_CMDLINE},
+    esp,
+    exits::kexec_into,
+    kexec::{KernelKind, KexecTarget},
+};
+
+/// The name of the EditView with the cmdline being edited.
+const CMDLINE_EDIT_VIEW: &str = "cmdline_edit";
+
+/// A named change to the cmdline, like adding `nomodeset` for safe graphics.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CmdlinePreset {
+    /// The text shown in the list of presets.
+    pub name: String,
+
+    /// Parameters to add. A parameter with a value, like `systemd.unit=rescue.target`,
+    /// replaces the ones with the same name.
+    #[serde(default)]
+    pub add: Vec<String>,
+
+    /// Parameters to remove, by name: `quiet` removes `quiet`, and `loglevel` removes `loglevel=3`.
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// The name of a parameter, which is the part before the `=` if there is one.
+fn param_name(param: &str) -> &str {
+    param.split_once('=').map_or(param, |(name, _)| name)
+}
+
+/// Apply the preset to a list of parameters.
+pub fn apply_preset(params: &[String], preset: &CmdlinePreset) -> Vec<String> {
+    let mut params: Vec<String> = params
+        .iter()
+        .filter(|param| {
+            !preset
+                .remove
+                .iter()
+                .any(|name| param_name(param) == param_name(name))
+        })
+        .cloned()
+        .collect();
+    for param in &preset.add {
+        params.retain(|existing| param_name(existing) != param_name(param));
+        params.push(param.clone());
+    }
+    params
+}
+
+/// Put the parameters back together, quoting the values that have spaces in them
+/// the way the kernel understands.
+pub fn join_params(params: &[String]) -> String {
+    params
+        .iter()
+        .map(|param| match param.split_once('=') {
+            Some((name, value)) if value.contains(char::is_whitespace) => {
+                format!("{name}=\"{value}\"")
+            }
+            _ if param.contains(char::is_whitespace) => format!("\"{param}\""),
+            _ => param.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn edited_cmdline(siv: &mut Cursive) -> String {
+    siv.call_on_name(CMDLINE_EDIT_VIEW, |view: &mut views::EditView| {
+        view.get_content().to_string()
+    })
+    .unwrap()
+}
+
+fn set_edited_cmdline(siv: &mut Cursive, cmdline: String) {
+    siv.call_on_name(CMDLINE_EDIT_VIEW, |view: &mut views::EditView| {
+        view.set_content(cmdline)
+    });
+}
+
+/// This function shows the current cmdline for editing, with the presets that can be applied to it,
+/// and boots the running UKI again with the edited cmdline.
+pub fn cmdline_editor(siv: &mut Cursive, presets: &[CmdlinePreset]) {
+    let current = match std::fs::read_to_string(PROC_CMDLINE) {
+        Ok(cmdline) => cmdline.trim().to_string(),
+        Err(why) => {
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(format!(
+                    "Failed to read {PROC_CMDLINE}: {why}"
+                )))
+                .dismiss_button("Return to menu"),
+            );
+            return;
+        }
+    };
+    // The kernel and initrd that are running are in the UKI that the firmware started.
+    let image = match esp::running_image_path() {
+        Ok(image) => image,
+        Err(why) => {
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(format!(
+                    "{why}\nThe running UKI could not be found, so it can't be started again."
+                )))
+                .dismiss_button("Return to menu"),
+            );
+            return;
+        }
+    };
+
+    let mut select = views::SelectView::new().autojump();
+    for preset in presets {
+        select.add_item(preset.name.clone(), preset.clone());
+    }
+    select.set_on_submit(|siv, preset: &CmdlinePreset| {
+        let params = split_params(&edited_cmdline(siv));
+        set_edited_cmdline(siv, join_params(&apply_preset(&params, preset)));
+    });
+
+    let original = current.clone();
+    siv.add_layer(
+        views::Dialog::around(
+            views::LinearLayout::vertical()
+                .child(views::TextView::new("Presets (Enter applies one)"))
+                .child(select)
+                .child(views::TextView::new("Kernel cmdline"))
+                .child(
+                    views::EditView::new()
+                        .content(current)
+                        .with_name(CMDLINE_EDIT_VIEW)
+                        .min_width(70),
+                ),
+        )
+        .title("Edit the kernel cmdline")
+        .button("Boot", move |siv| {
+            let target = KexecTarget {
+                path: esp::esp_relative_path(&image).to_string_lossy().to_string(),
+                kind: KernelKind::Uki,
+                os_name: None,
+                uname: None,
+                cmdline: Some(edited_cmdline(siv)),
+            };
+            kexec_into(siv, &target);
+        })
+        .button("Reset", move |siv| {
+            set_edited_cmdline(siv, original.clone())
+        })
+        .dismiss_button("Back"),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_preset, join_params, CmdlinePreset};
+    use crate::cmdline::split_params;
+
+    #[test]
+    fn test_apply_preset() {
+        let params =
+            split_params("root=/dev/mapper/root rw quiet loglevel=3 systemd.unit=graphical.target");
+        let preset = CmdlinePreset {
+            name: "rescue".to_string(),
+            add: vec![
+                "systemd.unit=rescue.target".to_string(),
+                "nomodeset".to_string(),
+            ],
+            remove: vec!["quiet".to_string(), "loglevel".to_string()],
+        };
+        assert_eq!(
+            join_params(&apply_preset(&params, &preset)),
+            "root=/dev/mapper/root rw systemd.unit=rescue.target nomodeset"
+        );
+        // Applying it again changes nothing.
+        let params = apply_preset(&params, &preset);
+        assert_eq!(apply_preset(&params, &preset), params);
+    }
+
+    #[test]
+    fn test_join_params() {
+        let params = split_params("a b=\"c d\" e");
+        assert_eq!(join_params(&params), "a b=\"c d\" e");
+    }
+}
@@ -0,0 +1,183 @@
+//! The menu entry that is preselected and chosen automatically after a countdown.
+//! This is the entry that was chosen on the previous boot, which is kept in an EFI variable.
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use cursive::{
+    event::{Event, EventTrigger},
+    views, Cursive,
+};
+
+use crate::{
+    efivarfs::{self, DEFAULT_ATTRIBUTES},
+    exits::choose_entry,
+    menu_config::{MenuAction, MenuEntry},
+    State,
+};
+
+/// The vendor GUID of the variables that boot-menu itself stores.
+pub const BOOT_MENU_VENDOR: &str = "7f72cc8a-1d0d-4f7a-befd-dbbc52fbe952";
+
+/// The variable with the label of the last chosen entry, as UTF-8.
+const LAST_CHOICE_VARIABLE: &str = "BootMenuLastChoice";
+
+/// The name of the TextView that shows the countdown.
+/// Every dialog that a countdown can run on has one of these.
+pub const COUNTDOWN_VIEW: &str = "countdown";
+
+pub fn read_last_choice() -> Option<String> {
+    let (_, value) = efivarfs::read(LAST_CHOICE_VARIABLE, BOOT_MENU_VENDOR).ok()?;
+    String::from_utf8(value).ok()
+}
+
+pub fn save_last_choice(label: &str) -> Result<(), String> {
+    efivarfs::write(
+        LAST_CHOICE_VARIABLE,
+        BOOT_MENU_VENDOR,
+        DEFAULT_ATTRIBUTES,
+        label.as_bytes(),
+    )
+}
+
+/// Whether choosing this action is worth remembering for the next boot.
+/// Only the actions that leave the menu are; the others would just show a dialog again.
+pub fn is_remembered(action: &MenuAction) -> bool {
+    matches!(
+        action,
+        MenuAction::ContinueBoot
+            | MenuAction::BootNext { .. }
+            | MenuAction::BootLoaderEntry { .. }
+            | MenuAction::FirmwareSetup
+            | MenuAction::Reboot
+            | MenuAction::Poweroff
+    )
+}
+
+/// Find the default among the entries that are visible in this login state,
+/// and return its position in the list of visible entries.
+/// After logging in, the first entry is the default if the last choice isn't there;
+/// before that, there is only a default if the last choice doesn't need a login.
+pub fn default_entry(
+    entries: &[MenuEntry],
+    last_choice: Option<&str>,
+    logged_in: bool,
+) -> Option<usize> {
+    let visible: Vec<&MenuEntry> = entries
+        .iter()
+        .filter(|entry| logged_in || !entry.requires_auth)
+        .collect();
+    let last = last_choice.and_then(|label| visible.iter().position(|e| e.label == label));
+    match last {
+        Some(index) => Some(index),
+        None if logged_in && !visible.is_empty() => Some(0),
+        None => None,
+    }
+}
+
+fn set_countdown_text(siv: &mut Cursive, text: String) {
+    siv.call_on_name(COUNTDOWN_VIEW, |view: &mut views::TextView| {
+        view.set_content(text)
+    });
+}
+
+/// Start counting down, and choose this entry when it reaches zero,
+/// unless it has been cancelled or another countdown was started in the meantime.
+pub fn start_countdown(siv: &mut Cursive, seconds: u64, entry: MenuEntry) {
+    let data: &mut State = siv.user_data().unwrap();
+    let generation = data.countdown.clone();
+    let this_generation = generation.fetch_add(1, Ordering::SeqCst) + 1;
+    let is_current = move || generation.load(Ordering::SeqCst) == this_generation;
+
+    let cb_sink = siv.cb_sink().clone();
+    std::thread::spawn(move || {
+        for remaining in (1..=seconds).rev() {
+            if !is_current() {
+                return;
+            }
+            let text = format!(
+                "\"{}\" in {remaining} s, press any key to cancel",
+                entry.label
+            );
+            let is_current = is_current.clone();
+            let sent = cb_sink.send(Box::new(move |siv| {
+                if is_current() {
+                    set_countdown_text(siv, text);
+                }
+            }));
+            if sent.is_err() {
+                return;
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        let _ = cb_sink.send(Box::new(move |siv| {
+            if !is_current() {
+                return;
+            }
+            set_countdown_text(siv, String::new());
+            choose_entry(siv, &entry);
+        }));
+    });
+}
+
+/// Stop the countdown that is running, if any.
+pub fn cancel_countdown(siv: &mut Cursive) {
+    let data: &mut State = siv.user_data().unwrap();
+    data.countdown.fetch_add(1, Ordering::SeqCst);
+    set_countdown_text(siv, String::new());
+}
+
+/// Start the countdown for the default entry in this login state, if the menu config has a timeout
+/// and there is a default.
+pub fn start_default_countdown(siv: &mut Cursive, logged_in: bool) {
+    let data: &mut State = siv.user_data().unwrap();
+    let Some(seconds) = data.menu.timeout else {
+        return;
+    };
+    let Some(index) = default_entry(&data.menu.entries, data.last_choice.as_deref(), logged_in)
+    else {
+        return;
+    };
+    let entry = data
+        .menu
+        .entries
+        .iter()
+        .filter(|entry| logged_in || !entry.requires_auth)
+        .nth(index)
+        .unwrap()
+        .clone();
+    start_countdown(siv, seconds, entry);
+}
+
+/// Keep the countdown going only while nobody is touching the keyboard.
+pub fn cancel_on_keypress(siv: &mut Cursive) {
+    siv.set_on_pre_event(
+        EventTrigger::from_fn(|event| !matches!(event, Event::Refresh | Event::WindowResize)),
+        cancel_countdown,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::default_entry;
+    use crate::menu_config::MenuConfig;
+
+    #[test]
+    fn test_default_entry() {
+        let menu: MenuConfig = serde_json::from_str(
+            r#"{"entries": [
+                {"label": "Arch", "action": {"type": "continue_boot"}, "requires_auth": true},
+                {"label": "Windows", "action": {"type": "boot_next", "description": "Windows"}, "requires_auth": true},
+                {"label": "Reboot", "action": {"type": "reboot"}}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(default_entry(&menu.entries, Some("Windows"), true), Some(1));
+        assert_eq!(default_entry(&menu.entries, Some("Gone"), true), Some(0));
+        assert_eq!(default_entry(&menu.entries, None, true), Some(0));
+        // Before logging in, only the entries that don't need it are visible.
+        assert_eq!(default_entry(&menu.entries, Some("Windows"), false), None);
+        assert_eq!(default_entry(&menu.entries, Some("Reboot"), false), Some(0));
+        assert_eq!(default_entry(&menu.entries, None, false), None);
+    }
+}
@@ -0,0 +1,406 @@
+//! Decoding of UEFI device paths, like the ones in the `Boot####` variables.
+//! See https://uefi.org/specs/UEFI/2.10/10_Protocols_Device_Path_Protocol.html
+
+use std::fmt::Display;
+
+const HARDWARE_DEVICE_PATH: u8 = 0x01;
+const ACPI_DEVICE_PATH: u8 = 0x02;
+const MESSAGING_DEVICE_PATH: u8 = 0x03;
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const END_OF_PATH: u8 = 0x7f;
+
+const HW_PCI: u8 = 0x01;
+const ACPI_ACPI: u8 = 0x01;
+const MSG_USB: u8 = 0x05;
+const MEDIA_HARD_DRIVE: u8 = 0x01;
+const MEDIA_FILE_PATH: u8 = 0x04;
+const END_ENTIRE: u8 = 0xff;
+
+/// The compressed EISA IDs that mean PNP0A03 and PNP0A08, which are PCI root bridges.
+const EISA_PNP0A03: u32 = 0x0a0341d0;
+const EISA_PNP0A08: u32 = 0x0a0841d0;
+
+/// Format a GUID in the mixed-endian layout that UEFI stores them in.
+pub fn format_guid(bytes: &[u8; 16]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        bytes[8],
+        bytes[9],
+        bytes[10],
+        bytes[11],
+        bytes[12],
+        bytes[13],
+        bytes[14],
+        bytes[15],
+    )
+}
+
+/// Parse a GUID from its text form into the mixed-endian layout that UEFI stores them in.
+pub fn parse_guid(text: &str) -> Option<[u8; 16]> {
+    let hex: String = text.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 || text.len() != 36 {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Some(bytes)
+}
+
+/// The way a partition in a HD node is identified.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionSignature {
+    None,
+    /// The 32-bit disk signature of an MBR disk.
+    Mbr(u32),
+    /// The unique partition GUID of a GPT partition, the same as its PARTUUID.
+    Gpt(String),
+}
+
+/// A single node of a device path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceNode {
+    Pci {
+        device: u8,
+        function: u8,
+    },
+    Acpi {
+        hid: u32,
+        uid: u32,
+    },
+    Usb {
+        parent_port: u8,
+        interface: u8,
+    },
+    HardDrive {
+        partition: u32,
+        start: u64,
+        size: u64,
+        signature: PartitionSignature,
+    },
+    FilePath(String),
+    /// Any other node, which is kept as raw bytes.
+    Other {
+        node_type: u8,
+        subtype: u8,
+        data: Vec<u8>,
+    },
+}
+
+fn utf16_string(buf: &[u8]) -> String {
+    let chars: Vec<u16> = buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf16_lossy(&chars)
+}
+
+impl DeviceNode {
+    fn parse(node_type: u8, subtype: u8, data: &[u8]) -> Self {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        match (node_type, subtype, data.len()) {
+            (HARDWARE_DEVICE_PATH, HW_PCI, 2) => Self::Pci {
+                function: data[0],
+                device: data[1],
+            },
+            (ACPI_DEVICE_PATH, ACPI_ACPI, 8) => Self::Acpi {
+                hid: u32_at(0),
+                uid: u32_at(4),
+            },
+            (MESSAGING_DEVICE_PATH, MSG_USB, 2) => Self::Usb {
+                parent_port: data[0],
+                interface: data[1],
+            },
+            (MEDIA_DEVICE_PATH, MEDIA_HARD_DRIVE, 38) => Self::HardDrive {
+                partition: u32_at(0),
+                start: u64_at(4),
+                size: u64_at(12),
+                signature: match (data[36], data[37]) {
+                    (0x01, 0x01) => PartitionSignature::Mbr(u32_at(20)),
+                    (0x02, 0x02) => {
+                        PartitionSignature::Gpt(format_guid(data[20..36].try_into().unwrap()))
+                    }
+                    _ => PartitionSignature::None,
+                },
+            },
+            (MEDIA_DEVICE_PATH, MEDIA_FILE_PATH, _) => Self::FilePath(utf16_string(data)),
+            _ => Self::Other {
+                node_type,
+                subtype,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
+fn node(node_type: u8, subtype: u8, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![node_type, subtype];
+    buf.extend_from_slice(&((data.len() + 4) as u16).to_le_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+impl DeviceNode {
+    /// Encode this node into the binary form that `parse` reads.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Pci { device, function } => {
+                node(HARDWARE_DEVICE_PATH, HW_PCI, &[*function, *device])
+            }
+            Self::Acpi { hid, uid } => {
+                let mut data = hid.to_le_bytes().to_vec();
+                data.extend_from_slice(&uid.to_le_bytes());
+                node(ACPI_DEVICE_PATH, ACPI_ACPI, &data)
+            }
+            Self::Usb {
+                parent_port,
+                interface,
+            } => node(MESSAGING_DEVICE_PATH, MSG_USB, &[*parent_port, *interface]),
+            Self::HardDrive {
+                partition,
+                start,
+                size,
+                signature,
+            } => {
+                let mut data = partition.to_le_bytes().to_vec();
+                data.extend_from_slice(&start.to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+                let mut sig = [0u8; 16];
+                let (format, sig_type) = match signature {
+                    PartitionSignature::None => (0, 0),
+                    PartitionSignature::Mbr(mbr) => {
+                        sig[..4].copy_from_slice(&mbr.to_le_bytes());
+                        (0x01, 0x01)
+                    }
+                    PartitionSignature::Gpt(guid) => {
+                        sig = parse_guid(guid).unwrap_or_default();
+                        (0x02, 0x02)
+                    }
+                };
+                data.extend_from_slice(&sig);
+                data.extend_from_slice(&[format, sig_type]);
+                node(MEDIA_DEVICE_PATH, MEDIA_HARD_DRIVE, &data)
+            }
+            Self::FilePath(path) => {
+                let data: Vec<u8> = path
+                    .encode_utf16()
+                    .chain([0])
+                    .flat_map(u16::to_le_bytes)
+                    .collect();
+                node(MEDIA_DEVICE_PATH, MEDIA_FILE_PATH, &data)
+            }
+            Self::Other {
+                node_type,
+                subtype,
+                data,
+            } => node(*node_type, *subtype, data),
+        }
+    }
+}
+
+impl Display for DeviceNode {
+    /// This follows the text representation from the UEFI spec, as printed by efibootmgr.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pci { device, function } => write!(f, "Pci({device:#x},{function:#x})"),
+            Self::Acpi { hid, uid } if *hid == EISA_PNP0A03 || *hid == EISA_PNP0A08 => {
+                write!(f, "PciRoot({uid:#x})")
+            }
+            Self::Acpi { hid, uid } => write!(f, "Acpi({hid:#x},{uid:#x})"),
+            Self::Usb {
+                parent_port,
+                interface,
+            } => write!(f, "USB({parent_port:#x},{interface:#x})"),
+            Self::HardDrive {
+                partition,
+                start,
+                size,
+                signature,
+            } => match signature {
+                PartitionSignature::None => write!(f, "HD({partition},{start:#x},{size:#x})"),
+                PartitionSignature::Mbr(sig) => {
+                    write!(f, "HD({partition},MBR,{sig:#010x},{start:#x},{size:#x})")
+                }
+                PartitionSignature::Gpt(guid) => {
+                    write!(f, "HD({partition},GPT,{guid},{start:#x},{size:#x})")
+                }
+            },
+            Self::FilePath(path) => write!(f, "File({path})"),
+            Self::Other {
+                node_type,
+                subtype,
+                data,
+            } => {
+                write!(f, "Path({node_type},{subtype},")?;
+                for byte in data {
+                    write!(f, "{byte:02x}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// A device path: a sequence of nodes from the root of the hardware down to a file.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct DevicePath(pub Vec<DeviceNode>);
+
+impl DevicePath {
+    /// The path of the file that this points at, if any.
+    /// A long path may be split over several consecutive file path nodes.
+    pub fn file_path(&self) -> Option<String> {
+        let mut path: Option<String> = None;
+        for node in &self.0 {
+            if let DeviceNode::FilePath(part) = node {
+                let path = path.get_or_insert_with(String::new);
+                if !path.is_empty() && !path.ends_with('\\') && !part.starts_with('\\') {
+                    path.push('\\');
+                }
+                path.push_str(part);
+            }
+        }
+        path
+    }
+
+    /// The GUID of the GPT partition that this points into, if any.
+    pub fn partition_guid(&self) -> Option<&str> {
+        self.0.iter().find_map(|node| match node {
+            DeviceNode::HardDrive {
+                signature: PartitionSignature::Gpt(guid),
+                ..
+            } => Some(guid.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl Display for DevicePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, node) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, "/")?;
+            }
+            write!(f, "{node}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Encode the file path list of a load option, with an End Entire node after each path.
+pub fn encode_file_path_list(paths: &[DevicePath]) -> Vec<u8> {
+    let mut buf = vec![];
+    for path in paths {
+        for node in &path.0 {
+            buf.extend(node.encode());
+        }
+        buf.extend(node(END_OF_PATH, END_ENTIRE, &[]));
+    }
+    buf
+}
+
+/// Parse the file path list of a load option.
+/// This is one or more device paths, each of which is terminated by an End Entire node.
+pub fn parse_file_path_list(mut buf: &[u8]) -> Result<Vec<DevicePath>, String> {
+    let mut paths = vec![];
+    let mut current = vec![];
+    while !buf.is_empty() {
+        if buf.len() < 4 {
+            return Err("Device path node header is truncated".to_string());
+        }
+        let node_type = buf[0];
+        let subtype = buf[1];
+        let length = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if length < 4 || length > buf.len() {
+            return Err(format!(
+                "Device path node has invalid length {length} with {} bytes left",
+                buf.len()
+            ));
+        }
+        let data = &buf[4..length];
+        buf = &buf[length..];
+
+        match (node_type, subtype) {
+            (END_OF_PATH, END_ENTIRE) => paths.push(DevicePath(std::mem::take(&mut current))),
+            // The end of one instance of a multi-instance path, which doesn't matter for boot entries.
+            (END_OF_PATH, _) => {}
+            _ => current.push(DeviceNode::parse(node_type, subtype, data)),
+        }
+    }
+    if !current.is_empty() {
+        return Err("Device path is missing its end node".to_string());
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        encode_file_path_list, format_guid, parse_file_path_list, parse_guid, DeviceNode,
+        DevicePath, PartitionSignature,
+    };
+
+    #[test]
+    fn test_guid() {
+        let bytes = [
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ];
+        // This is the ESP partition type GUID.
+        assert_eq!(format_guid(&bytes), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+    }
+
+    #[test]
+    fn test_mbr_and_unknown_nodes() {
+        let mut buf = vec![4, 1, 42, 0];
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(&0x800u64.to_le_bytes());
+        buf.extend_from_slice(&0x1000u64.to_le_bytes());
+        buf.extend_from_slice(&0xdeadbeefu32.to_le_bytes());
+        buf.extend_from_slice(&[0; 12]);
+        buf.extend_from_slice(&[1, 1]);
+        // A messaging node that isn't decoded.
+        buf.extend_from_slice(&[3, 0x18, 6, 0, 0xab, 0xcd]);
+        buf.extend_from_slice(&[0x7f, 0xff, 4, 0]);
+
+        let paths = parse_file_path_list(&buf).unwrap();
+        assert_eq!(
+            paths[0].to_string(),
+            "HD(2,MBR,0xdeadbeef,0x800,0x1000)/Path(3,24,abcd)"
+        );
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let guid = "c4a1d3f8-2f5e-4b8b-9d3a-5e3c1a7b2f10";
+        let paths = vec![DevicePath(vec![
+            DeviceNode::HardDrive {
+                partition: 1,
+                start: 0x800,
+                size: 0x32000,
+                signature: PartitionSignature::Gpt(guid.to_string()),
+            },
+            DeviceNode::FilePath("\\EFI\\Linux\\arch-linux.efi".to_string()),
+        ])];
+        let buf = encode_file_path_list(&paths);
+        assert_eq!(parse_file_path_list(&buf).unwrap(), paths);
+        assert_eq!(format_guid(&parse_guid(guid).unwrap()), guid);
+        assert_eq!(parse_guid("not-a-guid"), None);
+    }
+
+    #[test]
+    fn test_missing_end_node() {
+        assert!(parse_file_path_list(&[1, 1, 6, 0, 0, 0x14]).is_err());
+        assert!(parse_file_path_list(&[1, 1, 60, 0, 0, 0x14]).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Running an EFI program from the ESP once, like memtest86 or the EFI shell, which have no boot entry of their own.
+//! A temporary entry is created for it and set as BootNext, and deleted again on the next boot.
+
+use std::path::Path;
+
+use cursive::{align::HAlign, views, Cursive};
+
+use crate::{
+    block_device::list_partitions,
+    boot_entries::{delete_boot_option, set_boot_next, write_new_boot_option},
+    default_entry::BOOT_MENU_VENDOR,
+    efivarfs::{self, DEFAULT_ATTRIBUTES},
+    esp,
+    exits::reboot,
+    spinner::spinner_view,
+};
+
+/// The variable with the number of the temporary entry, as a little-endian u16.
+const TEMPORARY_ENTRY_VARIABLE: &str = "BootMenuTemporaryEntry";
+
+/// How deep the ESP is searched for EFI programs. They're usually in `\EFI\<vendor>`.
+const MAX_DEPTH: usize = 4;
+
+/// Turn a path relative to the root of the ESP into the form the firmware uses, like `\EFI\tools\shellx64.efi`.
+pub fn efi_path(relative: &Path) -> String {
+    let mut path = String::new();
+    for component in relative.components() {
+        path.push('\\');
+        path.push_str(&component.as_os_str().to_string_lossy());
+    }
+    path
+}
+
+fn find_efi_files(root: &Path, dir: &Path, depth: usize, found: &mut Vec<String>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() && depth < MAX_DEPTH {
+            find_efi_files(root, &path, depth + 1, found);
+        } else if file_type.is_file()
+            && path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("efi"))
+        {
+            if let Ok(relative) = path.strip_prefix(root) {
+                found.push(efi_path(relative));
+            }
+        }
+    }
+}
+
+/// List the EFI programs on the ESP, as paths in the form the firmware uses.
+pub fn list_efi_files() -> Result<Vec<String>, String> {
+    let mut found = esp::with_esp(|root| {
+        let mut found = vec![];
+        find_efi_files(root, root, 0, &mut found);
+        found
+    })?;
+    found.sort_by_key(|path| path.to_lowercase());
+    Ok(found)
+}
+
+/// Create a temporary entry for the program and make it the next thing the firmware boots.
+pub fn boot_once(loader: &str) -> Result<(), String> {
+    let partuuid = esp::esp_partuuid()?;
+    let partition = list_partitions()
+        .into_iter()
+        .find(|partition| partition.partuuid.as_deref() == Some(&partuuid))
+        .ok_or(format!("ESP partition {partuuid} not found"))?;
+
+    let name = loader.rsplit('\\').next().unwrap_or(loader);
+    let id = write_new_boot_option(&format!("boot-menu: {name}"), &partition, loader)?;
+    // This is written before BootNext, so that the entry is cleaned up even if the rest fails.
+    efivarfs::write(
+        TEMPORARY_ENTRY_VARIABLE,
+        BOOT_MENU_VENDOR,
+        DEFAULT_ATTRIBUTES,
+        &id.to_le_bytes(),
+    )?;
+    set_boot_next(id)
+}
+
+/// Delete the temporary entry that was created on a previous boot, if there is one.
+/// This is called once at startup.
+pub fn delete_temporary_entry() -> Result<(), String> {
+    let Ok((_, value)) = efivarfs::read(TEMPORARY_ENTRY_VARIABLE, BOOT_MENU_VENDOR) else {
+        return Ok(());
+    };
+    if let Ok(id) = value.as_slice().try_into() {
+        delete_boot_option(u16::from_le_bytes(id))?;
+    }
+    efivarfs::delete(TEMPORARY_ENTRY_VARIABLE, BOOT_MENU_VENDOR)
+}
+
+fn run_tool(siv: &mut Cursive, loader: &String) {
+    match boot_once(loader) {
+        Ok(()) => {
+            siv.add_layer(views::Dialog::around(views::TextView::new(format!(
+                "Rebooting into {loader}..."
+            ))));
+            reboot(siv);
+        }
+        Err(why) => siv.add_layer(
+            views::Dialog::around(views::TextView::new(why)).dismiss_button("Return to menu"),
+        ),
+    }
+}
+
+/// This function lists the EFI programs on the ESP, and reboots into the selected one once.
+pub fn efi_tool_menu(siv: &mut Cursive) {
+    siv.add_layer(views::Dialog::around(
+        views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
+            .child(spinner_view())
+            .child(views::TextView::new(
+                "Looking for EFI programs on the ESP...",
+            )),
+    ));
+    let cb_sink = siv.cb_sink().clone();
+    std::thread::spawn(move || {
+        let result = list_efi_files();
+        cb_sink
+            .send(Box::new(move |siv| {
+                siv.pop_layer();
+                let files = match result {
+                    Ok(files) => files,
+                    Err(why) => {
+                        siv.add_layer(
+                            views::Dialog::around(views::TextView::new(why))
+                                .dismiss_button("Return to menu"),
+                        );
+                        return;
+                    }
+                };
+
+                let mut select = views::SelectView::new().h_align(HAlign::Center).autojump();
+                for file in files {
+                    select.add_item(file.clone(), file);
+                }
+                select.set_on_submit(run_tool);
+                siv.add_layer(
+                    views::Dialog::around(select)
+                        .title("Run EFI tool")
+                        .dismiss_button("Back"),
+                );
+            }))
+            .unwrap();
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::efi_path;
+
+    #[test]
+    fn test_efi_path() {
+        assert_eq!(
+            efi_path(Path::new("EFI/tools/shellx64.efi")),
+            "\\EFI\\tools\\shellx64.efi"
+        );
+        assert_eq!(efi_path(Path::new("memtest.efi")), "\\memtest.efi");
+    }
+}
@@ -0,0 +1,167 @@
+//! Reading and writing EFI variables through efivarfs.
+//! See https://docs.kernel.org/filesystems/efivarfs.html
+
+use std::{
+    io::Write,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+pub const EFIVARFS_ROOT: &str = "/sys/firmware/efi/efivars";
+
+/// The vendor GUID of the variables defined by the UEFI spec, like BootOrder.
+pub const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+pub const EFI_VARIABLE_NON_VOLATILE: u32 = 0x1;
+pub const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+pub const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+/// The attributes that BootNext, OsIndications and the like need.
+pub const DEFAULT_ATTRIBUTES: u32 =
+    EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS;
+
+pub const EFI_VARIABLE_HARDWARE_ERROR_RECORD: u32 = 0x8;
+pub const EFI_VARIABLE_AUTHENTICATED_WRITE_ACCESS: u32 = 0x10;
+pub const EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+pub const EFI_VARIABLE_APPEND_WRITE: u32 = 0x40;
+
+// These come from linux/fs.h.
+const FS_IOC_GETFLAGS: usize = 0x80086601;
+const FS_IOC_SETFLAGS: usize = 0x40086602;
+const FS_IMMUTABLE_FL: i32 = 0x10;
+
+fn variable_path(name: &str, vendor: &str) -> PathBuf {
+    Path::new(EFIVARFS_ROOT).join(format!("{name}-{vendor}"))
+}
+
+/// Split the content of an efivarfs file into the attributes and the value.
+/// The first 4 bytes of the file are the attributes of the variable.
+pub fn split_attributes(content: &[u8]) -> Result<(u32, &[u8]), String> {
+    if content.len() < 4 {
+        return Err("EFI variable is shorter than its attribute header".to_string());
+    }
+    let attributes = u32::from_le_bytes(content[..4].try_into().unwrap());
+    Ok((attributes, &content[4..]))
+}
+
+/// Read a variable, returning its attributes and value.
+pub fn read(name: &str, vendor: &str) -> Result<(u32, Vec<u8>), String> {
+    let content = std::fs::read(variable_path(name, vendor))
+        .map_err(|why| format!("Failed to read EFI variable {name}: {why}"))?;
+    let (attributes, value) = split_attributes(&content).map_err(|why| format!("{name}: {why}"))?;
+    Ok((attributes, value.to_vec()))
+}
+
+/// List the names and vendor GUIDs of all variables.
+pub fn list_all() -> Result<Vec<(String, String)>, String> {
+    let entries = std::fs::read_dir(EFIVARFS_ROOT)
+        .map_err(|why| format!("Failed to list {EFIVARFS_ROOT}: {why}"))?;
+    Ok(entries
+        .flatten()
+        .filter_map(|entry| {
+            // The file name is the variable name, a dash, then the 36-character GUID.
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let split = file_name.len().checked_sub(37)?;
+            let (name, vendor) = file_name.split_at(split);
+            Some((name.to_string(), vendor.strip_prefix('-')?.to_string()))
+        })
+        .collect())
+}
+
+/// List the names of the variables with this vendor GUID.
+pub fn list(vendor: &str) -> Result<Vec<String>, String> {
+    Ok(list_all()?
+        .into_iter()
+        .filter(|(_, v)| v == vendor)
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// efivarfs marks the files of existing variables immutable,
+/// so that `rm -rf` can't delete ones the firmware needs.
+/// That makes writing to them fail, so this flag needs to be removed first.
+fn clear_immutable(path: &Path) -> Result<(), String> {
+    let file = std::fs::File::open(path)
+        .map_err(|why| format!("Failed to open {}: {why}", path.display()))?;
+    let fd = file.as_raw_fd() as usize;
+    let mut flags: i32 = 0;
+    unsafe {
+        syscalls::syscall!(
+            syscalls::Sysno::ioctl,
+            fd,
+            FS_IOC_GETFLAGS,
+            &mut flags as *mut i32 as usize
+        )
+    }
+    .map_err(|why| format!("Failed to get flags of {}: {why}", path.display()))?;
+
+    if flags & FS_IMMUTABLE_FL == 0 {
+        return Ok(());
+    }
+    flags &= !FS_IMMUTABLE_FL;
+    unsafe {
+        syscalls::syscall!(
+            syscalls::Sysno::ioctl,
+            fd,
+            FS_IOC_SETFLAGS,
+            &flags as *const i32 as usize
+        )
+    }
+    .map_err(|why| format!("Failed to make {} mutable: {why}", path.display()))?;
+    Ok(())
+}
+
+/// Create or replace a variable.
+pub fn write(name: &str, vendor: &str, attributes: u32, value: &[u8]) -> Result<(), String> {
+    let path = variable_path(name, vendor);
+    if path.exists() {
+        clear_immutable(&path)?;
+    }
+
+    // efivarfs needs the attributes and the value in a single write,
+    // and it replaces the whole variable, so the file isn't truncated first.
+    let mut content = attributes.to_le_bytes().to_vec();
+    content.extend_from_slice(value);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|why| format!("Failed to open EFI variable {name} for writing: {why}"))?;
+    let written = file
+        .write(&content)
+        .map_err(|why| format!("Failed to write EFI variable {name}: {why}"))?;
+    if written != content.len() {
+        return Err(format!(
+            "Only {written} of {} bytes of EFI variable {name} were written",
+            content.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Delete a variable. It is not an error if it doesn't exist.
+pub fn delete(name: &str, vendor: &str) -> Result<(), String> {
+    let path = variable_path(name, vendor);
+    if !path.exists() {
+        return Ok(());
+    }
+    clear_immutable(&path)?;
+    std::fs::remove_file(&path)
+        .map_err(|why| format!("Failed to delete EFI variable {name}: {why}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_attributes, DEFAULT_ATTRIBUTES};
+
+    #[test]
+    fn test_split_attributes() {
+        // BootNext pointing at Boot0003, as read from efivarfs.
+        let content = [0x07, 0, 0, 0, 0x03, 0x00];
+        let (attributes, value) = split_attributes(&content).unwrap();
+        assert_eq!(attributes, DEFAULT_ATTRIBUTES);
+        assert_eq!(value, &[3, 0]);
+        assert!(split_attributes(&[7, 0]).is_err());
+    }
+}
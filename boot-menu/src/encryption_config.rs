@@ -0,0 +1,115 @@
+use std::{fmt::Display, fs::File, path::Path};
+
+use disk_crypto::params::EncryptionParams;
+
+use crate::{esp, pe};
+
+/// Where the encryption config is looked up in the initramfs.
+pub const INITRAMFS_CONFIG_PATH: &str = "/etc/boot-menu/encrypt-config.json";
+
+/// Where the encryption config is looked up on the ESP, relative to its root.
+pub const ESP_CONFIG_PATH: &str = "boot-menu/encrypt-config.json";
+
+/// The PE section of the UKI that may contain the encryption config.
+/// This can be added with `objcopy --add-section` without rebuilding anything.
+pub const UKI_SECTION: &str = ".bmcrypt";
+
+/// Where the encryption config that is in use was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigSource {
+    Esp,
+    UkiSection,
+    Initramfs,
+    CompiledIn,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Esp => write!(f, "ESP ({ESP_CONFIG_PATH})"),
+            Self::UkiSection => write!(f, "UKI section {UKI_SECTION}"),
+            Self::Initramfs => write!(f, "initramfs ({INITRAMFS_CONFIG_PATH})"),
+            Self::CompiledIn => write!(f, "compiled-in copy"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LoadedConfig {
+    pub params: EncryptionParams,
+    pub source: ConfigSource,
+}
+
+fn parse(text: &[u8]) -> Result<EncryptionParams, String> {
+    let value = serde_json::from_slice(text).map_err(|why| why.to_string())?;
+    EncryptionParams::from_json_value(value)
+}
+
+/// The content of a place the config is looked up in, or why it couldn't be read.
+type FileContent = Result<Vec<u8>, String>;
+
+/// Read the `.bmcrypt` section of the running UKI, without reading the rest of the image.
+fn read_uki_section(esp: &Path) -> FileContent {
+    let image = esp::running_image_path()?;
+    let mut file = File::open(esp.join(esp::esp_relative_path(&image)))
+        .map_err(|why| format!("Failed to open {image}: {why}"))?;
+    let header = pe::read_section_headers(&mut file)?
+        .into_iter()
+        .find(|header| header.name == UKI_SECTION)
+        .ok_or("the running image has no such section")?;
+    pe::read_section(&mut file, &header)
+}
+
+/// Read the config file on the ESP, if the menu config trusts it, and the section of the running UKI.
+fn read_from_esp(esp: &Path, trust_esp: bool) -> (Option<FileContent>, FileContent) {
+    let config =
+        trust_esp.then(|| std::fs::read(esp.join(ESP_CONFIG_PATH)).map_err(|why| why.to_string()));
+    (config, read_uki_section(esp))
+}
+
+/// Find the encryption config.
+///
+/// Anyone who can write to the ESP could put a config with a password they know there, and log in with it,
+/// so the file on the ESP is only used if the menu config, which is in the signed initramfs, opts into it.
+/// In that case it is tried first, because it is the easiest to change.
+/// Then a section of the UKI itself, then the initramfs, and finally the copy compiled into the binary are tried,
+/// all of which are covered by the UKI's signature.
+/// The first one that parses is used.
+/// The reasons why the others were skipped are returned, so that they can be shown if nothing worked.
+pub fn load(trust_esp: bool) -> (Option<LoadedConfig>, Vec<String>) {
+    let mut errors = vec![];
+
+    let (esp_config, uki_section) = match esp::with_esp(|esp| read_from_esp(esp, trust_esp)) {
+        Ok(files) => files,
+        Err(why) => (trust_esp.then(|| Err(why.clone())), Err(why)),
+    };
+
+    let mut candidates: Vec<(ConfigSource, FileContent)> = vec![];
+    if let Some(esp_config) = esp_config {
+        candidates.push((ConfigSource::Esp, esp_config));
+    }
+    candidates.extend([
+        (ConfigSource::UkiSection, uki_section),
+        (
+            ConfigSource::Initramfs,
+            std::fs::read(INITRAMFS_CONFIG_PATH).map_err(|why| why.to_string()),
+        ),
+        (
+            ConfigSource::CompiledIn,
+            Ok(include_bytes!("../../disk-crypto/encrypt-config.json").to_vec()),
+        ),
+    ]);
+    for (source, text) in candidates {
+        match text.and_then(|text| parse(&text)) {
+            Ok(params) => {
+                println!("Using encryption config from {source}");
+                return (Some(LoadedConfig { params, source }), errors);
+            }
+            Err(why) => {
+                println!("Skipping encryption config from {source}: {why}");
+                errors.push(format!("{source}: {why}"));
+            }
+        }
+    }
+    (None, errors)
+}
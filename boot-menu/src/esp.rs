@@ -0,0 +1,91 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use crate::{
+    block_device,
+    cmdline::DeviceSpec,
+    efivarfs,
+    loader_interface::{decode_utf16, LOADER_VENDOR},
+};
+
+/// Where the ESP is mounted while we read from it.
+/// This is unmounted again afterwards, so that it doesn't get moved into the real root with `/run`.
+pub const ESP_MOUNTPOINT: &str = "/run/boot-menu/esp";
+
+/// Read one of the string variables set by the stub.
+fn read_loader_string(name: &str) -> Result<String, String> {
+    let (_, value) = efivarfs::read(name, LOADER_VENDOR)?;
+    Ok(decode_utf16(&value))
+}
+
+/// The PARTUUID of the ESP that the running image was loaded from.
+pub fn esp_partuuid() -> Result<String, String> {
+    Ok(read_loader_string("LoaderDevicePartUUID")?.to_lowercase())
+}
+
+/// The path of the running image on the ESP, like `\EFI\Linux\arch-linux.efi`.
+pub fn running_image_path() -> Result<String, String> {
+    read_loader_string("LoaderImageIdentifier")
+}
+
+/// Turn an EFI path on the ESP, like `\EFI\Linux` or `/EFI/Linux`, into a path relative to where it is mounted.
+pub fn esp_relative_path(efi_path: &str) -> PathBuf {
+    PathBuf::from(efi_path.replace('\\', "/").trim_start_matches('/'))
+}
+
+/// Run a command, and return its stderr as the error if it fails.
+pub fn run(command: &mut std::process::Command) -> Result<(), String> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|why| format!("Failed to spawn {command:?}: {why}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{command:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+/// Mount the ESP read-only, call the function with the mountpoint, then unmount it.
+pub fn with_esp<T>(f: impl FnOnce(&Path) -> T) -> Result<T, String> {
+    let spec = DeviceSpec::PartUuid(esp_partuuid()?);
+    let device = block_device::resolve(&spec).ok_or(format!("ESP device {spec} not found"))?;
+
+    std::fs::create_dir_all(ESP_MOUNTPOINT)
+        .map_err(|why| format!("Failed to create {ESP_MOUNTPOINT}: {why}"))?;
+    run(std::process::Command::new("mount")
+        .args(["-t", "vfat", "-o", "ro"])
+        .arg(&device)
+        .arg(ESP_MOUNTPOINT))?;
+
+    let result = f(Path::new(ESP_MOUNTPOINT));
+
+    run(std::process::Command::new("umount").arg(ESP_MOUNTPOINT))?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::esp_relative_path;
+    use crate::loader_interface::decode_utf16;
+
+    #[test]
+    fn test_loader_image_identifier() {
+        let raw: Vec<u8> = "\\EFI\\Linux\\arch.efi\0"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let path = decode_utf16(&raw);
+        assert_eq!(path, "\\EFI\\Linux\\arch.efi");
+        assert_eq!(
+            esp_relative_path(&path),
+            PathBuf::from("EFI/Linux/arch.efi")
+        );
+    }
+}
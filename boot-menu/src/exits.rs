@@ -1,14 +1,27 @@
-use std::{
-    io::{Cursor, Read, Seek, Write},
-    process::Stdio,
+use std::io::Write;
+
+use cursive::{align::HAlign, view::Nameable, views, Cursive, View};
+
+use crate::{
+    boot_entries::{filter_boot_options, list_boot_options, set_boot_next, BootOption},
+    boot_manager::boot_manager,
+    cmdline_editor::cmdline_editor,
+    default_entry::{default_entry, is_remembered, save_last_choice, COUNTDOWN_VIEW},
+    efi_tools::efi_tool_menu,
+    efivarfs::{self, DEFAULT_ATTRIBUTES, EFI_GLOBAL_VARIABLE},
+    hibernation::{find_image, hibernation_prompt},
+    kexec::{self, KexecTarget},
+    loader_interface::{export_entry_selected, export_exec_time},
+    lvm::{activate_volume_groups, logical_volume_picker},
+    menu_config::{MenuAction, MenuConfig, MenuEntry},
+    password_input::{config_unavailable_dialog, login_prompt},
+    snapshots::snapshot_picker,
+    spinner::spinner_view,
+    unlock::{discard_keyfile, open_volume, plan_volumes, write_keyfile, VolumeToOpen},
+    var_browser::variable_browser,
+    LoginState, State,
 };
 
-use cursive::{align::HAlign, views, Cursive, View};
-use efivar::efi::{VariableFlags, VariableName};
-use rand::Rng;
-
-use crate::{password_input::password_entry, spinner::spinner_view, LoginState, State};
-
 // These values are used for Linux syscalls and are taken from https://man7.org/linux/man-pages/man2/reboot.2.html
 pub const LINUX_REBOOT_MAGIC1: usize = 0xfee1dead;
 pub const LINUX_REBOOT_MAGIC2: usize = 0x28121969;
@@ -17,35 +30,54 @@ pub const LINUX_REBOOT_CMD_CAD_OFF: usize = 0;
 pub const LINUX_REBOOT_CMD_POWER_OFF: usize = 0x4321fedc;
 pub const LINUX_REBOOT_CMD_RESTART: usize = 0x1234567;
 
-pub enum BootMenuExitOption {
-    Arch,
-    Windows,
-    Uefi,
-    Poweroff,
-    Reboot,
+/// The bit of OsIndications that asks the firmware to show its settings UI on the next boot.
+/// See: https://uefi.org/specs/UEFI/2.10/08_Services_Runtime_Services.html#exchanging-information-between-the-os-and-firmware
+const EFI_OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 0x1;
+
+/// OsIndications and OsIndicationsSupported are 64-bit little-endian integers.
+fn read_u64(value: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    let len = value.len().min(8);
+    buf[..len].copy_from_slice(&value[..len]);
+    u64::from_le_bytes(buf)
 }
 
-pub fn full_menu() -> impl View {
-    views::Dialog::around({
-        let mut select = views::SelectView::new()
-            // Center the text horizontally
-            .h_align(HAlign::Center)
-            // Use keyboard to jump to the pressed letters
-            .autojump();
-        select.add_item("Boot into Arch Linux", BootMenuExitOption::Arch);
-        select.add_item("Boot into Windows", BootMenuExitOption::Windows);
-        select.add_item("Boot into UEFI Settings", BootMenuExitOption::Uefi);
-        select.add_item("Reboot", BootMenuExitOption::Reboot);
-        select.add_item("Poweroff", BootMenuExitOption::Poweroff);
+/// This builds the list of menu entries that should be visible in the current login state.
+fn entry_select(entries: &[MenuEntry], logged_in: bool) -> views::SelectView<MenuEntry> {
+    let mut select = views::SelectView::new()
+        // Center the text horizontally
+        .h_align(HAlign::Center)
+        // Use keyboard to jump to the pressed letters
+        .autojump();
+    for entry in entries {
+        if entry.requires_auth && !logged_in {
+            continue;
+        }
+        select.add_item(entry.label.clone(), entry.clone());
+    }
+    select
+}
 
-        select.set_on_submit(choose_exit);
+/// The countdown to choosing the default entry is shown under the entries.
+fn with_countdown(select: impl View) -> impl View {
+    views::LinearLayout::vertical()
+        .child(select)
+        .child(views::TextView::new("").with_name(COUNTDOWN_VIEW))
+}
 
-        select
+pub fn full_menu(menu: &MenuConfig, last_choice: Option<&str>) -> impl View {
+    views::Dialog::around({
+        let mut select = entry_select(&menu.entries, true);
+        if let Some(index) = default_entry(&menu.entries, last_choice, true) {
+            select.set_selection(index);
+        }
+        select.set_on_submit(choose_entry);
+        with_countdown(select)
     })
     .title("Boot menu")
 }
 
-pub fn partial_menu() -> impl View {
+pub fn partial_menu(menu: &MenuConfig, last_choice: Option<&str>) -> impl View {
     views::Dialog::around({
         let mut select = views::SelectView::new()
             // Center the text horizontally
@@ -53,305 +85,495 @@ pub fn partial_menu() -> impl View {
             // Use keyboard to jump to the pressed letters
             .autojump();
         select.add_item("Try logging in again", None);
-        select.add_item("Reboot", Some(BootMenuExitOption::Reboot));
-        select.add_item("Poweroff", Some(BootMenuExitOption::Poweroff));
+        for (label, entry) in entry_select(&menu.entries, false).iter() {
+            select.add_item(label, Some(entry.clone()));
+        }
+        if let Some(index) = default_entry(&menu.entries, last_choice, false) {
+            select.set_selection(index + 1);
+        }
 
         select.set_on_submit(|siv, v| match v {
             None => {
-                // Set the state to be logging in.
+                // Without an encryption config, there's nothing to log in with.
                 let data: &mut State = siv.user_data().unwrap();
+                if data.config.is_none() {
+                    config_unavailable_dialog(siv);
+                    return;
+                }
+
+                // Set the state to be logging in.
                 *data.login_state.lock().unwrap() = LoginState::WaitingForLogin;
 
-                // Pop the current layer and spawn the password entry box.
-                // If we need Yubikey, it'll get swapped out soon.
+                // Pop the current layer and spawn the login prompt.
+                // If a Yubikey is plugged in, it'll get swapped out soon.
                 siv.pop_layer();
-                password_entry(siv);
+                login_prompt(siv);
             }
-            Some(choice) => choose_exit(siv, choice),
+            Some(entry) => choose_entry(siv, entry),
         });
 
-        select
+        with_countdown(select)
     })
     .title("Boot menu")
 }
 
-/// This function terminates the boot menu in one of several ways.
-pub fn choose_exit(siv: &mut Cursive, choice: &BootMenuExitOption) {
-    match choice {
-        BootMenuExitOption::Arch => {
-            // Booting into Arch just means exiting the program and continuing the boot process.
-            // This stanza is therefore allowed to use `unwrap`s, since those will exit the program just as well.
+/// This function shows the entries of a submenu on top of the current menu.
+fn submenu(siv: &mut Cursive, title: &str, entries: &[MenuEntry]) {
+    let data: &mut State = siv.user_data().unwrap();
+    let logged_in = matches!(*data.login_state.lock().unwrap(), LoginState::LogInOkay);
+
+    let mut select = entry_select(entries, logged_in);
+    select.set_on_submit(choose_exit);
+    siv.add_layer(
+        views::Dialog::around(select)
+            .title(title)
+            .dismiss_button("Back"),
+    );
+}
 
-            // Because we're keeping the current kernel, we should disable the CAD key combination.
-            // This will allow using it in user space safely.
-            unsafe {
-                syscalls::syscall!(
-                    syscalls::Sysno::reboot,
-                    LINUX_REBOOT_MAGIC1,
-                    LINUX_REBOOT_MAGIC2,
-                    LINUX_REBOOT_CMD_CAD_OFF,
-                    0
-                )
-            }
-            .unwrap();
-            // Since we now know that we're booting Arch,
-            // this is also when we perform the expensive video card initialization.
-            // This also happens in the bash script after the program, so it's not a problem if it fails.
+/// This function shows the active EFI boot entries, and boots the selected one once.
+fn boot_once_menu(siv: &mut Cursive, include: &[String], exclude: &[String]) {
+    let boot_options = match list_boot_options() {
+        Ok(o) => filter_boot_options(o, include, exclude),
+        Err(why) => {
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(format!(
+                    "Listing EFI boot entries failed: {why}"
+                )))
+                .dismiss_button("Return to menu"),
+            );
+            return;
+        }
+    };
 
-            let mut modprobe_handle = std::process::Command::new("modprobe")
-                .arg("nouveau")
-                .spawn()
-                .unwrap();
+    boot_option_chooser(siv, "Boot once", boot_options);
+}
 
-            // Now we need to get the keyfile content,
-            // and write it to disk.
-            // The root partition is currently on ramdisk,
-            // so we can just write it there.
+/// This function shows a list of EFI boot entries, and boots the selected one once.
+fn boot_option_chooser(siv: &mut Cursive, title: &str, boot_options: Vec<BootOption>) {
+    let mut select = views::SelectView::new()
+        // Center the text horizontally
+        .h_align(HAlign::Center)
+        // Use keyboard to jump to the pressed letters
+        .autojump();
+    let first_path = boot_options
+        .first()
+        .map(BootOption::paths_text)
+        .unwrap_or_default();
+    for option in boot_options {
+        select.add_item(option.description.clone(), option);
+    }
+    select.set_on_submit(boot_once);
 
-            siv.add_layer(views::Dialog::around(
-                views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
-                    .child(spinner_view())
-                    .child(views::TextView::new("Unlocking system disk...")),
-            ));
+    // Show where the selected entry points, since the descriptions are often not enough to tell them apart.
+    select.set_on_select(|siv, option| {
+        siv.call_on_name("boot_once_path", |view: &mut views::TextView| {
+            view.set_content(option.paths_text())
+        });
+    });
+    siv.add_layer(
+        views::Dialog::around(
+            views::LinearLayout::vertical()
+                .child(select)
+                .child(views::DummyView)
+                .child(views::TextView::new(first_path).with_name("boot_once_path")),
+        )
+        .title(title)
+        .dismiss_button("Back"),
+    );
+}
 
-            // At this point, there should be no opportunity for the keyfile to not be present.
-            // That means that we can use unwraps here;
-            // but, if it turns out to not be present, then the normal encrypt fallback will be called.
-            let data: &mut State = siv.user_data().unwrap();
-            let keyfile = data.keyfile.as_ref().unwrap().to_vec();
-
-            // In order for menus to appear, all this needs to be happening in a thread.
-            let cb_sink = siv.cb_sink().clone();
-            std::thread::spawn(move || {
-                // Write the keyfile to the `/crypto_keyfile.bin` file, so that if we fail to run cryptsetup,
-                // the normal encrypt hook can try it before falling back to password.
-                let mut out = std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open("/crypto_keyfile.bin")
-                    .unwrap();
-                out.write_all(&keyfile).unwrap();
-                // Prepare to overwrite the keyfile if we succeed in unlocking the drive.
-                out.seek(std::io::SeekFrom::Start(0)).unwrap();
-                // Try opening the drive
-                let child = std::process::Command::new("cryptsetup")
-                    .arg("--key-file")
-                    .arg("/crypto_keyfile.bin")
-                    .arg("open")
-                    .arg("/dev/nvme0n1p3") // NOTE: this is where the main cryptdisk is on my system!
-                    .arg("cryptlvm")
-                    .stdin(Stdio::null())
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .spawn()
-                    .unwrap();
-
-                let output = child.wait_with_output().unwrap();
-                if !output.status.success() {
-                    // If failed to decrypt, show a message about this.
-                    // Do not exit on my own.
-                    cb_sink
-                    .send(Box::new(move |siv| {
-                        siv.add_layer(views::Dialog::around(views::TextView::new(&format!("Failed to unlock disk with cryptsetup:\n{}\n{}\nYou will need to use the backup password.", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)))).button("Exit", |siv| siv.quit()));
-                        ()
-                    }))
-                    .unwrap();
+/// Set BootNext to this entry and reboot.
+fn boot_once(siv: &mut Cursive, target: &BootOption) {
+    match set_boot_next(target.id) {
+        Ok(()) => {
+            // The BootNext has been set, and now we need to reboot into the target.
+            siv.add_layer(views::Dialog::around(views::TextView::new(format!(
+                "Rebooting into {}...",
+                target.description
+            ))));
+            reboot(siv);
+        }
+        Err(why) => {
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(why)).dismiss_button("Return to menu"),
+            );
+        }
+    }
+}
 
-                    return;
+/// This function lists the kernels in the directory on the ESP, and boots the selected one with kexec.
+fn kexec_menu(siv: &mut Cursive, directory: String) {
+    // Reading the UKIs to find out what's in them takes a while.
+    siv.add_layer(views::Dialog::around(
+        views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
+            .child(spinner_view())
+            .child(views::TextView::new(format!(
+                "Looking for kernels in {directory}..."
+            ))),
+    ));
+    let cb_sink = siv.cb_sink().clone();
+    std::thread::spawn(move || {
+        let result = kexec::scan(&directory);
+        cb_sink
+            .send(Box::new(move |siv| {
+                siv.pop_layer();
+                let targets = match result {
+                    Ok(targets) if targets.is_empty() => {
+                        siv.add_layer(
+                            views::Dialog::around(views::TextView::new(format!(
+                                "No kernels were found in {directory}."
+                            )))
+                            .dismiss_button("Return to menu"),
+                        );
+                        return;
+                    }
+                    Ok(targets) => targets,
+                    Err(why) => {
+                        siv.add_layer(
+                            views::Dialog::around(views::TextView::new(why))
+                                .dismiss_button("Return to menu"),
+                        );
+                        return;
+                    }
+                };
+
+                let mut select = views::SelectView::new()
+                    // Center the text horizontally
+                    .h_align(HAlign::Center)
+                    // Use keyboard to jump to the pressed letters
+                    .autojump();
+                for target in targets {
+                    select.add_item(target.label(), target);
                 }
+                select.set_on_submit(kexec_into);
+                siv.add_layer(
+                    views::Dialog::around(select)
+                        .title("Boot with kexec")
+                        .dismiss_button("Back"),
+                );
+            }))
+            .unwrap();
+    });
+}
 
-                // If here, successfully unlocked!
-                // Wipe the keyfile by overwriting the content with random multiple times.
-                let mut rng = rand::rngs::OsRng::default();
-                let mut chunk: [u8; 512] = [0; 512];
-                for _ in 0..4 {
-                    let mut written = 0;
-                    out.seek(std::io::SeekFrom::Start(0)).unwrap();
-
-                    while written < keyfile.len() {
-                        rng.fill(&mut chunk);
-                        written += out.write(&chunk).unwrap();
+/// Load the kernel, then leave the menu to start it.
+/// The volumes are closed and the kernel is started by `main` once the TUI is gone.
+pub fn kexec_into(siv: &mut Cursive, target: &KexecTarget) {
+    siv.add_layer(views::Dialog::around(
+        views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
+            .child(spinner_view())
+            .child(views::TextView::new(format!(
+                "Loading {}...",
+                target.label()
+            ))),
+    ));
+    let cb_sink = siv.cb_sink().clone();
+    let target = target.clone();
+    std::thread::spawn(move || {
+        let result = kexec::load(&target);
+        cb_sink
+            .send(Box::new(move |siv| {
+                siv.pop_layer();
+                match result {
+                    Ok(()) => {
+                        let data: &mut State = siv.user_data().unwrap();
+                        data.kexec_loaded = true;
+                        siv.quit();
                     }
+                    Err(why) => siv.add_layer(
+                        views::Dialog::around(views::TextView::new(why))
+                            .dismiss_button("Return to menu"),
+                    ),
                 }
+            }))
+            .unwrap();
+    });
+}
 
-                // Clear the screen of layers
-                // (We probably have fewer than 8 layers)
-                for _ in 0..8 {
-                    cb_sink
-                        .send(Box::new(|siv| {
-                            siv.pop_layer();
-                            ()
-                        }))
-                        .unwrap();
-                }
+/// Check that the user has logged in, and if not, tell them that they need to to do this.
+fn require_login(siv: &mut Cursive, what: &str) -> bool {
+    let data: &mut State = siv.user_data().unwrap();
+    if matches!(*data.login_state.lock().unwrap(), LoginState::LogInOkay) {
+        return true;
+    }
+    siv.add_layer(
+        views::Dialog::around(views::TextView::new(format!(
+            "You need to log in to {what}."
+        )))
+        .dismiss_button("Return to menu"),
+    );
+    false
+}
 
-                modprobe_handle.wait().unwrap();
-                cb_sink.send(Box::new(|siv| siv.quit())).unwrap();
-
-                // Before continuing, we should also clear the screen.
-                // To do this, we print the output of the `clear` command to the screen.
-                let clear_screen_magic = [
-                    0x1b, 0x5b, 0x48, 0x1b, 0x5b, 0x32, 0x4a, 0x1b, 0x5b, 0x33, 0x4a,
-                ];
-                std::io::stdout()
-                    .lock()
-                    .write_all(&clear_screen_magic)
-                    .unwrap();
-                std::io::stdout().lock().flush().unwrap();
-
-                // At this point, we should be exiting fully.
-            });
-        }
-        BootMenuExitOption::Windows => {
-            // To boot into Windows, we need to first find the boot menu entry corresponding to it.
-            let manager = efivar::system();
+/// This is called when an entry of the top-level menu is chosen.
+/// It is remembered as the default for the next boot, then it's run.
+pub fn choose_entry(siv: &mut Cursive, entry: &MenuEntry) {
+    // Not being able to remember it is not a reason to not do what was asked.
+    if is_remembered(&entry.action) {
+        let _ = save_last_choice(&entry.label);
+    }
+    let _ = export_entry_selected(&entry.label);
+    choose_exit(siv, entry);
+}
 
-            let boot_menu_entries = manager.get_boot_entries();
-            let boot_menu_entries = match boot_menu_entries {
-                Ok(e) => e,
-                Err(why) => {
-                    siv.add_layer(
-                        views::Dialog::around(views::TextView::new(format!(
-                            "Listing EFI boot entries failed: {why:?}"
-                        )))
-                        .dismiss_button("Return to menu"),
-                    );
-                    return;
+/// Unlock the volumes, then call `then`, which is what leaves the menu.
+/// This needs a login, and otherwise says that one is needed to do `what`.
+/// Booting into Arch just means exiting the program and continuing the boot process.
+/// This function is therefore allowed to use `unwrap`s, since those will exit the program just as well.
+fn continue_boot(siv: &mut Cursive, what: &str, then: fn(&mut Cursive)) {
+    // The entry can be in the menu without `requires_auth`, or be chosen by the countdown before logging in,
+    // so the keyfiles are only there if this check passes.
+    if !require_login(siv, what) {
+        return;
+    }
+    // The volume named on the cmdline is found in the same way as by the encrypt hook.
+    let data: &mut State = siv.user_data().unwrap();
+    let volumes = plan_volumes(data.keyfiles.as_ref().unwrap(), &data.unlock_targets);
+
+    // Because we're keeping the current kernel, we should disable the CAD key combination.
+    // This will allow using it in user space safely.
+    unsafe {
+        syscalls::syscall!(
+            syscalls::Sysno::reboot,
+            LINUX_REBOOT_MAGIC1,
+            LINUX_REBOOT_MAGIC2,
+            LINUX_REBOOT_CMD_CAD_OFF,
+            0
+        )
+    }
+    .unwrap();
+    // Since we now know that we're booting Arch,
+    // this is also when we perform the expensive video card initialization.
+    // This also happens in the bash script after the program, so it's not a problem if it fails.
+
+    let mut modprobe_handle = std::process::Command::new("modprobe")
+        .arg("nouveau")
+        .spawn()
+        .unwrap();
+
+    // Now we need to write the keyfiles to disk, and unlock the volumes with them.
+    // The root partition is currently on ramdisk,
+    // so we can just write them there.
+
+    siv.add_layer(
+        views::Dialog::around(
+            views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
+                .child(spinner_view())
+                .child(views::TextView::new("Unlocking system disk...").with_name("unlock_status")),
+        )
+        .title("Unlocking volumes"),
+    );
+
+    // In order for menus to appear, all this needs to be happening in a thread.
+    let cb_sink = siv.cb_sink().clone();
+    std::thread::spawn(move || {
+        // Each volume gets a line saying how unlocking it went.
+        let mut report: Vec<String> = vec![];
+        let mut all_unlocked = true;
+        let show_status = |report: &[String], current: Option<String>| {
+            let mut lines = report.to_vec();
+            lines.extend(current);
+            let text = lines.join("\n");
+            cb_sink
+                .send(Box::new(move |siv| {
+                    siv.call_on_name("unlock_status", |view: &mut views::TextView| {
+                        view.set_content(text)
+                    });
+                }))
+                .unwrap();
+        };
+
+        for volume in &volumes {
+            let result = match volume {
+                Err((name, why)) => Err((name.clone(), why.clone())),
+                Ok(volume) => {
+                    show_status(&report, Some(format!("{}: unlocking...", volume.name)));
+                    open_volume(volume, || {
+                        show_status(
+                            &report,
+                            Some(format!(
+                                "{}: waiting for {} to appear...",
+                                volume.name, volume.target.device
+                            )),
+                        )
+                    })
+                    .map_err(|why| (volume.name.clone(), why))
+                    .map(|_| volume.name.clone())
                 }
             };
+            match result {
+                Ok(name) => report.push(format!("{name}: unlocked")),
+                Err((name, why)) => {
+                    all_unlocked = false;
+                    report.push(format!("{name}: FAILED: {why}"));
+                }
+            }
+            show_status(&report, None);
+        }
 
-            // The boot entries don't have their ID inside them,
-            // however they are yielded in the BootOrder's order.
-            // https://github.com/iTrooz/efiboot-rs/issues/75
-            // So, we can also fetch the BootOrder variable
-            // and retrieve the values from there.
-            let mut boot_order_value = vec![0u8; 512]; // 256 boot entries should be enough
-            let boot_order_read_result =
-                manager.read(&VariableName::new("BootOrder"), &mut boot_order_value);
-
-            let boot_order_read_result = match boot_order_read_result {
-                Ok(e) => e,
-                Err(why) => {
+        if !all_unlocked {
+            // If failed to decrypt, show a message about this.
+            // Do not exit on my own.
+            let text = report.join("\n");
+            cb_sink
+                .send(Box::new(move |siv| {
                     siv.add_layer(
                         views::Dialog::around(views::TextView::new(format!(
-                            "Listing EFI boot entries failed: {why:?}"
+                            "Failed to unlock some volumes:\n{text}\nYou will need to use the backup password."
                         )))
-                        .dismiss_button("Return to menu"),
+                        .title("Unlocking volumes")
+                        .button("Exit", |siv| siv.quit()),
                     );
-                    return;
-                }
-            };
+                }))
+                .unwrap();
 
-            if boot_order_read_result.0 % 2 != 0 {
-                siv.add_layer(
-                    views::Dialog::around(views::TextView::new(format!(
-                        "EFI boot entries list has length {}, which is not allowed.",
-                        boot_order_read_result.0
-                    )))
-                    .dismiss_button("Return to menu"),
-                );
-                return;
-            };
+            return;
+        }
 
-            boot_order_value.truncate(boot_order_read_result.0);
-            let mut boot_order_cursor = Cursor::new(boot_order_value);
+        // The lvm2 hook does this later too, but the logical volumes have to be there
+        // for choosing the root from them, or from their btrfs snapshots.
+        // If it fails here, the lvm2 hook gets another go.
+        let _ = activate_volume_groups();
+
+        // If here, successfully unlocked everything!
+        // Clear the screen of layers
+        // (We probably have fewer than 8 layers)
+        for _ in 0..8 {
+            cb_sink
+                .send(Box::new(|siv| {
+                    siv.pop_layer();
+                    ()
+                }))
+                .unwrap();
+        }
 
-            let mut target_boot_entry_id = None;
+        modprobe_handle.wait().unwrap();
+        // Nothing on the unlocked volumes has been mounted yet, so it's not too late to resume.
+        let image = find_image();
+        cb_sink
+            .send(Box::new(move |siv| match image {
+                Some(device) => hibernation_prompt(siv, device, then),
+                None => then(siv),
+            }))
+            .unwrap();
+    });
+}
 
-            for (iter_index, entry) in boot_menu_entries.enumerate() {
-                let mut boot_entry_id = [0, 0];
-                if boot_order_cursor.read_exact(&mut boot_entry_id).is_err() {
-                    siv.add_layer(
-                        views::Dialog::around(views::TextView::new(format!(
-                        "Could not retrieve the ID of boot entry which is {iter_index} in order."
-                    )))
-                        .dismiss_button("Return to menu"),
-                    );
-                    return;
-                }
-                let entry = match entry {
-                    Ok(e) => e,
-                    Err(why) => {
-                        siv.add_layer(
-                            views::Dialog::around(views::TextView::new(format!(
-                                "Failed to get entry number {iter_index} while iterating: {why:?}"
-                            )))
-                            .dismiss_button("Return to menu"),
-                        );
-                        return;
-                    }
-                };
+/// Leave the menu, so that the rest of the initramfs continues booting with the unlocked volumes.
+pub fn finish_boot(siv: &mut Cursive) {
+    // This is when we hand over to the system, as far as systemd-analyze is concerned.
+    let _ = export_exec_time();
+    siv.quit();
+
+    // Before continuing, we should also clear the screen.
+    // To do this, we print the output of the `clear` command to the screen.
+    let clear_screen_magic = [
+        0x1b, 0x5b, 0x48, 0x1b, 0x5b, 0x32, 0x4a, 0x1b, 0x5b, 0x33, 0x4a,
+    ];
+    std::io::stdout()
+        .lock()
+        .write_all(&clear_screen_magic)
+        .unwrap();
+    std::io::stdout().lock().flush().unwrap();
+
+    // At this point, we should be exiting fully.
+}
 
-                // TODO: better way to find which of the entries is the Windows one.
-                // For now, use the entry's name
-                if entry.description == "Windows Boot Manager" {
-                    // Found entry, now need to set it to BootNext.
-                    // Because we're iterating these in the BootOrder sequence,
-                    // the iter_index is not the value we're looking for.
-                    // Instead, it's the value from boot_entry_id.
-                    // It's encoded as little-endian, and we need to write it the same way,
-                    // so we won't bother decoding it.
-                    target_boot_entry_id = Some(boot_entry_id);
-                    break;
+/// Run a shell on its own VT, and return to the menu when it exits.
+/// If `unlock_volumes` is set, these volumes are unlocked first, so that the shell can get at their mapper devices.
+/// Otherwise, their keyfiles are written out for the shell, and removed when it exits.
+fn run_shell(
+    siv: &mut Cursive,
+    volumes: Vec<Result<VolumeToOpen, (String, String)>>,
+    unlock_volumes: bool,
+) {
+    // The shell runs on its own VT, so that it doesn't fight the menu over the terminal.
+    // `openvt -s -w` switches to the new VT, and waits for the shell to exit.
+    siv.add_layer(views::Dialog::around(views::TextView::new(
+        "A shell is running on another VT.\nThe menu will return when it exits.",
+    )));
+
+    let cb_sink = siv.cb_sink().clone();
+    std::thread::spawn(move || {
+        let mut failures = vec![];
+        let mut written = vec![];
+        for volume in &volumes {
+            match volume {
+                Err((name, why)) => failures.push(format!("{name}: {why}")),
+                Ok(volume) if unlock_volumes => {
+                    if let Err(why) = open_volume(volume, || {}) {
+                        failures.push(format!("{}: {why}", volume.name));
+                    }
                 }
+                Ok(volume) => match write_keyfile(volume) {
+                    Ok(out) => written.push((volume, out)),
+                    Err(why) => failures.push(format!("{}: {why}", volume.name)),
+                },
             }
+        }
 
-            let target_boot_entry_id = match target_boot_entry_id {
-                Some(s) => s,
-                None => {
+        let result = std::process::Command::new("openvt")
+            .arg("-s")
+            .arg("-w")
+            .arg("--")
+            .arg("/bin/bash")
+            .status();
+        // The keyfiles were only for the shell; the one for the encrypt hook is kept.
+        for (volume, mut out) in written {
+            if let Err(why) = discard_keyfile(volume, &mut out, false) {
+                failures.push(format!("{}: {why}", volume.name));
+            }
+        }
+        cb_sink
+            .send(Box::new(move |siv| {
+                siv.pop_layer();
+                if !failures.is_empty() {
                     siv.add_layer(
                         views::Dialog::around(views::TextView::new(format!(
-                            "Could not find Windows boot option. Boot into another system to fix this."
+                            "Some volumes could not be prepared for the shell:\n{}",
+                            failures.join("\n")
                         )))
                         .dismiss_button("Return to menu"),
                     );
-                    return;
                 }
-            };
-            let _attrs = VariableFlags::NON_VOLATILE
-                & VariableFlags::BOOTSERVICE_ACCESS
-                & VariableFlags::RUNTIME_ACCESS;
-
-            // FIXME: for some reason, doing this with the library produces an Input-Output error.
-            // This is strange: normally, when writing wrong values there, only an Invalid Argument error is produced.
-            // This also happens when trying to execute the relevant operations manually!
-            // Shell out to tools to do this instead.
-
-            // let result =
-            //     manager.write(&VariableName::new("BootNext"), attrs, &target_boot_entry_id);
-            // match result {
-            //     Err(why) => {
-            //         siv.add_layer(
-            //             views::Dialog::around(views::TextView::new(format!(
-            //                 "Failed to write BootNext value into EFI variables: {why:?}"
-            //             )))
-            //             .dismiss_button("Return to menu"),
-            //         );
-            //         return;
-            //     }
-            //     Ok(_) => {
-            //         // The BootNext has been set, and now we need to reboot into Windows.
-            //         siv.add_layer(views::Dialog::around(views::TextView::new(format!(
-            //             "Rebooting into Windows..."
-            //         ))));
-            //     }
-            // };
-
-            let child = match std::process::Command::new("efibootmgr")
-                .arg("-n")
-                .arg(format!(
-                    "{:02X}{:02X}",
-                    target_boot_entry_id[1], target_boot_entry_id[0]
-                ))
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-            {
-                Ok(c) => c,
+                match result {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => siv.add_layer(
+                        views::Dialog::around(views::TextView::new(format!(
+                            "Shell exited with code: {:?}",
+                            status.code()
+                        )))
+                        .dismiss_button("Return to menu"),
+                    ),
+                    Err(why) => siv.add_layer(
+                        views::Dialog::around(views::TextView::new(format!(
+                            "Failed to spawn shell with openvt: {why:?}"
+                        )))
+                        .dismiss_button("Return to menu"),
+                    ),
+                }
+            }))
+            .unwrap();
+    });
+}
+
+/// This function terminates the boot menu in one of several ways, or opens a submenu titled with the entry's label.
+pub fn choose_exit(siv: &mut Cursive, entry: &MenuEntry) {
+    match &entry.action {
+        MenuAction::ContinueBoot => continue_boot(siv, "continue booting", finish_boot),
+        MenuAction::BootSnapshot => continue_boot(siv, "boot a snapshot", snapshot_picker),
+        MenuAction::ChooseRootVolume => {
+            continue_boot(siv, "choose the root volume", logical_volume_picker)
+        }
+        MenuAction::BootNext { target } => {
+            // To boot into another OS, we need to first find the boot menu entry corresponding to it.
+            let boot_options = match list_boot_options() {
+                Ok(o) => o,
                 Err(why) => {
                     siv.add_layer(
                         views::Dialog::around(views::TextView::new(format!(
-                            "Failed to spawn child process efibootmgr: {why:?}"
+                            "Listing EFI boot entries failed: {why}"
                         )))
                         .dismiss_button("Return to menu"),
                     );
@@ -359,146 +581,126 @@ pub fn choose_exit(siv: &mut Cursive, choice: &BootMenuExitOption) {
                 }
             };
 
-            match child.wait_with_output() {
-                Ok(exit) => {
-                    if exit.status.success() {
-                        // The BootNext has been set, and now we need to reboot into Windows.
-                        siv.add_layer(views::Dialog::around(views::TextView::new(format!(
-                            "Rebooting into Windows..."
-                        ))));
-                        choose_exit(siv, &BootMenuExitOption::Reboot);
-                        return;
-                    } else {
-                        siv.add_layer(
-                            views::Dialog::around(views::TextView::new(format!(
-                                "efibootmgr exited with code: {:?}\n{}\n{}",
-                                exit.status.code(),
-                                String::from_utf8_lossy(&exit.stdout),
-                                String::from_utf8_lossy(&exit.stderr),
-                            )))
-                            .dismiss_button("Return to menu"),
-                        );
-                        return;
-                    }
-                }
-                Err(why) => {
+            // The description changes with the firmware's language and can be edited,
+            // so the entry is usually found by the loader it points at.
+            let mut matching: Vec<BootOption> = boot_options
+                .into_iter()
+                .filter(|option| target.matches(option))
+                .collect();
+            match matching.len() {
+                0 => {
                     siv.add_layer(
                         views::Dialog::around(views::TextView::new(format!(
-                            "Failed to get response from efibootmgr: {why:?}"
+                            "Could not find boot option {target}. Boot into another system to fix this."
                         )))
                         .dismiss_button("Return to menu"),
                     );
-                    return;
                 }
+                1 => boot_once(siv, &matching.remove(0)),
+                _ => boot_option_chooser(siv, "Choose which one to boot", matching),
+            }
+        }
+        MenuAction::BootOnce { include, exclude } => boot_once_menu(siv, include, exclude),
+        MenuAction::EfiTool => {
+            // Something like the EFI shell can read and change anything the firmware can.
+            if require_login(siv, "run an EFI tool") {
+                efi_tool_menu(siv);
+            }
+        }
+        MenuAction::BootManager => {
+            // Changing the boot entries can make the machine unbootable,
+            // so this needs a login even if the menu config doesn't ask for one.
+            if require_login(siv, "change the boot entries") {
+                boot_manager(siv);
+            }
+        }
+        MenuAction::VariableBrowser => {
+            // The variables can contain things like the Wi-Fi passwords some firmwares store.
+            if require_login(siv, "look at the EFI variables") {
+                variable_browser(siv);
             }
         }
-        BootMenuExitOption::Uefi => {
+        MenuAction::Kexec { directory } => {
+            // Any kernel on the ESP can be booted from here, with its own initramfs and no login.
+            if require_login(siv, "boot another kernel") {
+                kexec_menu(siv, directory.clone());
+            }
+        }
+        MenuAction::CmdlineEditor { presets } => {
+            // Something like `init=/bin/sh` is as good as a root shell.
+            if require_login(siv, "change the kernel cmdline") {
+                cmdline_editor(siv, presets);
+            }
+        }
+        MenuAction::BootLoaderEntries => {
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(
+                    "No Boot Loader Specification entries were found on the ESP.",
+                ))
+                .dismiss_button("Return to menu"),
+            );
+        }
+        MenuAction::BootLoaderEntry { target } => {
+            if require_login(siv, "boot another kernel") {
+                kexec_into(siv, target);
+            }
+        }
+        MenuAction::FirmwareSetup => {
             // To reboot into UEFI, we need to set the OsIndications variable to indicate
             // that we want to boot to the firmware UI.
             // This is done by setting the least significant bit.
             // See: https://uefi.org/specs/UEFI/2.10/08_Services_Runtime_Services.html#exchanging-information-between-the-os-and-firmware
 
-            let manager = efivar::system();
-
             // But first, let's also check that OsIndicationsSupported has that least significant bit set.
-            let mut output = 0u64.to_le_bytes();
-            let read_result =
-                manager.read(&VariableName::new("OsIndicationsSupported"), &mut output);
-            if let Err(why) = read_result {
-                siv.add_layer(views::Dialog::around(views::TextView::new(format!(
-                    "Failed to read EFI OsIndicationsSupported: {why:?}\nEnter UEFI settings manually."
-                ))).dismiss_button("Return to menu"));
-                return;
-            }
-            let output = u64::from_le_bytes(output);
-            if !(output & 0x01 == 0x01) {
-                siv.add_layer(views::Dialog::around(views::TextView::new(format!(
-                    "EFI OsIndicationsSupported says UEFI settings are not supported (value is {output})."
-                ))).dismiss_button("Return to menu"));
-                return;
-            }
-
-            // Now that we know we can do this, we need to write the value 1 to the OsIndications.
-            // We don't need to worry about any other bits because we are the only OS right now.
-            // The attrs are the expected attrs for this variable.
-
-            // FIXME: for some reason, doing this with the library produces an Input-Output error.
-            // This is strange: normally, when writing wrong values there, only an Invalid Argument error is produced.
-            // This also happens when trying to execute the relevant operations manually!
-            // Shell out to tools to do this instead.
-
-            // let attrs = VariableFlags::NON_VOLATILE
-            //     & VariableFlags::BOOTSERVICE_ACCESS
-            //     & VariableFlags::RUNTIME_ACCESS;
-            // let write_result = manager.write(
-            //     &VariableName::new("OsIndications"),
-            //     attrs,
-            //     &1u64.to_le_bytes(),
-            // );
-            // if let Err(why) = write_result {
-            //     siv.add_layer(
-            //         views::Dialog::around(views::TextView::new(format!(
-            //             "Failed to write OsIndications: {why:?}\nEnter UEFI settings manually."
-            //         )))
-            //         .dismiss_button("Return to menu"),
-            //     );
-            //     return;
-            // }
-
-            // Use bootctl program to enable reboot to firmware
-
-            match std::process::Command::new("bootctl")
-                .arg("reboot-to-firmware")
-                .arg("1")
-                .spawn()
-            {
-                Ok(mut child) => {
-                    match child.wait() {
-                        Err(why) => {
-                            siv.add_layer(
-                                views::Dialog::around(views::TextView::new(format!(
-                                    "Failed to wait for bootctl: {why:?}\nEnter UEFI settings manually."
-                                )))
-                                .dismiss_button("Return to menu"),
-                            );
-                            return;
-                        }
-                        Ok(status) => {
-                            match status.success() {
-                                true => {
-                                    // Successfully set, continuing to reboot
-                                }
-                                false => {
-                                    siv.add_layer(views::Dialog::around(views::TextView::new(format!(
-                                        "Failed to use bootctl to set OsIndications (status is {:?})\nEnter UEFI settings manually.",
-                                        status.code()
-                                    )))
-                                    .dismiss_button("Return to menu"));
-
-                                    return;
-                                }
-                            }
-                        }
-                    }
-                }
+            let supported = match efivarfs::read("OsIndicationsSupported", EFI_GLOBAL_VARIABLE) {
+                Ok((_, value)) => read_u64(&value),
                 Err(why) => {
                     siv.add_layer(
                         views::Dialog::around(views::TextView::new(format!(
-                            "Failed to call bootctl: {why:?}\nEnter UEFI settings manually."
+                            "{why}\nEnter UEFI settings manually."
                         )))
                         .dismiss_button("Return to menu"),
                     );
                     return;
                 }
             };
+            if supported & EFI_OS_INDICATIONS_BOOT_TO_FW_UI == 0 {
+                siv.add_layer(views::Dialog::around(views::TextView::new(format!(
+                    "EFI OsIndicationsSupported says UEFI settings are not supported (value is {supported})."
+                ))).dismiss_button("Return to menu"));
+                return;
+            }
+
+            // Now that we know we can do this, we need to set that bit in OsIndications.
+            // Other bits may have been set by someone else, like a pending capsule update, so they are kept.
+            // The variable doesn't exist if nothing has been requested yet.
+            let indications = efivarfs::read("OsIndications", EFI_GLOBAL_VARIABLE)
+                .map(|(_, value)| read_u64(&value))
+                .unwrap_or(0);
+            let write_result = efivarfs::write(
+                "OsIndications",
+                EFI_GLOBAL_VARIABLE,
+                DEFAULT_ATTRIBUTES,
+                &(indications | EFI_OS_INDICATIONS_BOOT_TO_FW_UI).to_le_bytes(),
+            );
+            if let Err(why) = write_result {
+                siv.add_layer(
+                    views::Dialog::around(views::TextView::new(format!(
+                        "{why}\nEnter UEFI settings manually."
+                    )))
+                    .dismiss_button("Return to menu"),
+                );
+                return;
+            }
+
             siv.add_layer(views::Dialog::around(views::TextView::new(format!(
                 "Rebooting into UEFI..."
             ))));
 
             // Now that the OsIndications is written, we need to reboot.
-            choose_exit(siv, &BootMenuExitOption::Reboot);
+            reboot(siv);
         }
-        BootMenuExitOption::Poweroff => {
+        MenuAction::Poweroff => {
             // To poweroff, we need to call the Linux syscall reboot(2),
             // with an argument of LINUX_REBOOT_CMD_POWER_OFF (from the man page).
             // To be safe, we precede this with a call to sync(2).
@@ -529,33 +731,48 @@ pub fn choose_exit(siv: &mut Cursive, choice: &BootMenuExitOption) {
                 ))))
             }
         }
-        BootMenuExitOption::Reboot => {
-            // To reboot, we do the same procedure as above, except with a different argument to reboot().
-            let sync_result = unsafe { syscalls::syscall!(syscalls::Sysno::sync) };
-            if let Err(why) = sync_result {
-                let why = why.name_and_description();
-                siv.add_layer(views::Dialog::around(views::TextView::new(format!(
-                    "Failed to call sync() syscall: {why:?}\nReboot the system manually."
-                ))));
+        MenuAction::Reboot => reboot(siv),
+        MenuAction::RescueShell { unlock_volumes } => {
+            if !require_login(siv, "use the rescue shell") {
                 return;
             }
-
-            // Note: the `arg` parameter is explicitly set as zero. I think that's acceptable, but I don't know for sure.
-            let reboot_result = unsafe {
-                syscalls::syscall!(
-                    syscalls::Sysno::reboot,
-                    LINUX_REBOOT_MAGIC1,
-                    LINUX_REBOOT_MAGIC2,
-                    LINUX_REBOOT_CMD_RESTART,
-                    0
-                )
-            };
-            if let Err(why) = reboot_result {
-                let why = why.name_and_description();
-                siv.add_layer(views::Dialog::around(views::TextView::new(format!(
-                    "Failed to call reboot() syscall: {why:?}\nReboot the system manually."
-                ))))
-            }
+            let data: &mut State = siv.user_data().unwrap();
+            let volumes = plan_volumes(
+                data.keyfiles.as_deref().unwrap_or(&[]),
+                &data.unlock_targets,
+            );
+            run_shell(siv, volumes, *unlock_volumes);
         }
+        MenuAction::Submenu { entries } => submenu(siv, &entry.label, entries),
+    }
+}
+
+/// Reboot the system, after syncing the disks.
+pub fn reboot(siv: &mut Cursive) {
+    // This is the same procedure as for powering off, except with a different argument to reboot().
+    let sync_result = unsafe { syscalls::syscall!(syscalls::Sysno::sync) };
+    if let Err(why) = sync_result {
+        let why = why.name_and_description();
+        siv.add_layer(views::Dialog::around(views::TextView::new(format!(
+            "Failed to call sync() syscall: {why:?}\nReboot the system manually."
+        ))));
+        return;
+    }
+
+    // Note: the `arg` parameter is explicitly set as zero. I think that's acceptable, but I don't know for sure.
+    let reboot_result = unsafe {
+        syscalls::syscall!(
+            syscalls::Sysno::reboot,
+            LINUX_REBOOT_MAGIC1,
+            LINUX_REBOOT_MAGIC2,
+            LINUX_REBOOT_CMD_RESTART,
+            0
+        )
+    };
+    if let Err(why) = reboot_result {
+        let why = why.name_and_description();
+        siv.add_layer(views::Dialog::around(views::TextView::new(format!(
+            "Failed to call reboot() syscall: {why:?}\nReboot the system manually."
+        ))))
     }
 }
@@ -0,0 +1,163 @@
+//! Finding a hibernation image in swap after unlocking, and choosing between resuming from it and discarding it.
+//! Booting fresh while an image is there, and resuming from it later, would corrupt the filesystems
+//! that the hibernated system still has mounted, so the user has to decide.
+
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use cursive::{views, Cursive};
+
+use crate::{
+    block_device,
+    cmdline::{self, DeviceSpec},
+    lvm,
+};
+
+/// The swap header takes up the first page, and its signature is in the last 10 bytes of it.
+/// This is the page size on x86_64, which is what the menu runs on.
+const PAGE_SIZE: u64 = 4096;
+const SIGNATURE_SIZE: usize = 10;
+
+/// The signature of a swap area that isn't holding a hibernation image.
+const SWAP_SIGNATURE: &[u8; SIGNATURE_SIZE] = b"SWAPSPACE2";
+
+/// The signatures that the kernel and uswsusp put in place of the swap signature
+/// while there is a hibernation image.
+const HIBERNATION_SIGNATURES: [&[u8]; 2] = [b"S1SUSPEND", b"PMSUSPEND"];
+
+const SYS_POWER_RESUME: &str = "/sys/power/resume";
+
+/// Check the last bytes of the first page of a swap device for a hibernation signature.
+pub fn has_hibernation_signature(signature: &[u8]) -> bool {
+    HIBERNATION_SIGNATURES
+        .iter()
+        .any(|expected| signature.starts_with(expected))
+}
+
+fn read_signature(device: &Path) -> Option<[u8; SIGNATURE_SIZE]> {
+    let mut file = std::fs::File::open(device).ok()?;
+    let mut signature = [0; SIGNATURE_SIZE];
+    file.seek(SeekFrom::Start(PAGE_SIZE - SIGNATURE_SIZE as u64))
+        .ok()?;
+    file.read_exact(&mut signature).ok()?;
+    Some(signature)
+}
+
+/// The devices that could have a hibernation image: the `resume=` device, then every active logical volume.
+fn candidates() -> Vec<PathBuf> {
+    let mut devices = vec![];
+    if let Ok(Some(resume)) = cmdline::read_param("resume") {
+        devices.extend(block_device::resolve(&DeviceSpec::parse(&resume)));
+    }
+    if let Ok(volumes) = lvm::list_logical_volumes() {
+        devices.extend(
+            volumes
+                .iter()
+                .filter(|volume| volume.is_active())
+                .map(|volume| PathBuf::from(&volume.lv_dm_path)),
+        );
+    }
+    devices
+}
+
+/// Find the swap device with a hibernation image, if there is one.
+pub fn find_image() -> Option<PathBuf> {
+    candidates().into_iter().find(|device| {
+        read_signature(device).is_some_and(|signature| has_hibernation_signature(&signature))
+    })
+}
+
+/// Split a device number into its major and minor numbers, the way glibc's `major()` and `minor()` do.
+pub fn major_minor(rdev: u64) -> (u64, u64) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major, minor)
+}
+
+/// Ask the kernel to resume from the image on this device.
+/// If that works, the hibernated system takes over and this never returns.
+pub fn resume(device: &Path) -> Result<(), String> {
+    let metadata = std::fs::metadata(device)
+        .map_err(|why| format!("Failed to find {}: {why}", device.display()))?;
+    let (major, minor) = major_minor(metadata.rdev());
+    std::fs::write(SYS_POWER_RESUME, format!("{major}:{minor}"))
+        .map_err(|why| format!("Failed to resume from {}: {why}", device.display()))?;
+    Err(format!(
+        "The kernel did not resume from {}; the image may be from another kernel.",
+        device.display()
+    ))
+}
+
+/// Put the swap signature back, so that the image is never resumed from, and the swap can be used again.
+pub fn discard(device: &Path) -> Result<(), String> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(device)
+        .map_err(|why| format!("Failed to open {}: {why}", device.display()))?;
+    file.seek(SeekFrom::Start(PAGE_SIZE - SIGNATURE_SIZE as u64))
+        .and_then(|_| file.write_all(SWAP_SIGNATURE))
+        .and_then(|_| file.sync_all())
+        .map_err(|why| format!("Failed to discard the image on {}: {why}", device.display()))
+}
+
+/// Ask whether to resume from the image on this device, or discard it and call `then`, which continues the boot.
+pub fn hibernation_prompt(siv: &mut Cursive, device: PathBuf, then: fn(&mut Cursive)) {
+    let resume_device = device.clone();
+    siv.add_layer(
+        views::Dialog::around(views::TextView::new(format!(
+            "{} has a hibernation image.\n\
+             Resume the hibernated system, or discard the image and boot fresh?\n\
+             Anything not saved before hibernating will be lost if it's discarded.",
+            device.display()
+        )))
+        .title("Hibernation image found")
+        .button("Resume", move |siv| {
+            if let Err(why) = resume(&resume_device) {
+                siv.add_layer(
+                    views::Dialog::around(views::TextView::new(why))
+                        .title("Error")
+                        .dismiss_button("OK"),
+                );
+            }
+        })
+        .button("Discard and boot fresh", move |siv| {
+            match discard(&device) {
+                Ok(()) => {
+                    siv.pop_layer();
+                    then(siv);
+                }
+                Err(why) => siv.add_layer(
+                    views::Dialog::around(views::TextView::new(why))
+                        .title("Error")
+                        .dismiss_button("OK"),
+                ),
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::{has_hibernation_signature, major_minor};
+
+    #[test]
+    fn test_signature() {
+        assert!(has_hibernation_signature(b"S1SUSPEND\0"));
+        assert!(has_hibernation_signature(b"PMSUSPEND\0"));
+        assert!(!has_hibernation_signature(b"SWAPSPACE2"));
+        assert!(!has_hibernation_signature(b"\0\0\0\0\0\0\0\0\0\0"));
+    }
+
+    #[test]
+    fn test_major_minor() {
+        // /dev/dm-1
+        assert_eq!(major_minor(0xfe01), (254, 1));
+        // /dev/nvme0n1, with a major number that doesn't fit in 8 bits.
+        assert_eq!(major_minor(0x10300), (259, 0));
+        // A minor number that doesn't fit in 8 bits either.
+        assert_eq!(major_minor(0x100800), (8, 256));
+    }
+}
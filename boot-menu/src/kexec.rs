@@ -0,0 +1,340 @@
+//! Booting another kernel from the ESP without going through the firmware.
+//! See https://man7.org/linux/man-pages/man2/kexec_file_load.2.html
+
+use std::{
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    esp,
+    exits::{LINUX_REBOOT_MAGIC1, LINUX_REBOOT_MAGIC2},
+    pe,
+};
+
+/// Where the parts of a UKI are written for the kernel to load them.
+const KEXEC_DIR: &str = "/run/boot-menu/kexec";
+
+// These come from linux/kexec.h and linux/reboot.h.
+const KEXEC_FILE_NO_INITRAMFS: usize = 0x4;
+pub const LINUX_REBOOT_CMD_KEXEC: usize = 0x45584543;
+
+/// How a kernel found on the ESP is stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KernelKind {
+    /// A unified kernel image, with the kernel, initrd and cmdline as PE sections.
+    Uki,
+
+    /// A plain kernel, with the initrd of the same name next to it if there is one,
+    /// like `vmlinuz-linux-lts` and `initramfs-linux-lts.img`.
+    /// It is started with the cmdline of the running kernel.
+    Kernel { initrd: Option<String> },
+
+    /// A kernel described by a Boot Loader Specification entry,
+    /// with the initrds and options it lists. The initrd paths are relative to the root of the ESP.
+    Entry {
+        initrds: Vec<String>,
+        options: String,
+    },
+}
+
+/// A kernel that can be booted with kexec.
+/// The paths are relative to the root of the ESP.
+#[derive(Clone, Debug)]
+pub struct KexecTarget {
+    pub path: String,
+    pub kind: KernelKind,
+    /// The PRETTY_NAME from the `.osrel` section of a UKI.
+    pub os_name: Option<String>,
+    /// The kernel version from the `.uname` section of a UKI.
+    pub uname: Option<String>,
+    /// If this is set, the kernel is started with this cmdline instead of the one it would get otherwise.
+    pub cmdline: Option<String>,
+}
+
+impl KexecTarget {
+    pub fn label(&self) -> String {
+        let mut label = self.os_name.clone().unwrap_or_else(|| self.path.clone());
+        if let Some(uname) = &self.uname {
+            label.push_str(&format!(" {uname}"));
+        }
+        if self.os_name.is_some() {
+            label.push_str(&format!(" ({})", self.path));
+        }
+        label
+    }
+}
+
+/// Find the value of a key in the content of an os-release file.
+/// See https://www.freedesktop.org/software/systemd/man/latest/os-release.html
+pub fn os_release_value(text: &str, wanted: &str) -> Option<String> {
+    text.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == wanted).then(|| {
+            value
+                .trim()
+                .trim_matches('"')
+                .trim_matches('\'')
+                .to_string()
+        })
+    })
+}
+
+/// Find the name of the OS in the content of an os-release file.
+pub fn parse_os_release(text: &str) -> Option<String> {
+    os_release_value(text, "PRETTY_NAME").or_else(|| os_release_value(text, "NAME"))
+}
+
+fn section_string(data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data);
+    text.trim_end_matches('\0').trim().to_string()
+}
+
+fn section_text(image: &[u8], name: &str) -> Option<String> {
+    pe::find_section(image, name).ok()?.map(section_string)
+}
+
+/// What a UKI says about itself.
+pub struct UkiInfo {
+    /// The content of the `.osrel` section.
+    pub os_release: Option<String>,
+    /// The kernel version from the `.uname` section.
+    pub uname: Option<String>,
+}
+
+/// Read the text sections of a UKI, without reading the kernel and initrd in it.
+/// Returns None if the file isn't a PE image with a kernel in it,
+/// like the other EFI programs that can be in the same directory.
+pub fn read_uki_info(path: &Path) -> Option<UkiInfo> {
+    let mut file = File::open(path).ok()?;
+    let headers = pe::read_section_headers(&mut file).ok()?;
+    headers.iter().find(|header| header.name == ".linux")?;
+    let mut text = |name: &str| {
+        let header = headers.iter().find(|header| header.name == name)?;
+        let data = pe::read_section(&mut file, header).ok()?;
+        Some(section_string(&data))
+    };
+    Some(UkiInfo {
+        os_release: text(".osrel"),
+        uname: text(".uname"),
+    })
+}
+
+/// Work out which of the file names in a directory are kernels that can be booted.
+/// UKIs are only recognized by their name here; `scan` checks that they really contain a kernel.
+pub fn find_kernels<'a>(names: &[&'a str]) -> Vec<(&'a str, KernelKind)> {
+    let mut found = vec![];
+    for name in names {
+        if name.to_lowercase().ends_with(".efi") {
+            found.push((*name, KernelKind::Uki));
+        } else if let Some(suffix) = name.strip_prefix("vmlinuz-") {
+            let initrd = format!("initramfs-{suffix}.img");
+            let initrd = names.contains(&initrd.as_str()).then_some(initrd);
+            found.push((*name, KernelKind::Kernel { initrd }));
+        }
+    }
+    found.sort_by_key(|(name, _)| *name);
+    found
+}
+
+/// List the kernels in this directory on the ESP.
+pub fn scan(directory: &str) -> Result<Vec<KexecTarget>, String> {
+    let relative = esp::esp_relative_path(directory);
+    esp::with_esp(|root| {
+        let dir = root.join(&relative);
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|why| format!("Failed to list {}: {why}", dir.display()))?;
+        let names: Vec<String> = entries
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        let mut targets = vec![];
+        for (name, kind) in find_kernels(&names) {
+            let path = relative.join(name).to_string_lossy().to_string();
+            let mut target = KexecTarget {
+                path,
+                kind,
+                os_name: None,
+                uname: None,
+                cmdline: None,
+            };
+            if target.kind == KernelKind::Uki {
+                // Other EFI programs, like the shell or systemd-boot, can be in the same directory.
+                let Some(info) = read_uki_info(&dir.join(name)) else {
+                    continue;
+                };
+                target.os_name = info.os_release.as_deref().and_then(parse_os_release);
+                target.uname = info.uname;
+            }
+            targets.push(target);
+        }
+        Ok(targets)
+    })?
+}
+
+fn write_part(name: &str, data: &[u8]) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(KEXEC_DIR)
+        .map_err(|why| format!("Failed to create {KEXEC_DIR}: {why}"))?;
+    let path = Path::new(KEXEC_DIR).join(name);
+    std::fs::write(&path, data)
+        .map_err(|why| format!("Failed to write {}: {why}", path.display()))?;
+    Ok(path)
+}
+
+/// Copy the kernel and initrd of the target off the ESP, and work out its cmdline.
+fn prepare(
+    root: &Path,
+    target: &KexecTarget,
+) -> Result<(PathBuf, Option<PathBuf>, String), String> {
+    let path = root.join(&target.path);
+    let file =
+        std::fs::read(&path).map_err(|why| format!("Failed to read {}: {why}", path.display()))?;
+    match &target.kind {
+        KernelKind::Uki => {
+            let linux =
+                pe::find_section(&file, ".linux")?.ok_or("The UKI has no .linux section")?;
+            let kernel = write_part("linux", linux)?;
+            let initrd = match pe::find_section(&file, ".initrd")? {
+                Some(initrd) => Some(write_part("initrd", initrd)?),
+                None => None,
+            };
+            let cmdline = section_text(&file, ".cmdline").unwrap_or_default();
+            Ok((kernel, initrd, cmdline))
+        }
+        KernelKind::Kernel { initrd } => {
+            let kernel = write_part("linux", &file)?;
+            let initrd = match initrd {
+                Some(name) => {
+                    let initrd_path = path.with_file_name(name);
+                    let data = std::fs::read(&initrd_path).map_err(|why| {
+                        format!("Failed to read {}: {why}", initrd_path.display())
+                    })?;
+                    Some(write_part("initrd", &data)?)
+                }
+                None => None,
+            };
+            let cmdline = std::fs::read_to_string("/proc/cmdline")
+                .map_err(|why| format!("Failed to read /proc/cmdline: {why}"))?;
+            Ok((kernel, initrd, cmdline.trim().to_string()))
+        }
+        KernelKind::Entry { initrds, options } => {
+            let kernel = write_part("linux", &file)?;
+            // The kernel accepts several initrds one after the other, like microcode and the main one.
+            let mut initrd_data = vec![];
+            for name in initrds {
+                let initrd_path = root.join(esp::esp_relative_path(name));
+                let data = std::fs::read(&initrd_path)
+                    .map_err(|why| format!("Failed to read {}: {why}", initrd_path.display()))?;
+                initrd_data.extend_from_slice(&data);
+            }
+            let initrd = if initrd_data.is_empty() {
+                None
+            } else {
+                Some(write_part("initrd", &initrd_data)?)
+            };
+            Ok((kernel, initrd, options.clone()))
+        }
+    }
+}
+
+/// Load the target, so that it's started by the next `reboot(LINUX_REBOOT_CMD_KEXEC)`.
+pub fn load(target: &KexecTarget) -> Result<(), String> {
+    let (kernel, initrd, cmdline) = esp::with_esp(|root| prepare(root, target))??;
+    let cmdline = target.cmdline.clone().unwrap_or(cmdline);
+
+    let open = |path: &Path| {
+        std::fs::File::open(path).map_err(|why| format!("Failed to open {}: {why}", path.display()))
+    };
+    let kernel_file = open(&kernel)?;
+    let initrd_file = initrd.as_deref().map(open).transpose()?;
+    let (initrd_fd, flags) = match &initrd_file {
+        Some(file) => (file.as_raw_fd() as usize, 0),
+        None => (0, KEXEC_FILE_NO_INITRAMFS),
+    };
+    // The length of the cmdline includes the terminating NUL.
+    let cmdline = format!("{cmdline}\0");
+
+    let result = unsafe {
+        syscalls::syscall!(
+            syscalls::Sysno::kexec_file_load,
+            kernel_file.as_raw_fd() as usize,
+            initrd_fd,
+            cmdline.len(),
+            cmdline.as_ptr() as usize,
+            flags
+        )
+    };
+    // The kernel has its own copy now.
+    let _ = std::fs::remove_dir_all(KEXEC_DIR);
+    result.map_err(|why| format!("kexec_file_load failed: {why}"))?;
+    Ok(())
+}
+
+/// Start the loaded kernel. This only returns if it fails.
+pub fn exec() -> String {
+    let _ = unsafe { syscalls::syscall!(syscalls::Sysno::sync) };
+    let result = unsafe {
+        syscalls::syscall!(
+            syscalls::Sysno::reboot,
+            LINUX_REBOOT_MAGIC1,
+            LINUX_REBOOT_MAGIC2,
+            LINUX_REBOOT_CMD_KEXEC,
+            0
+        )
+    };
+    match result {
+        Ok(_) => "reboot() returned without starting the loaded kernel".to_string(),
+        Err(why) => format!("Failed to start the loaded kernel: {why}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_kernels, os_release_value, parse_os_release, KernelKind};
+
+    #[test]
+    fn test_parse_os_release() {
+        assert_eq!(
+            parse_os_release("NAME=\"Arch Linux\"\nPRETTY_NAME=\"Arch Linux (LTS)\"\nID=arch\n")
+                .as_deref(),
+            Some("Arch Linux (LTS)")
+        );
+        assert_eq!(
+            parse_os_release("NAME=Arch\nID=arch").as_deref(),
+            Some("Arch")
+        );
+        assert_eq!(parse_os_release("ID=arch"), None);
+        assert_eq!(
+            os_release_value("NAME=Arch\nID=arch\n", "ID").as_deref(),
+            Some("arch")
+        );
+    }
+
+    #[test]
+    fn test_find_kernels() {
+        let names = [
+            "vmlinuz-linux-lts",
+            "initramfs-linux-lts.img",
+            "initramfs-linux-lts-fallback.img",
+            "vmlinuz-linux-zen",
+            "arch-linux-fallback.efi",
+            "intel-ucode.img",
+        ];
+        assert_eq!(
+            find_kernels(&names),
+            vec![
+                ("arch-linux-fallback.efi", KernelKind::Uki),
+                (
+                    "vmlinuz-linux-lts",
+                    KernelKind::Kernel {
+                        initrd: Some("initramfs-linux-lts.img".to_string())
+                    }
+                ),
+                ("vmlinuz-linux-zen", KernelKind::Kernel { initrd: None }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! The variables of the Boot Loader Interface, which tools like `bootctl status` and `systemd-analyze` read.
+//! See https://systemd.io/BOOT_LOADER_INTERFACE/
+//!
+//! A boot loader sets these as volatile variables before ExitBootServices.
+//! We run after that, when only non-volatile variables can be created,
+//! so ours are non-volatile, and the ones left over from the previous boot are replaced at startup.
+//! If a real boot loader has already set them, they are left alone.
+
+use crate::efivarfs::{self, DEFAULT_ATTRIBUTES, EFI_VARIABLE_NON_VOLATILE};
+
+/// The vendor GUID of the Boot Loader Interface variables,
+/// which systemd-stub also sets when it starts a UKI.
+pub const LOADER_VENDOR: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// The variables that are written after the user has chosen something.
+/// These are deleted at startup, so that the ones from the previous boot don't look like they're from this one.
+const EXEC_VARIABLES: [&str; 2] = ["LoaderTimeExecUSec", "LoaderEntrySelected"];
+
+// This comes from linux/time.h.
+const CLOCK_BOOTTIME: usize = 7;
+
+/// Decode a NUL-terminated UTF-16LE string, as stored in EFI variables.
+pub fn decode_utf16(buf: &[u8]) -> String {
+    let chars: Vec<u16> = buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf16_lossy(&chars)
+}
+
+/// Encode a string as NUL-terminated UTF-16LE, as the Boot Loader Interface expects.
+pub fn encode_utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// The time since the kernel started, in microseconds, including time spent suspended.
+/// A boot loader would count from the CPU reset instead, which we can't know,
+/// so `systemd-analyze` counts the kernel's time until the menu is shown as part of the firmware's.
+pub fn boottime_usec() -> u64 {
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: i64,
+        tv_nsec: i64,
+    }
+    let mut time = Timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let result = unsafe {
+        syscalls::syscall!(
+            syscalls::Sysno::clock_gettime,
+            CLOCK_BOOTTIME,
+            &mut time as *mut Timespec as usize
+        )
+    };
+    match result {
+        Ok(_) => time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1000,
+        Err(_) => 0,
+    }
+}
+
+/// Whether we should be setting the variables: not if a boot loader before us has.
+/// The volatile ones are from a loader on this boot; the non-volatile ones are ours from a previous boot.
+fn variables_are_ours() -> bool {
+    match efivarfs::read("LoaderInfo", LOADER_VENDOR) {
+        Ok((attributes, _)) => attributes & EFI_VARIABLE_NON_VOLATILE != 0,
+        Err(_) => true,
+    }
+}
+
+fn write_string(name: &str, value: &str) -> Result<(), String> {
+    efivarfs::write(
+        name,
+        LOADER_VENDOR,
+        DEFAULT_ATTRIBUTES,
+        &encode_utf16(value),
+    )
+}
+
+/// Set the variables that describe the menu itself, and record when it was shown.
+/// This is called once at startup.
+pub fn export_loader_info(init_usec: u64) -> Result<(), String> {
+    if !variables_are_ours() {
+        return Ok(());
+    }
+    for name in EXEC_VARIABLES {
+        efivarfs::delete(name, LOADER_VENDOR)?;
+    }
+
+    write_string(
+        "LoaderInfo",
+        &format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+    )?;
+    write_string("LoaderTimeInitUSec", &init_usec.to_string())?;
+    // None of the features are about things that boot-menu does:
+    // it doesn't read LoaderConfigTimeout, LoaderEntryDefault and the like.
+    efivarfs::write(
+        "LoaderFeatures",
+        LOADER_VENDOR,
+        DEFAULT_ATTRIBUTES,
+        &0u64.to_le_bytes(),
+    )?;
+
+    // systemd-stub sets this when it's started directly by the firmware, so it should already be there.
+    if efivarfs::read("LoaderDevicePartUUID", LOADER_VENDOR).is_err() {
+        return Err("LoaderDevicePartUUID was not set by systemd-stub".to_string());
+    }
+    Ok(())
+}
+
+/// Record which menu entry was chosen.
+pub fn export_entry_selected(label: &str) -> Result<(), String> {
+    if !variables_are_ours() {
+        return Ok(());
+    }
+    write_string("LoaderEntrySelected", label)
+}
+
+/// Record when the menu handed over to the system, after unlocking the disks.
+pub fn export_exec_time() -> Result<(), String> {
+    if !variables_are_ours() {
+        return Ok(());
+    }
+    write_string("LoaderTimeExecUSec", &boottime_usec().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_utf16, encode_utf16};
+
+    #[test]
+    fn test_utf16() {
+        let encoded = encode_utf16("12345");
+        assert_eq!(encoded.len(), 12);
+        assert_eq!(&encoded[10..], &[0, 0]);
+        assert_eq!(decode_utf16(&encoded), "12345");
+    }
+}
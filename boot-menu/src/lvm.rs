@@ -0,0 +1,219 @@
+//! Activating the LVM volume groups on the unlocked volumes, and choosing the root logical volume.
+
+use std::process::Stdio;
+
+use cursive::{views, Cursive};
+use serde::Deserialize;
+
+use crate::{
+    cmdline,
+    exits::finish_boot,
+    root_handoff::{boot_with_root, continue_anyway, RootOverrides},
+    spinner::spinner_view,
+};
+
+/// The columns asked from `lvm lvs`, which are the fields of `LogicalVolume`.
+const LVS_COLUMNS: &str = "vg_name,lv_name,lv_size,lv_attr,origin,lv_path,lv_dm_path";
+
+#[derive(Deserialize)]
+struct LvsOutput {
+    report: Vec<LvsReport>,
+}
+
+#[derive(Deserialize)]
+struct LvsReport {
+    lv: Vec<LogicalVolume>,
+}
+
+/// A logical volume, as `lvm lvs --reportformat json` describes it.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogicalVolume {
+    pub vg_name: String,
+    pub lv_name: String,
+    /// Like `<100.00g`.
+    pub lv_size: String,
+    /// Like `owi-a-----`. See lvs(8) for what each character means.
+    pub lv_attr: String,
+    /// The volume this is a snapshot of, or an empty string.
+    pub origin: String,
+    /// Like `/dev/arch_vg/root`.
+    pub lv_path: String,
+    /// Like `/dev/mapper/arch_vg-root`.
+    pub lv_dm_path: String,
+}
+
+impl LogicalVolume {
+    pub fn is_snapshot(&self) -> bool {
+        !self.origin.is_empty()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.lv_attr.chars().nth(4) == Some('a')
+    }
+
+    /// Whether this is the volume that `root=` names, in either of the usual spellings.
+    pub fn is_root(&self, root: &str) -> bool {
+        root == self.lv_path || root == self.lv_dm_path
+    }
+
+    pub fn label(&self, root: Option<&str>) -> String {
+        let mut label = format!("{}/{} ({})", self.vg_name, self.lv_name, self.lv_size);
+        if self.is_snapshot() {
+            label.push_str(&format!(", snapshot of {}", self.origin));
+        }
+        if root.is_some_and(|root| self.is_root(root)) {
+            label.push_str(", the root from the kernel cmdline");
+        }
+        label
+    }
+}
+
+pub fn parse_lvs(json: &str) -> Result<Vec<LogicalVolume>, String> {
+    let output: LvsOutput =
+        serde_json::from_str(json).map_err(|why| format!("Failed to parse lvs output: {why}"))?;
+    Ok(output
+        .report
+        .into_iter()
+        .flat_map(|report| report.lv)
+        .collect())
+}
+
+fn run_lvm(args: &[&str]) -> Result<String, String> {
+    let output = std::process::Command::new("lvm")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|why| format!("Failed to spawn lvm: {why}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "lvm {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Activate the volume groups on the unlocked volumes, the same way the `lvm2` hook does later.
+/// Doing it twice does no harm.
+pub fn activate_volume_groups() -> Result<(), String> {
+    run_lvm(&["vgchange", "--sysinit", "--activate", "ay"])?;
+    Ok(())
+}
+
+/// Deactivate all the volume groups, so that nothing holds the unlocked volumes open and they can be closed.
+pub fn deactivate_volume_groups() -> Result<(), String> {
+    run_lvm(&["vgchange", "--activate", "n"])?;
+    Ok(())
+}
+
+pub fn list_logical_volumes() -> Result<Vec<LogicalVolume>, String> {
+    let json = run_lvm(&["lvs", "--reportformat", "json", "--options", LVS_COLUMNS])?;
+    parse_lvs(&json)
+}
+
+/// Activate one logical volume. Thin snapshots are skipped by `vgchange` by default,
+/// so they need `--ignoreactivationskip`.
+pub fn activate(volume: &LogicalVolume) -> Result<(), String> {
+    let name = format!("{}/{}", volume.vg_name, volume.lv_name);
+    run_lvm(&[
+        "lvchange",
+        "--activate",
+        "y",
+        "--ignoreactivationskip",
+        &name,
+    ])?;
+    Ok(())
+}
+
+fn boot_volume(siv: &mut Cursive, volume: &Option<LogicalVolume>) {
+    let Some(volume) = volume else {
+        finish_boot(siv);
+        return;
+    };
+    if !volume.is_active() {
+        if let Err(why) = activate(volume) {
+            continue_anyway(siv, why);
+            return;
+        }
+    }
+    let overrides = RootOverrides {
+        root: Some(volume.lv_path.clone()),
+        rootflags: None,
+    };
+    boot_with_root(siv, &overrides);
+}
+
+/// This function lists the logical volumes on the unlocked volumes,
+/// and continues the boot with the selected one as the root.
+pub fn logical_volume_picker(siv: &mut Cursive) {
+    siv.add_layer(views::Dialog::around(
+        views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
+            .child(spinner_view())
+            .child(views::TextView::new("Looking for logical volumes...")),
+    ));
+    let cb_sink = siv.cb_sink().clone();
+    std::thread::spawn(move || {
+        // The volume groups were already activated after unlocking, but that didn't stop the boot if it failed.
+        let result = activate_volume_groups().and_then(|_| list_logical_volumes());
+        let root = cmdline::read_param("root").ok().flatten();
+        cb_sink
+            .send(Box::new(move |siv| {
+                siv.pop_layer();
+                let volumes = match result {
+                    Ok(volumes) => volumes,
+                    Err(why) => {
+                        continue_anyway(siv, why);
+                        return;
+                    }
+                };
+
+                let mut select = views::SelectView::new().autojump();
+                select.add_item("The root from the kernel cmdline", None);
+                for volume in volumes {
+                    select.add_item(volume.label(root.as_deref()), Some(volume));
+                }
+                select.set_on_submit(boot_volume);
+                siv.add_layer(views::Dialog::around(select).title("Choose the root volume"));
+            }))
+            .unwrap();
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_lvs;
+
+    #[test]
+    fn test_parse_lvs() {
+        let volumes = parse_lvs(
+            r#"  {
+      "report": [
+          {
+              "lv": [
+                  {"vg_name":"arch_vg", "lv_name":"root", "lv_size":"<100.00g", "lv_attr":"owi-a-s---", "origin":"", "lv_path":"/dev/arch_vg/root", "lv_dm_path":"/dev/mapper/arch_vg-root"},
+                  {"vg_name":"arch_vg", "lv_name":"root-pre-upgrade", "lv_size":"20.00g", "lv_attr":"swi---s---", "origin":"root", "lv_path":"/dev/arch_vg/root-pre-upgrade", "lv_dm_path":"/dev/mapper/arch_vg-root--pre--upgrade"}
+              ]
+          }
+      ]
+  }
+"#,
+        )
+        .unwrap();
+        assert_eq!(volumes.len(), 2);
+
+        let root = Some("/dev/mapper/arch_vg-root");
+        assert!(volumes[0].is_active());
+        assert_eq!(
+            volumes[0].label(root),
+            "arch_vg/root (<100.00g), the root from the kernel cmdline"
+        );
+        assert!(!volumes[1].is_active());
+        assert_eq!(
+            volumes[1].label(root),
+            "arch_vg/root-pre-upgrade (20.00g), snapshot of root"
+        );
+
+        assert!(parse_lvs("not json").is_err());
+    }
+}
@@ -1,9 +1,33 @@
 #![feature(div_duration)]
+mod block_device;
+mod bls;
+mod boot_entries;
+mod boot_manager;
+mod cmdline;
+mod cmdline_editor;
+mod default_entry;
+mod device_path;
+mod efi_tools;
+mod efivarfs;
+mod encryption_config;
+mod esp;
 mod exits;
+mod hibernation;
+mod kexec;
+mod loader_interface;
+mod lvm;
+mod menu_config;
 mod password_input;
+mod pe;
+mod root_handoff;
+mod secure_boot;
+mod snapshots;
 mod spinner;
+mod unlock;
+mod var_browser;
+mod var_decode;
 
-use std::sync::{Arc, Mutex};
+use std::sync::{atomic::AtomicUsize, Arc, Mutex};
 
 use cursive::{
     align::HAlign,
@@ -11,14 +35,21 @@ use cursive::{
     view::Margins,
     views, With,
 };
-use disk_crypto::params::EncryptionParams;
+use disk_crypto::{
+    disk_encryption::DecryptedVolume,
+    unlocker::{registered_unlockers, Unlocker},
+};
 
 use crate::{
-    exits::{
-        choose_exit, BootMenuExitOption, LINUX_REBOOT_CMD_CAD_ON, LINUX_REBOOT_MAGIC1,
-        LINUX_REBOOT_MAGIC2,
-    },
-    password_input::{input_switcher_thread, password_entry},
+    cmdline::UnlockTarget,
+    default_entry::{cancel_on_keypress, read_last_choice, start_default_countdown},
+    encryption_config::LoadedConfig,
+    exits::{partial_menu, LINUX_REBOOT_CMD_CAD_ON, LINUX_REBOOT_MAGIC1, LINUX_REBOOT_MAGIC2},
+    loader_interface::{boottime_usec, export_loader_info},
+    menu_config::MenuConfig,
+    password_input::{config_unavailable_dialog, input_switcher_thread, login_prompt},
+    secure_boot::SecureBootStatus,
+    unlock::close_all_volumes,
 };
 
 fn main_theme() -> Theme {
@@ -96,14 +127,6 @@ pub enum LoginState {
     LogInFail,
 }
 
-pub enum InputCredentials {
-    /// The user typed a password
-    Password(String),
-
-    /// The user typed a PIN code for the Yubikey
-    YkPin(String),
-}
-
 impl Default for LoginState {
     fn default() -> Self {
         Self::WaitingForLogin
@@ -111,18 +134,63 @@ impl Default for LoginState {
 }
 
 struct State {
-    keyfile: Option<Vec<u8>>,
+    /// The decrypted keyfiles of the volumes, once we've logged in.
+    keyfiles: Option<Vec<DecryptedVolume>>,
     login_state: Arc<Mutex<LoginState>>,
-    config: EncryptionParams,
+    /// The ways of logging in, most preferred first.
+    unlockers: Arc<Vec<Box<dyn Unlocker>>>,
+    /// The encryption config, if one of the places it's looked up in had a valid one.
+    config: Option<LoadedConfig>,
+    /// Why each of the places the encryption config is looked up in didn't work.
+    config_errors: Vec<String>,
+    menu: MenuConfig,
+    /// The encrypted devices named on the kernel cmdline.
+    unlock_targets: Vec<UnlockTarget>,
+    /// Whether the firmware has Secure Boot enabled, read once at startup.
+    secure_boot: SecureBootStatus,
+    /// The label of the entry that was chosen on the previous boot.
+    last_choice: Option<String>,
+    /// This is incremented to stop the countdown that is running.
+    countdown: Arc<AtomicUsize>,
+    /// If this is set when the menu exits, the kernel loaded with kexec is started.
+    kexec_loaded: bool,
 }
 
 fn main() {
     println!("Boot menu launching!");
 
-    // The first thing we need to do is to parse the encryption config.
-    let config_txt = include_str!("../../disk-crypto/encrypt-config.json");
-    let config: EncryptionParams = serde_json::from_str(config_txt)
-        .expect("Compiled-in encryption JSON is invalid -- please rebuild boot-menu");
+    // The menu layout can be customized per machine, so it is read at runtime.
+    let mut menu = MenuConfig::load();
+
+    // Then we need to find the encryption config. The menu config says whether the copy on the ESP can be trusted.
+    // If there isn't a valid one anywhere, we can still show the menu entries that don't need a login.
+    let (config, config_errors) = encryption_config::load(menu.trust_esp_encryption_config);
+
+    // If an EFI tool was run once through a temporary entry, that was the previous boot.
+    if let Err(why) = efi_tools::delete_temporary_entry() {
+        println!("Failed to delete the temporary boot entry: {why}");
+    }
+
+    // The boot entries on the ESP are listed where the menu config asks for them.
+    if bls::has_placeholders(&menu.entries) {
+        match bls::scan() {
+            Ok(found) => bls::replace_placeholders(&mut menu.entries, &found),
+            Err(why) => println!("Failed to find the Boot Loader Specification entries: {why}"),
+        }
+    }
+
+    // This is shown on the login screen, and checked against the policy in the menu config after logging in.
+    let secure_boot = SecureBootStatus::read();
+    println!("{secure_boot}");
+
+    // The encrypted device is named on the kernel cmdline, so that the same image works on every machine.
+    let unlock_targets = match cmdline::read_unlock_targets() {
+        Ok(targets) => targets,
+        Err(why) => {
+            println!("{why}");
+            vec![]
+        }
+    };
 
     // For ease of use, for the duration of the menu, we enable the CAD combination,
     // which will reboot instantly.
@@ -141,12 +209,22 @@ fn main() {
     let mut siv = cursive::CursiveRunnable::new(cursive::backends::termion::Backend::init);
 
     siv.set_theme(main_theme());
+    let has_config = config.is_some();
     let state = State {
         config,
-        keyfile: None,
+        config_errors,
+        menu,
+        unlock_targets,
+        secure_boot,
+        last_choice: read_last_choice(),
+        countdown: Arc::new(AtomicUsize::new(0)),
+        kexec_loaded: false,
+        keyfiles: None,
         login_state: Arc::new(Mutex::new(LoginState::default())),
+        unlockers: Arc::new(registered_unlockers()),
     };
     let login_state = state.login_state.clone();
+    let unlockers = state.unlockers.clone();
     siv.set_user_data(state);
 
     // Do not allow closing the app with ^C.
@@ -155,11 +233,47 @@ fn main() {
     siv.set_autorefresh(true);
 
     // Immediately after this, spawn another layer. This will prompt the user for a password.
-    password_entry(&mut siv);
+    // Without a config, there's nothing to check the password against,
+    // so go straight to the reduced menu instead.
+    if has_config {
+        login_prompt(&mut siv);
+    } else {
+        *login_state.lock().unwrap() = LoginState::LogInFail;
+        let data = siv.user_data::<State>().unwrap();
+        let (menu, last_choice) = (data.menu.clone(), data.last_choice.clone());
+        siv.add_layer(partial_menu(&menu, last_choice.as_deref()));
+        config_unavailable_dialog(&mut siv);
+    }
+
+    // If the entry chosen last time doesn't need a login, it can be chosen again without one.
+    // Pressing any key, like when typing the password, stops that.
+    cancel_on_keypress(&mut siv);
+    start_default_countdown(&mut siv, false);
+
+    // The menu is about to be shown, which is when a boot loader would have started, as far as
+    // `systemd-analyze` is concerned.
+    if let Err(why) = export_loader_info(boottime_usec()) {
+        println!("Failed to set the Boot Loader Interface variables: {why}");
+    }
 
     // Also spawn the input box switcher thread.
     let sink = siv.cb_sink().clone();
-    std::thread::spawn(|| input_switcher_thread(sink, login_state));
+    std::thread::spawn(|| input_switcher_thread(sink, login_state, unlockers));
 
     siv.run();
+
+    // Starting another kernel has to wait until the TUI has given the terminal back.
+    let data: State = siv.take_user_data().unwrap();
+    if data.kexec_loaded {
+        // Nothing should have been unlocked unless continuing the boot failed halfway,
+        // but the keys of anything that was shouldn't be left in memory for the next kernel.
+        drop(data);
+        match close_all_volumes() {
+            Ok(()) => {
+                println!("Starting the loaded kernel...");
+                println!("{}", kexec::exec());
+            }
+            Err(why) => println!("{why}\nNot starting the loaded kernel."),
+        }
+    }
 }
@@ -0,0 +1,210 @@
+use serde::Deserialize;
+
+use crate::{
+    boot_entries::BootEntryMatch, cmdline_editor::CmdlinePreset, kexec::KexecTarget,
+    secure_boot::SecureBootPolicy,
+};
+
+/// Where the menu definition is looked up at runtime.
+/// The install hook copies `menu-config.json` from the project directory here.
+pub const MENU_CONFIG_PATH: &str = "/etc/boot-menu/menu-config.json";
+
+/// This structure describes the entries shown in the boot menu.
+#[derive(Deserialize, Clone)]
+pub struct MenuConfig {
+    pub entries: Vec<MenuEntry>,
+
+    /// What to do after logging in if Secure Boot is off or the firmware is in Setup Mode.
+    #[serde(default)]
+    pub secure_boot_policy: SecureBootPolicy,
+
+    /// If this is set, the default entry is chosen after this many seconds, unless a key is pressed.
+    /// The default is the entry that was chosen last time.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    /// Whether `boot-menu/encrypt-config.json` on the ESP may be used.
+    /// The ESP isn't signed, so anyone who can write to it could replace the password.
+    #[serde(default)]
+    pub trust_esp_encryption_config: bool,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MenuEntry {
+    /// The text shown in the menu.
+    pub label: String,
+
+    /// What happens when this entry is selected.
+    pub action: MenuAction,
+
+    /// If this is set, the entry is only shown after the user has logged in successfully.
+    #[serde(default)]
+    pub requires_auth: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MenuAction {
+    /// Unlock the disk and continue booting the current kernel.
+    ContinueBoot,
+
+    /// Unlock the disk, then list the btrfs subvolumes of the root filesystem, including snapper snapshots,
+    /// and continue booting with the selected one as the root.
+    BootSnapshot,
+
+    /// Unlock the disk, then list the LVM logical volumes, including snapshots,
+    /// and continue booting with the selected one as the root.
+    ChooseRootVolume,
+
+    /// Set BootNext to the EFI boot entry that matches, then reboot.
+    /// If several entries match, the user picks one.
+    BootNext {
+        #[serde(flatten)]
+        target: BootEntryMatch,
+    },
+
+    /// Show the active EFI boot entries, and set BootNext to the selected one, then reboot.
+    /// If `include` is not empty, only the entries whose description contains one of those are shown;
+    /// then, the entries whose description contains one of `exclude` are hidden.
+    /// The comparison ignores case.
+    BootOnce {
+        #[serde(default)]
+        include: Vec<String>,
+        #[serde(default)]
+        exclude: Vec<String>,
+    },
+
+    /// List the EFI programs on the ESP, like memtest86 or the EFI shell, and reboot into the selected one once,
+    /// through a temporary boot entry that is deleted on the next boot.
+    EfiTool,
+
+    /// Show the EFI boot entries, and allow reordering, enabling, disabling, deleting and creating them.
+    /// This is only available after logging in.
+    BootManager,
+
+    /// Show all EFI variables, with their content as hex and decoded where we know the format.
+    /// This is only available after logging in.
+    VariableBrowser,
+
+    /// List the kernels and UKIs in this directory on the ESP, and boot the selected one with kexec.
+    Kexec {
+        #[serde(default = "default_kexec_directory")]
+        directory: String,
+    },
+
+    /// Show the current kernel cmdline for editing, with these presets that can be applied to it,
+    /// and boot the running UKI again with the edited cmdline, with kexec.
+    /// This is only available after logging in.
+    CmdlineEditor {
+        #[serde(default)]
+        presets: Vec<CmdlinePreset>,
+    },
+
+    /// This is replaced at startup with one entry for each Boot Loader Specification entry on the ESP,
+    /// which all require a login. If none are found, choosing this says so.
+    BootLoaderEntries,
+
+    /// Boot one of the Boot Loader Specification entries with kexec.
+    /// These are only created in place of `boot_loader_entries`, not read from the menu config.
+    #[serde(skip)]
+    BootLoaderEntry {
+        target: KexecTarget,
+    },
+
+    /// Ask the firmware to show its settings UI on next boot, then reboot.
+    FirmwareSetup,
+
+    Reboot,
+    Poweroff,
+
+    /// Run a shell on a separate VT after logging in, and return to the menu when it exits.
+    /// If `unlock_volumes` is set, the volumes are unlocked with the keyfiles before the shell starts,
+    /// so that it can get at their mapper devices. Otherwise, the shell gets the keyfiles themselves.
+    /// Menu configs from before this needed a login still say `shell`, and get this instead.
+    #[serde(alias = "shell")]
+    RescueShell {
+        #[serde(default)]
+        unlock_volumes: bool,
+    },
+
+    /// Show another menu with these entries.
+    Submenu {
+        entries: Vec<MenuEntry>,
+    },
+}
+
+fn default_kexec_directory() -> String {
+    "\\EFI\\Linux".to_string()
+}
+
+impl MenuConfig {
+    /// Load the menu definition from the initramfs,
+    /// falling back to the compiled-in default if it's missing or invalid.
+    pub fn load() -> Self {
+        match std::fs::read_to_string(MENU_CONFIG_PATH) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(config) => return config,
+                Err(why) => {
+                    println!("Failed to parse {MENU_CONFIG_PATH}: {why}; using built-in menu")
+                }
+            },
+            Err(why) => println!("Failed to read {MENU_CONFIG_PATH}: {why}; using built-in menu"),
+        }
+        Self::builtin()
+    }
+
+    /// The menu definition that is compiled into the binary.
+    pub fn builtin() -> Self {
+        serde_json::from_str(include_str!("../menu-config.json"))
+            .expect("Compiled-in menu JSON is invalid -- please rebuild boot-menu")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MenuAction, MenuConfig};
+    use crate::secure_boot::SecureBootPolicy;
+
+    #[test]
+    fn test_builtin_menu_parses() {
+        let config = MenuConfig::builtin();
+        assert!(!config.entries.is_empty());
+        assert_eq!(config.secure_boot_policy, SecureBootPolicy::Warn);
+    }
+
+    #[test]
+    fn test_shell_needs_login() {
+        let config: MenuConfig = serde_json::from_str(
+            r#"{"entries": [{"label": "Shell", "action": {"type": "shell"}}]}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            config.entries[0].action,
+            MenuAction::RescueShell {
+                unlock_volumes: false
+            }
+        ));
+    }
+
+    #[test]
+    fn test_submenu_parses() {
+        let config: MenuConfig = serde_json::from_str(
+            r#"{"entries": [
+                {"label": "More", "action": {"type": "submenu", "entries": [
+                    {"label": "Windows", "action": {"type": "boot_next", "description": "Windows Boot Manager"}, "requires_auth": true}
+                ]}}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.secure_boot_policy, SecureBootPolicy::Ignore);
+        let MenuAction::Submenu { entries } = &config.entries[0].action else {
+            panic!("expected a submenu");
+        };
+        assert!(!config.entries[0].requires_auth);
+        assert!(entries[0].requires_auth);
+        assert!(
+            matches!(&entries[0].action, MenuAction::BootNext { target } if target.description.as_deref() == Some("Windows Boot Manager"))
+        );
+    }
+}
@@ -1,7 +1,4 @@
-use std::{
-    process::Stdio,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
 use cursive::{
     view::Nameable,
@@ -9,17 +6,36 @@ use cursive::{
     Cursive,
 };
 
+use disk_crypto::{
+    disk_encryption::DecryptedVolume,
+    unlocker::{InputKind, Unlocker},
+};
+
 use crate::{
+    default_entry::{start_default_countdown, COUNTDOWN_VIEW},
     exits::{full_menu, partial_menu},
+    secure_boot::SecureBootPolicy,
     spinner::spinner_view,
     LoginState, State,
 };
 
-/// This thread is responsible for switching between the two different types of password entry.
+/// The name of the TextView in the "waiting" box, which the unlocker can change.
+const VERIFYING_VIEW: &str = "unlock_verifying";
+
+/// The name of the dialog with the prompt of an unlocker.
+fn prompt_view_name(unlocker: &dyn Unlocker) -> String {
+    format!("{}_input", unlocker.name())
+}
+
+/// This thread is responsible for switching between the prompts of the unlockers.
 ///
-/// - If we are LoginState::WaitingForLogin, and there is no Yubikey present, ensure that the password entry dialog is on top.
-/// - If we are LoginState::WaitingForLogin, and there is a Yubikey present, ensure that the PIN entry dialog is on top.
-pub fn input_switcher_thread(cb_sink: cursive::CbSink, state: Arc<Mutex<LoginState>>) {
+/// If we are LoginState::WaitingForLogin, ensure that the prompt of the first available unlocker is on top,
+/// so that plugging in a Yubikey switches from the password entry to the PIN entry, and unplugging it switches back.
+pub fn input_switcher_thread(
+    cb_sink: cursive::CbSink,
+    state: Arc<Mutex<LoginState>>,
+    unlockers: Arc<Vec<Box<dyn Unlocker>>>,
+) {
     loop {
         std::thread::sleep(std::time::Duration::from_secs_f32(0.1f32));
         // Check that the state is currently WaitingForLogin
@@ -32,229 +48,249 @@ pub fn input_switcher_thread(cb_sink: cursive::CbSink, state: Arc<Mutex<LoginSta
             break;
         }
 
-        // Check for the presence of a Yubikey.
+        let Some(index) = unlockers
+            .iter()
+            .position(|unlocker| unlocker.is_available())
+        else {
+            continue;
+        };
+        cb_sink
+            .send(Box::new(move |siv| {
+                // Check that we are really waiting for the login
+                let state: &mut State = siv.user_data().unwrap();
+                if !matches!(
+                    *state.login_state.lock().unwrap(),
+                    LoginState::WaitingForLogin
+                ) {
+                    return;
+                }
 
-        let child = std::process::Command::new("ykinfo")
-            .arg("-s")
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
+                // Check that there exists a box with this unlocker's prompt
+                let name = prompt_view_name(state.unlockers[index].as_ref());
+                let is_shown = siv
+                    .call_on_name(&name, |_view: &mut views::Dialog| ())
+                    .is_some();
+                if !is_shown {
+                    // If not, then the top layer is wrong.
+                    // Pop it and put this unlocker's prompt there.
+                    siv.pop_layer();
+
+                    unlock_prompt(siv, index);
+                }
+            }))
             .unwrap();
-        let output = child.wait_with_output().unwrap();
-        if output.status.success() {
-            // Yubikey is present.
-            cb_sink
-                .send(Box::new(|siv| {
-                    // Check that we are really waiting for the password
-                    let state: &mut State = siv.user_data().unwrap();
-                    if !matches!(
-                        *state.login_state.lock().unwrap(),
-                        LoginState::WaitingForLogin
-                    ) {
-                        return;
-                    }
+    }
+}
 
-                    // Check that there exists a box called "ykpin_input"
-                    let is_ykpin = siv
-                        .call_on_name("ykpin_input", |_view: &mut views::Dialog| ())
-                        .is_some();
+/// This shows where the encryption config that the login is checked against came from,
+/// and the Secure Boot and lockdown state, so that the user can tell whether this boot can be trusted.
+/// The countdown to the default entry, if there is one, is shown under them.
+fn status_lines(siv: &mut Cursive) -> views::LinearLayout {
+    let data: &mut State = siv.user_data().unwrap();
+    let source = match &data.config {
+        Some(config) => format!("Encryption config: {}", config.source),
+        None => "No encryption config".to_string(),
+    };
+    views::LinearLayout::vertical()
+        .child(
+            views::TextView::new(format!("{source}\n{}", data.secure_boot))
+                .style(cursive::theme::ColorStyle::secondary()),
+        )
+        .child(views::TextView::new("").with_name(COUNTDOWN_VIEW))
+}
 
-                    if !is_ykpin {
-                        // If not, then the top layer is wrong.
-                        // Pop it and put the Ykpin layer there.
-                        siv.pop_layer();
+/// Save the keyfiles and show the full menu.
+fn release_keyfiles(siv: &mut Cursive, keyfiles: Vec<DecryptedVolume>) {
+    let data: &mut State = siv.user_data().unwrap();
+    data.keyfiles = Some(keyfiles);
+    *data.login_state.lock().unwrap() = LoginState::LogInOkay;
+    let menu = data.menu.clone();
+    let last_choice = data.last_choice.clone();
+    siv.add_layer(full_menu(&menu, last_choice.as_deref()));
+    start_default_countdown(siv, true);
+}
 
-                        yubikey_pinentry(siv);
-                    }
-                }))
-                .unwrap();
-        } else {
-            // Yubikey is absent.
-            cb_sink
-                .send(Box::new(|siv| {
-                    // Check that we are really waiting for the password
-                    let state: &mut State = siv.user_data().unwrap();
-                    if !matches!(
-                        *state.login_state.lock().unwrap(),
-                        LoginState::WaitingForLogin
-                    ) {
-                        return;
-                    }
+/// Go back to the reduced menu without keeping the keyfiles.
+fn withhold_keyfiles(siv: &mut Cursive) {
+    let data: &mut State = siv.user_data().unwrap();
+    *data.login_state.lock().unwrap() = LoginState::LogInFail;
+    let menu = data.menu.clone();
+    let last_choice = data.last_choice.clone();
+    siv.add_layer(partial_menu(&menu, last_choice.as_deref()));
+}
 
-                    // Check that there exists a box called "password_input"
-                    let is_password = siv
-                        .call_on_name("password_input", |_view: &mut views::Dialog| ())
-                        .is_some();
-                    if !is_password {
-                        // If not, then the top layer is wrong.
-                        // Pop it and put the Yubikey layer there.
-                        siv.pop_layer();
+/// This is called when the password or PIN was correct.
+/// It pops the waiting dialog, and then applies the Secure Boot policy from the menu config
+/// before releasing the keyfiles.
+fn login_succeeded(siv: &mut Cursive, keyfiles: Vec<DecryptedVolume>) {
+    siv.pop_layer();
+    let data: &mut State = siv.user_data().unwrap();
+    let policy = data.menu.secure_boot_policy;
+    let Some(problem) = data.secure_boot.problem() else {
+        release_keyfiles(siv, keyfiles);
+        return;
+    };
 
-                        password_entry(siv);
+    match policy {
+        SecureBootPolicy::Ignore => release_keyfiles(siv, keyfiles),
+        SecureBootPolicy::Require => {
+            drop(keyfiles);
+            withhold_keyfiles(siv);
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(format!(
+                    "{problem}\nThe security policy requires Secure Boot, so the disks will not be unlocked."
+                )))
+                .title("Error")
+                .dismiss_button("OK"),
+            );
+        }
+        SecureBootPolicy::Warn => {
+            // The button callbacks can run more than once, so the keyfiles are taken out of here.
+            // If the dialog is cancelled, they are dropped together with it.
+            let pending = Mutex::new(Some(keyfiles));
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(format!(
+                    "{problem}\nSomething running before the boot menu could be reading what is typed here, \
+                    or could tamper with the system after the disks are unlocked.\n\n\
+                    Unlock the disks anyway?"
+                )))
+                .title("Warning")
+                .button("Unlock anyway", move |siv| {
+                    if let Some(keyfiles) = pending.lock().unwrap().take() {
+                        siv.pop_layer();
+                        release_keyfiles(siv, keyfiles);
                     }
-                }))
-                .unwrap();
+                })
+                .button("Cancel", |siv| {
+                    siv.pop_layer();
+                    withhold_keyfiles(siv);
+                }),
+            );
         }
     }
 }
 
-/// This function pushes a dialog layer that prompts for a password.
-pub fn password_entry(siv: &mut Cursive) {
+/// This function pushes a dialog explaining that logging in is not possible,
+/// because no valid encryption config was found.
+pub fn config_unavailable_dialog(siv: &mut Cursive) {
+    let data: &mut State = siv.user_data().unwrap();
+    let mut text =
+        "No valid encryption config was found, so logging in is not possible.\n\n".to_string();
+    for error in &data.config_errors {
+        text.push_str(error);
+        text.push('\n');
+    }
     siv.add_layer(
-        views::Dialog::new()
-            .title("Please enter password to continue...")
-            .content(views::LinearLayout::vertical().child({
-                let mut edit = views::EditView::new();
-                edit.set_secret(true);
-                edit.set_on_submit(|siv, text| {
-                    // TODO: right now we do not do anything with the password we read.
-                    // Later, this should be validated, e.g. for decrypting the root partition.
-                    let data: &mut State = siv.user_data().unwrap();
-                    let mut stateref = data.login_state.lock().unwrap();
-                    *stateref = LoginState::ValidatingLogin;
-                    drop(stateref);
-
-                    let config = data.config.clone();
+        views::Dialog::around(views::TextView::new(text))
+            .title("Error")
+            .dismiss_button("OK"),
+    );
+}
 
-                    // Remove the password entry box, and show a "waiting" box,
-                    // and in a thread start verifying the result.
-                    siv.pop_layer();
-                    siv.add_layer(views::Dialog::around(
-                        views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
-                            .child(spinner_view())
-                            .child(views::TextView::new("Verifying password...")),
-                    ));
+/// Show the reduced menu, with the error from the unlocker on top.
+fn login_failed(siv: &mut Cursive, message: &str) {
+    let data: &mut State = siv.user_data().unwrap();
 
-                    let cb_sink = siv.cb_sink().clone();
-                    let pw = text.to_string();
-                    std::thread::spawn(move || match config.try_keyfile_from_password(pw) {
-                        Ok(keyfile) => {
-                            cb_sink
-                                .send(Box::new(|siv| {
-                                    let data: &mut State = siv.user_data().unwrap();
+    // Set the state to be failed.
+    *data.login_state.lock().unwrap() = LoginState::LogInFail;
 
-                                    // Set the state to be logged in, and save the keyfile contents.
-                                    data.keyfile = Some(keyfile);
-                                    *data.login_state.lock().unwrap() = LoginState::LogInOkay;
+    // Pop the waiting dialog, then draw the reduced menu,
+    // and on top of that draw an error message.
+    let menu = data.menu.clone();
+    let last_choice = data.last_choice.clone();
+    siv.pop_layer();
+    siv.add_layer(partial_menu(&menu, last_choice.as_deref()));
+    siv.add_layer(
+        views::Dialog::around(views::TextView::new(message))
+            .title("Error")
+            .dismiss_button("OK"),
+    )
+}
 
-                                    // Pop the waiting dialog, and draw the full menu.
-                                    siv.pop_layer();
-                                    siv.add_layer(full_menu());
-                                }))
-                                .unwrap();
-                        }
-                        Err(_) => {
-                            cb_sink
-                                .send(Box::new(|siv| {
-                                    let data: &mut State = siv.user_data().unwrap();
+/// Check the input with the unlocker, in a thread, while a "waiting" box is shown.
+fn submit_input(siv: &mut Cursive, index: usize, input: String) {
+    let data: &mut State = siv.user_data().unwrap();
+    let Some(config) = data.config.as_ref().map(|c| c.params.clone()) else {
+        return;
+    };
+    let mut stateref = data.login_state.lock().unwrap();
+    *stateref = LoginState::ValidatingLogin;
+    drop(stateref);
+    let unlockers = data.unlockers.clone();
+    let prompt = unlockers[index].prompt();
 
-                                    // Set the state to be failed.
-                                    *data.login_state.lock().unwrap() = LoginState::LogInFail;
+    // Remove the prompt, and show a "waiting" box,
+    // and in a thread start verifying the result.
+    siv.pop_layer();
+    siv.add_layer(views::Dialog::around(
+        views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
+            .child(spinner_view())
+            .child(views::TextView::new(prompt.verifying).with_name(VERIFYING_VIEW)),
+    ));
 
-                                    // Pop the waiting dialog, then draw the reduced menu,
-                                    // and on top of that draw an error message.
-                                    siv.pop_layer();
-                                    siv.add_layer(partial_menu());
-                                    siv.add_layer(
-                                        views::Dialog::around(views::TextView::new(
-                                            "Failed to unlock with password",
-                                        ))
-                                        .title("Error")
-                                        .dismiss_button("OK"),
-                                    )
-                                }))
-                                .unwrap();
-                        }
-                    });
+    let cb_sink = siv.cb_sink().clone();
+    std::thread::spawn(move || {
+        // The unlocker can replace the message while it runs, like when the Yubikey is waiting for a touch.
+        let notify_sink = cb_sink.clone();
+        let notify = move |message: &'static str| {
+            let _ = notify_sink.send(Box::new(move |siv| {
+                siv.call_on_name(VERIFYING_VIEW, |view: &mut views::TextView| {
+                    view.set_content(message)
                 });
-                edit
-            }))
-            .with_name("password_input"),
-    )
+            }));
+        };
+        match config.try_keyfiles(unlockers[index].as_ref(), input, &notify) {
+            Ok(keyfiles) => {
+                cb_sink
+                    .send(Box::new(|siv| login_succeeded(siv, keyfiles)))
+                    .unwrap();
+            }
+            Err(why) => {
+                cb_sink
+                    .send(Box::new(move |siv| {
+                        login_failed(siv, &format!("{}: {why}", prompt.failed))
+                    }))
+                    .unwrap();
+            }
+        }
+    });
 }
 
-/// This function pushes a dialog layer that prompts for a Yubikey pin
-pub fn yubikey_pinentry(siv: &mut Cursive) {
+/// This function pushes a dialog layer with the prompt of the unlocker at this index in the list.
+fn unlock_prompt(siv: &mut Cursive, index: usize) {
+    let status = status_lines(siv);
+    let data: &mut State = siv.user_data().unwrap();
+    let unlocker = &data.unlockers[index];
+    let prompt = unlocker.prompt();
+    let name = prompt_view_name(unlocker.as_ref());
+
+    let mut content = views::LinearLayout::vertical().child(status);
+    match prompt.input {
+        InputKind::Secret => {
+            let mut edit = views::EditView::new();
+            edit.set_secret(true);
+            edit.set_on_submit(move |siv, text| submit_input(siv, index, text.to_string()));
+            content.add_child(edit);
+        }
+        InputKind::None => {
+            content.add_child(views::Button::new("Unlock", move |siv| {
+                submit_input(siv, index, String::new())
+            }));
+        }
+    }
     siv.add_layer(
         views::Dialog::new()
-            .title("Please enter PIN to continue...")
-            .content(views::LinearLayout::vertical().child({
-                let mut edit = views::EditView::new();
-                edit.set_secret(true);
-                edit.set_on_submit(|siv, text| {
-                    // TODO: right now we do not do anything with the password we read.
-                    // Later, this should be validated, e.g. for decrypting the root partition.
-                    let data: &mut State = siv.user_data().unwrap();
-                    let mut stateref = data.login_state.lock().unwrap();
-                    *stateref = LoginState::ValidatingLogin;
-                    drop(stateref);
-
-                    let config = data.config.clone();
-
-                    // Remove the password entry box, and show a "waiting" box,
-                    // and in a thread start verifying the result.
-                    siv.pop_layer();
-                    siv.add_layer(views::Dialog::around(
-                        views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
-                            .child(spinner_view())
-                            .child(views::TextView::new(
-                                "Verifying PIN code; you may need to touch your Yubikey now...",
-                            )),
-                    ));
-
-                    let cb_sink = siv.cb_sink().clone();
-                    let pw = text.to_string();
-                    std::thread::spawn(move || {
-                        // We need to try the Yubikey a couple times,
-                        // because only one process may use it at one time,
-                        // and the detection thread could be still running its copy.
-                        let resp = config
-                            .try_keyfile_from_pin(pw.clone())
-                            .or_else(|_| config.try_keyfile_from_pin(pw.clone()))
-                            .or_else(|_| config.try_keyfile_from_pin(pw.clone()));
-                        match resp {
-                            Ok(keyfile) => {
-                                cb_sink
-                                    .send(Box::new(|siv| {
-                                        let data: &mut State = siv.user_data().unwrap();
-
-                                        // Set the state to be logged in, and save the keyfile contents.
-                                        data.keyfile = Some(keyfile);
-                                        *data.login_state.lock().unwrap() = LoginState::LogInOkay;
-
-                                        // Pop the waiting dialog, and draw the full menu.
-                                        siv.pop_layer();
-                                        siv.add_layer(full_menu());
-                                    }))
-                                    .unwrap();
-                            }
-                            Err(_) => {
-                                cb_sink
-                                    .send(Box::new(|siv| {
-                                        let data: &mut State = siv.user_data().unwrap();
-
-                                        // Set the state to be failed.
-                                        *data.login_state.lock().unwrap() = LoginState::LogInFail;
-
-                                        // Pop the waiting dialog, then draw the reduced menu,
-                                        // and on top of that draw an error message.
-                                        siv.pop_layer();
-                                        siv.add_layer(partial_menu());
-                                        siv.add_layer(
-                                            views::Dialog::around(views::TextView::new(
-                                                "Failed to unlock with Yubikey",
-                                            ))
-                                            .title("Error")
-                                            .dismiss_button("OK"),
-                                        )
-                                    }))
-                                    .unwrap();
-                            }
-                        }
-                    });
-                });
-                edit
-            }))
-            .with_name("ykpin_input"),
+            .title(prompt.title)
+            .content(content)
+            .with_name(name),
     )
 }
+
+/// This function pushes the login prompt.
+/// It starts with the last unlocker, which is always available,
+/// and the switcher thread replaces it if a preferred one is available.
+pub fn login_prompt(siv: &mut Cursive) {
+    let data: &mut State = siv.user_data().unwrap();
+    let last = data.unlockers.len() - 1;
+    unlock_prompt(siv, last);
+}
@@ -0,0 +1,182 @@
+//! Just enough of the PE format to find the sections of a unified kernel image.
+//! See https://learn.microsoft.com/en-us/windows/win32/debug/pe-format
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+/// The offset of the field that points at the PE signature.
+const PE_POINTER_OFFSET: usize = 0x3c;
+const PE_SIGNATURE: &[u8] = b"PE\0\0";
+
+/// The size of the signature plus the COFF file header.
+const COFF_HEADER_END: usize = 24;
+const SECTION_HEADER_SIZE: usize = 40;
+
+/// A section of a PE image, like `.linux` or `.cmdline` in a UKI.
+pub struct Section<'a> {
+    pub name: String,
+    pub data: &'a [u8],
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Where a section of a PE image is in the file.
+pub struct SectionHeader {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// How much of the start of a file is read to find the section table.
+/// The headers have to fit in SizeOfHeaders, which is a few KiB at most in practice.
+const HEADERS_READ_SIZE: u64 = 64 * 1024;
+
+/// Read the section table of a PE image.
+/// Only the headers are needed, not the whole image.
+pub fn section_headers(headers: &[u8]) -> Result<Vec<SectionHeader>, String> {
+    if !headers.starts_with(b"MZ") {
+        return Err("Not a PE image: missing MZ header".to_string());
+    }
+    let pe_offset = u32_at(headers, PE_POINTER_OFFSET).ok_or("PE image is truncated")? as usize;
+    if headers.get(pe_offset..pe_offset + 4) != Some(PE_SIGNATURE) {
+        return Err("Not a PE image: missing PE signature".to_string());
+    }
+    let section_count = u16_at(headers, pe_offset + 6).ok_or("PE image is truncated")? as usize;
+    let optional_header_size =
+        u16_at(headers, pe_offset + 20).ok_or("PE image is truncated")? as usize;
+    let section_table = pe_offset + COFF_HEADER_END + optional_header_size;
+
+    let mut sections = vec![];
+    for index in 0..section_count {
+        let header = section_table + index * SECTION_HEADER_SIZE;
+        let name = headers
+            .get(header..header + 8)
+            .ok_or("PE section table is truncated")?;
+        let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+        let name = String::from_utf8_lossy(&name[..end]).to_string();
+
+        let virtual_size = u32_at(headers, header + 8).ok_or("PE section table is truncated")?;
+        let raw_size = u32_at(headers, header + 16).ok_or("PE section table is truncated")?;
+        let raw_offset = u32_at(headers, header + 20).ok_or("PE section table is truncated")?;
+
+        // The raw data is padded to the file alignment, and the virtual size is the real one,
+        // unless the section is partly made of zeros that aren't stored in the file.
+        sections.push(SectionHeader {
+            name,
+            offset: raw_offset as usize,
+            size: virtual_size.min(raw_size) as usize,
+        });
+    }
+    Ok(sections)
+}
+
+/// List the sections of a PE image.
+pub fn sections(image: &[u8]) -> Result<Vec<Section<'_>>, String> {
+    section_headers(image)?
+        .into_iter()
+        .map(|header| {
+            let data = image
+                .get(header.offset..header.offset + header.size)
+                .ok_or_else(|| format!("PE section {} is outside the file", header.name))?;
+            Ok(Section {
+                name: header.name,
+                data,
+            })
+        })
+        .collect()
+}
+
+/// Read the section table of a PE image file, without reading the rest of it.
+pub fn read_section_headers(file: &mut File) -> Result<Vec<SectionHeader>, String> {
+    let mut headers = vec![];
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.take(HEADERS_READ_SIZE).read_to_end(&mut headers))
+        .map_err(|why| format!("Failed to read PE headers: {why}"))?;
+    section_headers(&headers)
+}
+
+/// Read the content of one section of a PE image file.
+pub fn read_section(file: &mut File, header: &SectionHeader) -> Result<Vec<u8>, String> {
+    let mut data = vec![0; header.size];
+    file.seek(SeekFrom::Start(header.offset as u64))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(|why| format!("Failed to read PE section {}: {why}", header.name))?;
+    Ok(data)
+}
+
+/// Find the content of the section with this name.
+pub fn find_section<'a>(image: &'a [u8], name: &str) -> Result<Option<&'a [u8]>, String> {
+    Ok(sections(image)?
+        .into_iter()
+        .find(|section| section.name == name)
+        .map(|section| section.data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_section, sections};
+
+    /// Build a PE image with no optional header, and these sections laid out after the headers.
+    fn image(contents: &[(&str, &[u8])]) -> Vec<u8> {
+        let pe_offset = 0x40;
+        let table = pe_offset + 24;
+        let mut data_offset = (table + 40 * contents.len()).next_multiple_of(16);
+        let mut buf = vec![0; data_offset];
+        buf[0..2].copy_from_slice(b"MZ");
+        buf[0x3c..0x40].copy_from_slice(&(pe_offset as u32).to_le_bytes());
+        buf[pe_offset..pe_offset + 4].copy_from_slice(b"PE\0\0");
+        buf[pe_offset + 6..pe_offset + 8].copy_from_slice(&(contents.len() as u16).to_le_bytes());
+
+        for (index, (name, data)) in contents.iter().enumerate() {
+            let header = table + index * 40;
+            buf[header..header + name.len()].copy_from_slice(name.as_bytes());
+            buf[header + 8..header + 12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            // Pad the raw data, like a linker would.
+            let raw_size = data.len().next_multiple_of(16);
+            buf[header + 16..header + 20].copy_from_slice(&(raw_size as u32).to_le_bytes());
+            buf[header + 20..header + 24].copy_from_slice(&(data_offset as u32).to_le_bytes());
+            data_offset += raw_size;
+        }
+        for (_, data) in contents {
+            buf.extend_from_slice(data);
+            buf.resize(buf.len().next_multiple_of(16), 0);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_sections() {
+        let buf = image(&[(".osrel", b"ID=arch\n"), (".cmdline", b"quiet rw")]);
+        let names: Vec<String> = sections(&buf)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec![".osrel", ".cmdline"]);
+        assert_eq!(
+            find_section(&buf, ".cmdline").unwrap(),
+            Some(&b"quiet rw"[..])
+        );
+        assert_eq!(find_section(&buf, ".linux").unwrap(), None);
+    }
+
+    #[test]
+    fn test_not_pe() {
+        assert!(sections(b"\x7fELF").is_err());
+        let mut buf = image(&[]);
+        buf[0x40] = b'X';
+        assert!(sections(&buf).is_err());
+    }
+}
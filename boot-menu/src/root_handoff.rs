@@ -0,0 +1,90 @@
+//! Passing a different root filesystem to the rest of the boot.
+//!
+//! The `boot_menu_root` hook runs after `encrypt` and `lvm2`, and sources this file if it exists,
+//! so the variables in it replace the `root=` and `rootflags=` from the kernel cmdline
+//! before the initramfs mounts the root.
+
+use cursive::{views, Cursive};
+
+use crate::exits::finish_boot;
+
+/// The file that the `boot_menu_root` hook reads.
+pub const ROOT_HANDOFF_PATH: &str = "/run/boot-menu/root.env";
+
+/// The cmdline parameters to replace. The ones that are None are left as they are.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct RootOverrides {
+    pub root: Option<String>,
+    pub rootflags: Option<String>,
+}
+
+/// Quote a value for a POSIX shell, so that it can't be anything but a string.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+impl RootOverrides {
+    /// The file content: shell variable assignments.
+    pub fn to_shell(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in [("root", &self.root), ("rootflags", &self.rootflags)] {
+            if let Some(value) = value {
+                text.push_str(&format!("{name}={}\n", shell_quote(value)));
+            }
+        }
+        text
+    }
+
+    pub fn write(&self) -> Result<(), String> {
+        let path = std::path::Path::new(ROOT_HANDOFF_PATH);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|why| format!("Failed to create {}: {why}", dir.display()))?;
+        }
+        std::fs::write(path, self.to_shell())
+            .map_err(|why| format!("Failed to write {ROOT_HANDOFF_PATH}: {why}"))
+    }
+}
+
+/// Continue the boot with the cmdline's root, after something went wrong with choosing another one.
+/// The volumes are already unlocked at this point, so every way out of this continues the boot.
+pub fn continue_anyway(siv: &mut Cursive, why: String) {
+    siv.add_layer(
+        views::Dialog::around(views::TextView::new(format!(
+            "{why}\nThe boot will continue with the root from the kernel cmdline."
+        )))
+        .title("Error")
+        .button("OK", finish_boot),
+    );
+}
+
+/// Leave the menu, and continue the boot with these overrides.
+pub fn boot_with_root(siv: &mut Cursive, overrides: &RootOverrides) {
+    match overrides.write() {
+        Ok(()) => finish_boot(siv),
+        Err(why) => continue_anyway(siv, why),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RootOverrides;
+
+    #[test]
+    fn test_to_shell() {
+        let overrides = RootOverrides {
+            root: None,
+            rootflags: Some("subvol=@snapshots/1/snapshot,compress=zstd".to_string()),
+        };
+        assert_eq!(
+            overrides.to_shell(),
+            "rootflags='subvol=@snapshots/1/snapshot,compress=zstd'\n"
+        );
+
+        let overrides = RootOverrides {
+            root: Some("/dev/it's".to_string()),
+            rootflags: None,
+        };
+        assert_eq!(overrides.to_shell(), "root='/dev/it'\\''s'\n");
+    }
+}
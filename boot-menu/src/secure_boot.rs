@@ -0,0 +1,143 @@
+use std::fmt::Display;
+
+use serde::Deserialize;
+
+use crate::efivarfs::{self, EFI_GLOBAL_VARIABLE};
+
+/// Where the kernel shows the lockdown mode, if the lockdown LSM is enabled.
+/// The runtime hook mounts securityfs here before starting the menu.
+const LOCKDOWN_PATH: &str = "/sys/kernel/security/lockdown";
+
+/// What to do when logging in succeeds, but the firmware isn't enforcing Secure Boot.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SecureBootPolicy {
+    /// Release the keyfiles anyway.
+    #[default]
+    Ignore,
+
+    /// Ask the user whether to continue before releasing the keyfiles.
+    Warn,
+
+    /// Never release the keyfiles.
+    Require,
+}
+
+/// The Secure Boot state of the firmware and the kernel.
+/// Each value is None if it couldn't be read.
+#[derive(Clone, Default, Debug)]
+pub struct SecureBootStatus {
+    pub secure_boot: Option<bool>,
+    pub setup_mode: Option<bool>,
+    pub audit_mode: Option<bool>,
+    pub deployed_mode: Option<bool>,
+    pub lockdown: Option<String>,
+}
+
+/// Read one of the one-byte boolean variables, like SecureBoot.
+fn read_flag(name: &str) -> Option<bool> {
+    let (_, value) = efivarfs::read(name, EFI_GLOBAL_VARIABLE).ok()?;
+    Some(*value.first()? == 1)
+}
+
+/// Find the active mode in the lockdown file, which looks like `none [integrity] confidentiality`.
+fn parse_lockdown(text: &str) -> Option<String> {
+    text.split_whitespace()
+        .find_map(|word| word.strip_prefix('[')?.strip_suffix(']'))
+        .map(|mode| mode.to_string())
+}
+
+impl SecureBootStatus {
+    pub fn read() -> Self {
+        Self {
+            secure_boot: read_flag("SecureBoot"),
+            setup_mode: read_flag("SetupMode"),
+            audit_mode: read_flag("AuditMode"),
+            deployed_mode: read_flag("DeployedMode"),
+            lockdown: std::fs::read_to_string(LOCKDOWN_PATH)
+                .ok()
+                .and_then(|text| parse_lockdown(&text)),
+        }
+    }
+
+    /// Why the firmware can't be trusted to only have run signed code, if it can't.
+    /// In Setup Mode, anything running before us could have enrolled its own keys.
+    pub fn problem(&self) -> Option<&'static str> {
+        match (self.secure_boot, self.setup_mode) {
+            (Some(true), Some(false)) => None,
+            (_, Some(true)) => Some("The firmware is in Setup Mode."),
+            (Some(false), _) => Some("Secure Boot is off."),
+            _ => Some("The Secure Boot state could not be read."),
+        }
+    }
+}
+
+impl Display for SecureBootStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn on_off(value: Option<bool>) -> &'static str {
+            match value {
+                Some(true) => "on",
+                Some(false) => "off",
+                None => "unknown",
+            }
+        }
+        write!(
+            f,
+            "Secure Boot: {}, Setup Mode: {}",
+            on_off(self.secure_boot),
+            on_off(self.setup_mode)
+        )?;
+        // These only exist on firmware implementing UEFI 2.5 or later, so they're only shown when set.
+        if self.audit_mode == Some(true) {
+            write!(f, ", Audit Mode")?;
+        }
+        if self.deployed_mode == Some(true) {
+            write!(f, ", Deployed Mode")?;
+        }
+        write!(
+            f,
+            ", lockdown: {}",
+            self.lockdown.as_deref().unwrap_or("unavailable")
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_lockdown, SecureBootStatus};
+
+    #[test]
+    fn test_parse_lockdown() {
+        assert_eq!(
+            parse_lockdown("none [integrity] confidentiality\n").as_deref(),
+            Some("integrity")
+        );
+        assert_eq!(parse_lockdown("none integrity confidentiality"), None);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut status = SecureBootStatus {
+            secure_boot: Some(true),
+            setup_mode: Some(false),
+            deployed_mode: Some(true),
+            lockdown: Some("integrity".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(status.problem(), None);
+        assert_eq!(
+            status.to_string(),
+            "Secure Boot: on, Setup Mode: off, Deployed Mode, lockdown: integrity"
+        );
+
+        status.setup_mode = Some(true);
+        assert_eq!(status.problem(), Some("The firmware is in Setup Mode."));
+
+        let status = SecureBootStatus::default();
+        assert!(status.problem().is_some());
+        assert_eq!(
+            status.to_string(),
+            "Secure Boot: unknown, Setup Mode: unknown, lockdown: unavailable"
+        );
+    }
+}
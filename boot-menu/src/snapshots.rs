@@ -0,0 +1,320 @@
+//! Booting into another btrfs subvolume of the root filesystem, like a snapper snapshot.
+//! See http://snapper.io/ for the layout of the snapshots.
+
+use std::{path::Path, process::Stdio};
+
+use cursive::{views, Cursive};
+
+use crate::{
+    block_device,
+    cmdline::{self, DeviceSpec},
+    esp,
+    exits::finish_boot,
+    root_handoff::{boot_with_root, continue_anyway, RootOverrides},
+    spinner::spinner_view,
+};
+
+/// Where the top level of the root filesystem is mounted while the subvolumes are listed.
+const BTRFS_MOUNTPOINT: &str = "/run/boot-menu/btrfs";
+
+/// The btrfs ID of the top-level subvolume, which is always there.
+const TOP_LEVEL_SUBVOLUME_ID: u64 = 5;
+
+/// What snapper records about a snapshot in its `info.xml`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapperInfo {
+    pub number: u64,
+    /// Like `single`, `pre` or `post`.
+    pub kind: String,
+    /// In UTC, like `2024-01-15 10:00:00`.
+    pub date: String,
+    pub description: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subvolume {
+    pub id: u64,
+    /// The path from the top level of the filesystem, which is what `subvol=` takes.
+    pub path: String,
+    pub snapshot: Option<SnapperInfo>,
+}
+
+impl Subvolume {
+    pub fn label(&self) -> String {
+        match &self.snapshot {
+            Some(info) => format!(
+                "#{} {} UTC, {}: {} ({})",
+                info.number, info.date, info.kind, info.description, self.path
+            ),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// Parse the output of `btrfs subvolume list`, which has lines like
+/// `ID 256 gen 1234 top level 5 path @`.
+pub fn parse_subvolume_list(output: &str) -> Vec<Subvolume> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let id = line.strip_prefix("ID ")?.split_whitespace().next()?;
+            let (_, path) = line.split_once(" path ")?;
+            Some(Subvolume {
+                id: id.parse().ok()?,
+                path: path.trim().to_string(),
+                snapshot: None,
+            })
+        })
+        .collect()
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Find the text of the first element with this name.
+/// The files that snapper writes are simple enough to not need a real XML parser.
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    Some(xml_unescape(xml[start..end].trim()))
+}
+
+pub fn parse_snapper_info(xml: &str) -> Option<SnapperInfo> {
+    Some(SnapperInfo {
+        number: xml_element(xml, "num")?.parse().ok()?,
+        kind: xml_element(xml, "type").unwrap_or_default(),
+        date: xml_element(xml, "date").unwrap_or_default(),
+        description: xml_element(xml, "description").unwrap_or_default(),
+    })
+}
+
+/// Where snapper keeps the `info.xml` of a snapshot subvolume, like `@snapshots/12/snapshot`.
+fn snapper_info_path(path: &str) -> Option<String> {
+    let directory = path.strip_suffix("/snapshot")?;
+    let (_, number) = directory.rsplit_once('/')?;
+    number.parse::<u64>().ok()?;
+    Some(format!("{directory}/info.xml"))
+}
+
+/// Whether this subvolume only holds snapshots, like `@snapshots` or `@/.snapshots`,
+/// which is not something to boot.
+fn is_snapshot_container(path: &str, subvolumes: &[Subvolume]) -> bool {
+    subvolumes.iter().any(|other| {
+        let Some(rest) = other.path.strip_prefix(&format!("{path}/")) else {
+            return false;
+        };
+        rest.split_once('/')
+            .is_some_and(|(number, name)| name == "snapshot" && number.parse::<u64>().is_ok())
+    })
+}
+
+/// Put the subvolumes in the order they are shown: the plain ones first,
+/// then the snapshots, newest first.
+pub fn sort_subvolumes(subvolumes: &mut [Subvolume]) {
+    subvolumes.sort_by(|a, b| match (&a.snapshot, &b.snapshot) {
+        (None, None) => a.path.cmp(&b.path),
+        (None, Some(_)) => std::cmp::Ordering::Less,
+        (Some(_), None) => std::cmp::Ordering::Greater,
+        (Some(a), Some(b)) => b.number.cmp(&a.number),
+    });
+}
+
+/// The rootflags to mount this subvolume, keeping the other options from the cmdline.
+pub fn rootflags_for(current: Option<&str>, subvolume: &str) -> String {
+    let mut flags: Vec<&str> = current
+        .unwrap_or("")
+        .split(',')
+        .filter(|flag| {
+            !flag.is_empty() && !flag.starts_with("subvol=") && !flag.starts_with("subvolid=")
+        })
+        .collect();
+    let subvol = format!("subvol={subvolume}");
+    flags.push(&subvol);
+    flags.join(",")
+}
+
+fn list_subvolumes(mountpoint: &Path) -> Result<Vec<Subvolume>, String> {
+    let output = std::process::Command::new("btrfs")
+        .args(["subvolume", "list"])
+        .arg(mountpoint)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|why| format!("Failed to spawn btrfs: {why}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "btrfs subvolume list failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let listed = parse_subvolume_list(&String::from_utf8_lossy(&output.stdout));
+
+    let mut subvolumes = vec![];
+    for subvolume in &listed {
+        if subvolume.id == TOP_LEVEL_SUBVOLUME_ID || is_snapshot_container(&subvolume.path, &listed)
+        {
+            continue;
+        }
+        let snapshot = snapper_info_path(&subvolume.path)
+            .and_then(|info| std::fs::read_to_string(mountpoint.join(info)).ok())
+            .and_then(|xml| parse_snapper_info(&xml));
+        subvolumes.push(Subvolume {
+            snapshot,
+            ..subvolume.clone()
+        });
+    }
+    sort_subvolumes(&mut subvolumes);
+    Ok(subvolumes)
+}
+
+/// Mount the top level of the root filesystem named on the cmdline read-only, and list its subvolumes.
+/// Returns the rootflags from the cmdline too.
+pub fn list_root_subvolumes() -> Result<(Option<String>, Vec<Subvolume>), String> {
+    let root = cmdline::read_param("root")?.ok_or("The kernel cmdline has no root=")?;
+    let rootflags = cmdline::read_param("rootflags")?;
+    let spec = DeviceSpec::parse(&root);
+    let device = block_device::resolve(&spec).ok_or(format!("Root device {spec} not found"))?;
+
+    std::fs::create_dir_all(BTRFS_MOUNTPOINT)
+        .map_err(|why| format!("Failed to create {BTRFS_MOUNTPOINT}: {why}"))?;
+    esp::run(
+        std::process::Command::new("mount")
+            .args(["-t", "btrfs", "-o"])
+            .arg(format!("ro,subvolid={TOP_LEVEL_SUBVOLUME_ID}"))
+            .arg(&device)
+            .arg(BTRFS_MOUNTPOINT),
+    )?;
+
+    let result = list_subvolumes(Path::new(BTRFS_MOUNTPOINT));
+
+    esp::run(std::process::Command::new("umount").arg(BTRFS_MOUNTPOINT))?;
+    Ok((rootflags, result?))
+}
+
+fn boot_subvolume(siv: &mut Cursive, rootflags: Option<&str>, path: &Option<String>) {
+    let Some(path) = path else {
+        finish_boot(siv);
+        return;
+    };
+    let overrides = RootOverrides {
+        root: None,
+        rootflags: Some(rootflags_for(rootflags, path)),
+    };
+    boot_with_root(siv, &overrides);
+}
+
+/// This function lists the subvolumes of the unlocked root filesystem,
+/// and continues the boot with the selected one as the root.
+pub fn snapshot_picker(siv: &mut Cursive) {
+    siv.add_layer(views::Dialog::around(
+        views::LinearLayout::new(cursive::direction::Orientation::Horizontal)
+            .child(spinner_view())
+            .child(views::TextView::new("Looking for snapshots...")),
+    ));
+    let cb_sink = siv.cb_sink().clone();
+    std::thread::spawn(move || {
+        let result = list_root_subvolumes();
+        cb_sink
+            .send(Box::new(move |siv| {
+                siv.pop_layer();
+                let (rootflags, subvolumes) = match result {
+                    Ok(found) => found,
+                    Err(why) => {
+                        continue_anyway(siv, why);
+                        return;
+                    }
+                };
+
+                let mut select = views::SelectView::new().autojump();
+                select.add_item("The root from the kernel cmdline", None);
+                for subvolume in subvolumes {
+                    select.add_item(subvolume.label(), Some(subvolume.path));
+                }
+                select.set_on_submit(move |siv, path: &Option<String>| {
+                    boot_subvolume(siv, rootflags.as_deref(), path)
+                });
+                siv.add_layer(views::Dialog::around(select).title("Boot snapshot"));
+            }))
+            .unwrap();
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        is_snapshot_container, parse_snapper_info, parse_subvolume_list, rootflags_for,
+        snapper_info_path,
+    };
+
+    #[test]
+    fn test_parse_subvolume_list() {
+        let subvolumes = parse_subvolume_list(
+            "ID 256 gen 2101 top level 5 path @\n\
+             ID 257 gen 2099 top level 5 path @home\n\
+             ID 258 gen 2050 top level 5 path @snapshots\n\
+             ID 260 gen 1900 top level 258 path @snapshots/1/snapshot\n",
+        );
+        assert_eq!(subvolumes.len(), 4);
+        assert_eq!(subvolumes[3].id, 260);
+        assert_eq!(subvolumes[3].path, "@snapshots/1/snapshot");
+        assert_eq!(
+            snapper_info_path(&subvolumes[3].path).as_deref(),
+            Some("@snapshots/1/info.xml")
+        );
+        assert_eq!(snapper_info_path("@home"), None);
+
+        assert!(is_snapshot_container("@snapshots", &subvolumes));
+        assert!(!is_snapshot_container("@", &subvolumes));
+        assert!(!is_snapshot_container("@home", &subvolumes));
+    }
+
+    #[test]
+    fn test_nested_snapshots() {
+        let subvolumes = parse_subvolume_list(
+            "ID 256 gen 2101 top level 5 path @\n\
+             ID 257 gen 2050 top level 256 path @/.snapshots\n\
+             ID 260 gen 1900 top level 257 path @/.snapshots/1/snapshot\n",
+        );
+        assert!(!is_snapshot_container("@", &subvolumes));
+        assert!(is_snapshot_container("@/.snapshots", &subvolumes));
+    }
+
+    #[test]
+    fn test_parse_snapper_info() {
+        let info = parse_snapper_info(
+            r#"<?xml version="1.0"?>
+<snapshot>
+  <type>pre</type>
+  <num>42</num>
+  <date>2024-01-15 10:00:00</date>
+  <description>pacman -Syu &amp; reboot</description>
+  <cleanup>number</cleanup>
+</snapshot>"#,
+        )
+        .unwrap();
+        assert_eq!(info.number, 42);
+        assert_eq!(info.kind, "pre");
+        assert_eq!(info.date, "2024-01-15 10:00:00");
+        assert_eq!(info.description, "pacman -Syu & reboot");
+        assert!(parse_snapper_info("<snapshot></snapshot>").is_none());
+    }
+
+    #[test]
+    fn test_rootflags_for() {
+        assert_eq!(
+            rootflags_for(None, "@/.snapshots/1/snapshot"),
+            "subvol=@/.snapshots/1/snapshot"
+        );
+        assert_eq!(
+            rootflags_for(
+                Some("compress=zstd,subvol=@,subvolid=256"),
+                "@snapshots/3/snapshot"
+            ),
+            "compress=zstd,subvol=@snapshots/3/snapshot"
+        );
+    }
+}
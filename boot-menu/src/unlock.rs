@@ -0,0 +1,241 @@
+use std::{
+    io::{Seek, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use disk_crypto::disk_encryption::DecryptedVolume;
+use rand::Rng;
+
+use crate::{
+    block_device::{self, DEVICE_WAIT_TIMEOUT},
+    cmdline::{CryptOption, DeviceSpec, UnlockTarget},
+    lvm::deactivate_volume_groups,
+};
+
+/// Where the keyfile of the volume named on the cmdline is written.
+/// The encrypt hook looks for it here, so if we fail to run cryptsetup,
+/// it can try it before falling back to password.
+pub const ROOT_KEYFILE_PATH: &str = "/crypto_keyfile.bin";
+
+/// A volume with its keyfile, and where to find it.
+pub struct VolumeToOpen {
+    pub name: String,
+    pub target: UnlockTarget,
+    pub keyfile: Vec<u8>,
+    pub keyfile_path: PathBuf,
+}
+
+/// Work out where each of the decrypted volumes is.
+///
+/// A volume without a device is the one named on the kernel cmdline.
+/// If the cmdline doesn't name one, the volume can't be opened,
+/// and the error is returned in its place.
+pub fn plan_volumes(
+    volumes: &[DecryptedVolume],
+    cmdline_targets: &[UnlockTarget],
+) -> Vec<Result<VolumeToOpen, (String, String)>> {
+    volumes
+        .iter()
+        .map(|volume| match &volume.device {
+            None => match cmdline_targets.first() {
+                Some(target) => Ok(VolumeToOpen {
+                    name: volume.name.clone(),
+                    target: target.clone(),
+                    keyfile: volume.keyfile.clone(),
+                    keyfile_path: PathBuf::from(ROOT_KEYFILE_PATH),
+                }),
+                None => Err((
+                    volume.name.clone(),
+                    "The kernel cmdline does not name an encrypted device (cryptdevice= or rd.luks.*)"
+                        .to_string(),
+                )),
+            },
+            Some(device) => Ok(VolumeToOpen {
+                name: volume.name.clone(),
+                target: UnlockTarget {
+                    device: DeviceSpec::parse(device),
+                    mapper_name: volume.name.clone(),
+                    options: volume
+                        .options
+                        .iter()
+                        .filter_map(|o| CryptOption::parse(o))
+                        .collect(),
+                },
+                keyfile: volume.keyfile.clone(),
+                keyfile_path: PathBuf::from(format!("/crypto_keyfile-{}.bin", volume.name)),
+            }),
+        })
+        .collect()
+}
+
+/// Overwrite the content of a file with random data multiple times.
+fn wipe_file(out: &mut std::fs::File, len: usize) -> std::io::Result<()> {
+    let mut rng = rand::rngs::OsRng;
+    let mut chunk: [u8; 512] = [0; 512];
+    for _ in 0..4 {
+        let mut written = 0;
+        out.seek(std::io::SeekFrom::Start(0))?;
+
+        while written < len {
+            rng.fill(&mut chunk);
+            written += out.write(&chunk)?;
+        }
+    }
+    out.sync_all()
+}
+
+/// Check whether the volume is already open under its mapper name.
+pub fn is_open(mapper_name: &str) -> bool {
+    Path::new("/dev/mapper").join(mapper_name).exists()
+}
+
+/// Write the keyfile of a volume to its path.
+/// The returned file is what `discard_keyfile` wipes.
+pub fn write_keyfile(volume: &VolumeToOpen) -> Result<std::fs::File, String> {
+    let mut out = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&volume.keyfile_path)
+        .map_err(|why| format!("Failed to create {}: {why}", volume.keyfile_path.display()))?;
+    out.write_all(&volume.keyfile)
+        .map_err(|why| format!("Failed to write {}: {why}", volume.keyfile_path.display()))?;
+    Ok(out)
+}
+
+/// Wipe and remove a keyfile written by `write_keyfile`.
+/// The one the encrypt hook looks at is left alone unless its volume was `opened`,
+/// so that the hook can still try it.
+pub fn discard_keyfile(
+    volume: &VolumeToOpen,
+    out: &mut std::fs::File,
+    opened: bool,
+) -> Result<(), String> {
+    let is_root_keyfile = volume.keyfile_path == Path::new(ROOT_KEYFILE_PATH);
+    if is_root_keyfile && !opened {
+        return Ok(());
+    }
+    wipe_file(out, volume.keyfile.len())
+        .map_err(|why| format!("Failed to wipe {}: {why}", volume.keyfile_path.display()))?;
+    if !is_root_keyfile {
+        let _ = std::fs::remove_file(&volume.keyfile_path);
+    }
+    Ok(())
+}
+
+/// Open a volume with cryptsetup.
+///
+/// `on_wait` is called if the device isn't there yet and we need to wait for it.
+/// The keyfile is wiped after a successful unlock,
+/// and also after a failed one unless it is the one the encrypt hook will look at.
+pub fn open_volume(volume: &VolumeToOpen, on_wait: impl FnOnce()) -> Result<(), String> {
+    if is_open(&volume.target.mapper_name) {
+        return Ok(());
+    }
+
+    let mut out = write_keyfile(volume)?;
+    let result = run_cryptsetup(volume, on_wait);
+    discard_keyfile(volume, &mut out, result.is_ok())?;
+    result
+}
+
+fn run_cryptsetup(volume: &VolumeToOpen, on_wait: impl FnOnce()) -> Result<(), String> {
+    // The disk may not have shown up yet if it's slow or udev is still settling,
+    // so wait for it before trying to unlock it.
+    let device = match block_device::resolve(&volume.target.device) {
+        Some(device) => device,
+        None => {
+            on_wait();
+            block_device::wait_for(&volume.target.device, DEVICE_WAIT_TIMEOUT)?
+        }
+    };
+
+    let child = std::process::Command::new("cryptsetup")
+        .arg("--key-file")
+        .arg(&volume.keyfile_path)
+        .args(volume.target.cryptsetup_args())
+        .arg("open")
+        .arg(device)
+        .arg(&volume.target.mapper_name)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|why| format!("Failed to spawn cryptsetup: {why}"))?;
+
+    let output = child
+        .wait_with_output()
+        .map_err(|why| format!("Failed to get response from cryptsetup: {why}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "cryptsetup failed:\n{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+/// List the mapper names of the dm-crypt volumes that are open.
+fn open_crypt_volumes() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir("/sys/block") else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let dm = entry.path().join("dm");
+            // cryptsetup gives the devices it creates a UUID starting with CRYPT-.
+            let uuid = std::fs::read_to_string(dm.join("uuid")).ok()?;
+            if !uuid.starts_with("CRYPT-") {
+                return None;
+            }
+            Some(
+                std::fs::read_to_string(dm.join("name"))
+                    .ok()?
+                    .trim()
+                    .to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Close every open dm-crypt volume, so that their keys don't stay in memory,
+/// like before handing over to another kernel with kexec.
+pub fn close_all_volumes() -> Result<(), String> {
+    let volumes = open_crypt_volumes();
+    if volumes.is_empty() {
+        return Ok(());
+    }
+    // The volume groups on them were activated after unlocking, and their logical volumes hold them open.
+    // Without LVM this fails harmlessly, so it is only reported if closing fails too.
+    let deactivated = deactivate_volume_groups();
+    let mut errors = vec![];
+    for name in volumes {
+        let output = std::process::Command::new("cryptsetup")
+            .arg("close")
+            .arg(&name)
+            .stdin(Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => errors.push(format!(
+                "{name}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(why) => errors.push(format!("{name}: failed to spawn cryptsetup: {why}")),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        if let Err(why) = deactivated {
+            errors.insert(0, why);
+        }
+        Err(format!(
+            "Failed to close some volumes:\n{}",
+            errors.join("\n")
+        ))
+    }
+}
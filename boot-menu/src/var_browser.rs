@@ -0,0 +1,63 @@
+use cursive::{view::Scrollable, views, Cursive};
+
+use crate::{
+    efivarfs,
+    var_decode::{attributes_text, decode, hex_dump},
+};
+
+/// This function shows a list of all EFI variables with their attributes and size, and the content of the selected one.
+/// Nothing is ever written from here.
+pub fn variable_browser(siv: &mut Cursive) {
+    let mut variables = match efivarfs::list_all() {
+        Ok(v) => v,
+        Err(why) => {
+            siv.add_layer(
+                views::Dialog::around(views::TextView::new(why)).dismiss_button("Return to menu"),
+            );
+            return;
+        }
+    };
+    variables.sort();
+
+    let mut select = views::SelectView::new().autojump();
+    for (name, vendor) in variables {
+        let label = match efivarfs::read(&name, &vendor) {
+            Ok((attributes, value)) => format!(
+                "{name}-{vendor}  {}  {} B",
+                attributes_text(attributes),
+                value.len()
+            ),
+            Err(_) => format!("{name}-{vendor}  (unreadable)"),
+        };
+        select.add_item(label, (name, vendor));
+    }
+    select.set_on_submit(|siv, (name, vendor): &(String, String)| show_variable(siv, name, vendor));
+    siv.add_layer(
+        views::Dialog::around(select.scrollable())
+            .title("EFI variables")
+            .dismiss_button("Back"),
+    );
+}
+
+fn show_variable(siv: &mut Cursive, name: &str, vendor: &str) {
+    let text = match efivarfs::read(name, vendor) {
+        Ok((attributes, value)) => {
+            let mut text = format!(
+                "Vendor: {vendor}\nAttributes: {attributes:#x} ({})\nSize: {} bytes\n",
+                attributes_text(attributes),
+                value.len()
+            );
+            if let Some(decoded) = decode(name, vendor, &value) {
+                text.push_str(&format!("\n{decoded}\n"));
+            }
+            text.push_str(&format!("\n{}", hex_dump(&value)));
+            text
+        }
+        Err(why) => why,
+    };
+    siv.add_layer(
+        views::Dialog::around(views::TextView::new(text).scrollable())
+            .title(name)
+            .dismiss_button("Back"),
+    );
+}
@@ -0,0 +1,266 @@
+//! Human-readable renderings of EFI variables, for the variable browser.
+
+use std::fmt::Write;
+
+use crate::{
+    boot_entries::{parse_boot_order, BootOption},
+    device_path::format_guid,
+    efivarfs::{
+        EFI_GLOBAL_VARIABLE, EFI_VARIABLE_APPEND_WRITE, EFI_VARIABLE_AUTHENTICATED_WRITE_ACCESS,
+        EFI_VARIABLE_BOOTSERVICE_ACCESS, EFI_VARIABLE_HARDWARE_ERROR_RECORD,
+        EFI_VARIABLE_NON_VOLATILE, EFI_VARIABLE_RUNTIME_ACCESS,
+        EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS,
+    },
+};
+
+/// The vendor GUID of `db` and `dbx`.
+pub const EFI_IMAGE_SECURITY_DATABASE_GUID: &str = "d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+
+const EFI_CERT_X509_GUID: &str = "a5c059a1-94e4-4aa7-87b5-ab155c2bf072";
+const EFI_CERT_SHA256_GUID: &str = "c1c41626-504c-4092-aca9-41f936934328";
+const EFI_CERT_RSA2048_GUID: &str = "3c5766e8-269c-4e34-aa14-ed776e85b3b6";
+
+/// The bits of OsIndications and OsIndicationsSupported.
+const OS_INDICATIONS: [(u64, &str); 7] = [
+    (0x01, "BOOT_TO_FW_UI"),
+    (0x02, "TIMESTAMP_REVOCATION"),
+    (0x04, "FILE_CAPSULE_DELIVERY_SUPPORTED"),
+    (0x08, "FMP_CAPSULE_SUPPORTED"),
+    (0x10, "CAPSULE_RESULT_VAR_SUPPORTED"),
+    (0x20, "START_OS_RECOVERY"),
+    (0x40, "START_PLATFORM_RECOVERY"),
+];
+
+/// The short names that efivar and the UEFI spec use for the attributes.
+pub fn attributes_text(attributes: u32) -> String {
+    let names = [
+        (EFI_VARIABLE_NON_VOLATILE, "NV"),
+        (EFI_VARIABLE_BOOTSERVICE_ACCESS, "BS"),
+        (EFI_VARIABLE_RUNTIME_ACCESS, "RT"),
+        (EFI_VARIABLE_HARDWARE_ERROR_RECORD, "HR"),
+        (EFI_VARIABLE_AUTHENTICATED_WRITE_ACCESS, "AW"),
+        (EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS, "AT"),
+        (EFI_VARIABLE_APPEND_WRITE, "AP"),
+    ];
+    names
+        .iter()
+        .filter(|(bit, _)| attributes & bit != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A classic hex dump, with the offset, 16 bytes per line, and the printable characters.
+pub fn hex_dump(value: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in value.chunks(16).enumerate() {
+        let _ = write!(out, "{:04x}  ", line * 16);
+        for index in 0..16 {
+            match chunk.get(index) {
+                Some(byte) => {
+                    let _ = write!(out, "{byte:02x} ");
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push(' ');
+        out.extend(chunk.iter().map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push('\n');
+    }
+    out
+}
+
+fn u16_value(value: &[u8]) -> Option<u16> {
+    Some(u16::from_le_bytes(value.get(..2)?.try_into().ok()?))
+}
+
+fn u64_value(value: &[u8]) -> Option<u64> {
+    let mut buf = [0u8; 8];
+    let len = value.len().min(8);
+    buf[..len].copy_from_slice(&value[..len]);
+    (len > 0).then_some(u64::from_le_bytes(buf))
+}
+
+fn enabled(value: &[u8]) -> Option<String> {
+    match value.first()? {
+        0 => Some("disabled".to_string()),
+        1 => Some("enabled".to_string()),
+        other => Some(format!("unknown value {other}")),
+    }
+}
+
+/// Find the common name of the subject of a DER X.509 certificate.
+/// This doesn't parse the ASN.1; it just takes the last commonName attribute,
+/// because the subject comes after the issuer in the certificate.
+fn certificate_common_name(der: &[u8]) -> Option<String> {
+    const COMMON_NAME_OID: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+    let mut found = None;
+    for start in 0..der.len().saturating_sub(COMMON_NAME_OID.len()) {
+        if !der[start..].starts_with(COMMON_NAME_OID) {
+            continue;
+        }
+        let rest = &der[start + COMMON_NAME_OID.len()..];
+        // The value is a UTF8String, PrintableString or IA5String with a short length.
+        let (tag, len) = (*rest.first()?, *rest.get(1)? as usize);
+        if matches!(tag, 0x0c | 0x13 | 0x16) && len < 0x80 {
+            if let Some(name) = rest.get(2..2 + len) {
+                found = Some(String::from_utf8_lossy(name).to_string());
+            }
+        }
+    }
+    found
+}
+
+/// Summarize an EFI_SIGNATURE_LIST array, like in PK, KEK, db and dbx.
+/// See https://uefi.org/specs/UEFI/2.10/32_Secure_Boot_and_Driver_Signing.html#signature-database
+pub fn signature_lists_summary(mut value: &[u8]) -> Result<String, String> {
+    let mut out = String::new();
+    while !value.is_empty() {
+        if value.len() < 28 {
+            return Err("EFI_SIGNATURE_LIST header is truncated".to_string());
+        }
+        let signature_type = format_guid(value[0..16].try_into().unwrap());
+        let list_size = u32::from_le_bytes(value[16..20].try_into().unwrap()) as usize;
+        let header_size = u32::from_le_bytes(value[20..24].try_into().unwrap()) as usize;
+        let signature_size = u32::from_le_bytes(value[24..28].try_into().unwrap()) as usize;
+        if list_size > value.len() || 28 + header_size > list_size || signature_size < 16 {
+            return Err("EFI_SIGNATURE_LIST has invalid sizes".to_string());
+        }
+        let signatures = &value[28 + header_size..list_size];
+        value = &value[list_size..];
+
+        let type_name = match signature_type.as_str() {
+            EFI_CERT_X509_GUID => "X.509 certificate",
+            EFI_CERT_SHA256_GUID => "SHA-256 hash",
+            EFI_CERT_RSA2048_GUID => "RSA-2048 key",
+            other => other,
+        };
+        let count = signatures.len() / signature_size;
+        let _ = writeln!(out, "{count} x {type_name}");
+        if signature_type != EFI_CERT_X509_GUID {
+            continue;
+        }
+        for signature in signatures.chunks_exact(signature_size) {
+            let owner = format_guid(signature[0..16].try_into().unwrap());
+            let name =
+                certificate_common_name(&signature[16..]).unwrap_or("(no common name)".to_string());
+            let _ = writeln!(out, "  {name} (owner {owner})");
+        }
+    }
+    Ok(out)
+}
+
+/// Render the value of a variable that we know the meaning of.
+/// Returns None for the variables we don't know.
+pub fn decode(name: &str, vendor: &str, value: &[u8]) -> Option<String> {
+    if vendor == EFI_IMAGE_SECURITY_DATABASE_GUID && (name == "db" || name == "dbx") {
+        return Some(signature_lists_summary(value).unwrap_or_else(|why| why));
+    }
+    if vendor != EFI_GLOBAL_VARIABLE {
+        return None;
+    }
+    match name {
+        "BootOrder" => Some(match parse_boot_order(value) {
+            Ok(order) => order
+                .iter()
+                .map(|id| format!("Boot{id:04X}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            Err(why) => why,
+        }),
+        "BootCurrent" | "BootNext" => u16_value(value).map(|id| format!("Boot{id:04X}")),
+        "Timeout" => u16_value(value).map(|seconds| format!("{seconds} seconds")),
+        "SecureBoot" | "SetupMode" | "AuditMode" | "DeployedMode" => enabled(value),
+        "OsIndications" | "OsIndicationsSupported" => u64_value(value).map(|bits| {
+            let names: Vec<&str> = OS_INDICATIONS
+                .iter()
+                .filter(|(bit, _)| bits & bit != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            format!("{bits:#x}: {}", names.join(", "))
+        }),
+        "PK" | "KEK" => Some(signature_lists_summary(value).unwrap_or_else(|why| why)),
+        _ => {
+            let id = name.strip_prefix("Boot")?;
+            let id = u16::from_str_radix(id, 16).ok().filter(|_| id.len() == 4)?;
+            Some(match BootOption::parse(id, value) {
+                Ok(option) => format!("{}\n{}", option.description, option.paths_text()),
+                Err(why) => why,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{attributes_text, decode, hex_dump, signature_lists_summary};
+    use crate::{device_path::parse_guid, efivarfs::EFI_GLOBAL_VARIABLE};
+
+    #[test]
+    fn test_simple_variables() {
+        assert_eq!(
+            decode("BootOrder", EFI_GLOBAL_VARIABLE, &[1, 0, 3, 0]).unwrap(),
+            "Boot0001, Boot0003"
+        );
+        assert_eq!(
+            decode("Timeout", EFI_GLOBAL_VARIABLE, &[5, 0]).unwrap(),
+            "5 seconds"
+        );
+        assert_eq!(
+            decode("SecureBoot", EFI_GLOBAL_VARIABLE, &[1]).unwrap(),
+            "enabled"
+        );
+        assert_eq!(
+            decode(
+                "OsIndicationsSupported",
+                EFI_GLOBAL_VARIABLE,
+                &[0x5, 0, 0, 0, 0, 0, 0, 0]
+            )
+            .unwrap(),
+            "0x5: BOOT_TO_FW_UI, FILE_CAPSULE_DELIVERY_SUPPORTED"
+        );
+        assert_eq!(decode("Lang", "some-other-vendor", b"en"), None);
+        assert_eq!(attributes_text(0x27), "NV,BS,RT,AT");
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(
+            hex_dump(b"PK\x00\x01"),
+            format!("0000  50 4b 00 01 {} PK..\n", "   ".repeat(12))
+        );
+    }
+
+    #[test]
+    fn test_signature_list() {
+        // A certificate with only an issuer and a subject, which is enough for finding the name.
+        let mut cert = vec![0x30, 0x00];
+        for name in ["Issuer CA", "Platform Key"] {
+            cert.extend_from_slice(&[0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, name.len() as u8]);
+            cert.extend_from_slice(name.as_bytes());
+        }
+        let owner = "77fa9abd-0359-4d32-bd60-28f4e78f784b";
+
+        let mut list = parse_guid("a5c059a1-94e4-4aa7-87b5-ab155c2bf072")
+            .unwrap()
+            .to_vec();
+        let signature_size = 16 + cert.len();
+        list.extend_from_slice(&(28 + signature_size as u32).to_le_bytes());
+        list.extend_from_slice(&0u32.to_le_bytes());
+        list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+        list.extend_from_slice(&parse_guid(owner).unwrap());
+        list.extend_from_slice(&cert);
+
+        assert_eq!(
+            signature_lists_summary(&list).unwrap(),
+            format!("1 x X.509 certificate\n  Platform Key (owner {owner})\n")
+        );
+        assert!(signature_lists_summary(&list[..20]).is_err());
+    }
+}
@@ -1,39 +1,42 @@
-use std::process::Stdio;
-
 use secrecy::{ExposeSecret, Secret};
 
-use crate::params::EncryptionParams;
+use crate::{keyfile::KeyEncryptionKey, params::EncryptionParams, unlocker::Unlocker};
+
+/// A volume whose keyfile has been decrypted, ready to be passed to cryptsetup.
+#[derive(Clone)]
+pub struct DecryptedVolume {
+    pub name: String,
+    pub device: Option<String>,
+    pub options: Vec<String>,
+    pub keyfile: Vec<u8>,
+}
 
 impl EncryptionParams {
-    pub fn try_keyfile_from_password(&self, pw: String) -> Result<Vec<u8>, ()> {
-        let kek = self.password_auth.decrypt(Secret::new(pw))?;
-        let keyfile = self.keyfile.decrypt(kek)?;
-        Ok(keyfile.expose_secret().clone())
+    /// Decrypt the keyfiles of all volumes with the KEK.
+    fn decrypt_volumes(&self, kek: KeyEncryptionKey) -> Result<Vec<DecryptedVolume>, ()> {
+        self.volumes
+            .iter()
+            .map(|volume| {
+                let keyfile = volume.keyfile.decrypt(&kek)?;
+                Ok(DecryptedVolume {
+                    name: volume.name.clone(),
+                    device: volume.device.clone(),
+                    options: volume.options.clone(),
+                    keyfile: keyfile.expose_secret().clone(),
+                })
+            })
+            .collect()
     }
 
-    pub fn try_keyfile_from_pin(&self, pin: String) -> Result<Vec<u8>, ()> {
-        let chalresp = |data: [u8; 32]| -> Option<[u8; 20]> {
-            let data = hex_string::HexString::from_bytes(&data.to_vec());
-            let child = std::process::Command::new("ykchalresp")
-                .arg("-x")
-                .arg(&data.as_string())
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("Failed to launch ykchalresp");
-            let output = child.wait_with_output().ok()?;
-
-            if !output.status.success() {
-                return None;
-            }
-            let outdata = String::from_utf8_lossy(&output.stdout);
-            let outdata = hex_string::HexString::from_string(&outdata.strip_suffix("\n")?).ok()?;
-            let outdata: [u8; 20] = outdata.as_bytes().try_into().ok()?;
-
-            Some(outdata)
-        };
-
-        let kek = self.yubikey_auth.decrypt(Secret::new(pin), chalresp)?;
-        let keyfile = self.keyfile.decrypt(kek)?;
-        Ok(keyfile.expose_secret().clone())
+    /// Recover the KEK with one of the unlockers, and decrypt the keyfiles of all volumes with it.
+    pub fn try_keyfiles(
+        &self,
+        unlocker: &dyn Unlocker,
+        input: String,
+        notify: &dyn Fn(&'static str),
+    ) -> Result<Vec<DecryptedVolume>, String> {
+        let kek = unlocker.recover_kek(self, Secret::new(input), notify)?;
+        self.decrypt_volumes(kek)
+            .map_err(|_| "The key didn't decrypt the keyfiles of the volumes".to_string())
     }
 }
@@ -6,30 +6,32 @@ use crate::params::{EncryptedKek, EncryptedKeyfile};
 
 impl EncryptedKeyfile {
     pub fn new(plain_keyfile_content: SecretVec<u8>) -> (Self, KeyEncryptionKey) {
+        // Generate the encryption key for self.
+        let kek = KeyEncryptionKey::generate();
+        (Self::new_with_kek(plain_keyfile_content, &kek), kek)
+    }
+
+    /// Encrypt a keyfile with an existing KEK,
+    /// so that it can be unlocked together with the other keyfiles using it.
+    pub fn new_with_kek(plain_keyfile_content: SecretVec<u8>, kek: &KeyEncryptionKey) -> Self {
         use secrecy::ExposeSecret;
         let mut rng = rand::rngs::OsRng::default();
-        // Generate the encryption key for self.
-        let auth_key = XChaCha20Poly1305::generate_key(&mut rng);
 
-        let cipher = XChaCha20Poly1305::new(&auth_key);
+        let cipher = XChaCha20Poly1305::new_from_slice(kek.key.expose_secret())
+            .expect("XChaCha20 key should be 32 bytes");
         let nonce = XChaCha20Poly1305::generate_nonce(&mut rng);
 
         let plaintext: &[u8] = plain_keyfile_content.expose_secret();
         let ciphertext = cipher
             .encrypt(&nonce, plaintext)
             .expect("Failed to encrypt keyfile");
-        (
-            Self {
-                encrypted_keyfile_content: ciphertext,
-                nonce: nonce.try_into().unwrap(),
-            },
-            KeyEncryptionKey {
-                key: Secret::new(auth_key.try_into().unwrap()),
-            },
-        )
+        Self {
+            encrypted_keyfile_content: ciphertext,
+            nonce: nonce.try_into().unwrap(),
+        }
     }
 
-    pub fn decrypt(&self, kek: KeyEncryptionKey) -> Result<SecretVec<u8>, ()> {
+    pub fn decrypt(&self, kek: &KeyEncryptionKey) -> Result<SecretVec<u8>, ()> {
         use secrecy::ExposeSecret;
         let key = kek.key.expose_secret();
         let cipher = XChaCha20Poly1305::new_from_slice(key).map_err(|_| ())?;
@@ -47,6 +49,15 @@ pub struct KeyEncryptionKey {
 }
 
 impl KeyEncryptionKey {
+    /// Generate a new random KEK.
+    pub fn generate() -> Self {
+        let mut rng = rand::rngs::OsRng::default();
+        let key = XChaCha20Poly1305::generate_key(&mut rng);
+        Self {
+            key: Secret::new(key.into()),
+        }
+    }
+
     /// Encrypt the KEK for on-disk storage
     pub fn encrypt(&self, key: Secret<[u8; 32]>) -> EncryptedKek {
         use secrecy::ExposeSecret;
@@ -103,11 +114,23 @@ mod test {
 
         // ...
 
-        let decrypted_keyfile = enc_keyfile.decrypt(kek).unwrap();
+        let decrypted_keyfile = enc_keyfile.decrypt(&kek).unwrap();
         let decrypted_keyfile = decrypted_keyfile.expose_secret();
         assert_eq!(&src_keyfile, decrypted_keyfile);
     }
 
+    #[test]
+    fn test_keyfiles_share_kek() {
+        let (first, kek) = EncryptedKeyfile::new(vec![1, 2, 3].into());
+        let second = EncryptedKeyfile::new_with_kek(vec![4, 5, 6].into(), &kek);
+
+        assert_eq!(first.decrypt(&kek).unwrap().expose_secret(), &vec![1, 2, 3]);
+        assert_eq!(
+            second.decrypt(&kek).unwrap().expose_secret(),
+            &vec![4, 5, 6]
+        );
+    }
+
     #[test]
     fn test_kek_round_trip() {
         let src_kek_data: Vec<u8> = (0..32).collect();
@@ -1,5 +1,8 @@
 pub mod disk_encryption;
 pub mod keyfile;
 pub mod params;
+pub mod schema;
 pub mod unlock_password;
 pub mod unlock_yubikey;
+pub mod unlocker;
+pub mod yubikey;
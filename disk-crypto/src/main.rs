@@ -1,17 +1,65 @@
-use std::{io::Read, process::Stdio};
+use std::io::Read;
 
 use dialoguer::theme::ColorfulTheme;
-use secrecy::Secret;
+use secrecy::{Secret, SecretVec};
 
 use crate::params::{
-    EncryptedKeyfile, EncryptionParams, PasswordAuthParameters, YubikeyAuthParams,
+    EncryptedKeyfile, EncryptedVolume, EncryptionParams, PasswordAuthParameters, YubikeyAuthParams,
 };
 
 pub mod keyfile;
 mod params;
+mod schema;
 mod unlock_password;
 mod unlock_yubikey;
+mod yubikey;
 fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        None => generate(),
+        Some("migrate") => migrate(args.get(2).map_or("encrypt-config.json", String::as_str)),
+        Some(other) => {
+            println!("Unknown subcommand: {other}");
+            println!("Usage:");
+            println!("  disk-crypto                  generate a new encrypt-config.json");
+            println!("  disk-crypto migrate [FILE]   upgrade FILE (default encrypt-config.json) to the current format");
+            Ok(())
+        }
+    }
+}
+
+/// Upgrade an existing config file to the current format, in place.
+fn migrate(path: &str) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    let value: serde_json::Value = serde_json::from_str(&text)?;
+    let version = schema::schema_version(&value).map_err(anyhow::Error::msg)?;
+    if version == schema::CURRENT_VERSION {
+        println!("{path} is already at version {version}; nothing to do.");
+        return Ok(());
+    }
+
+    let config = EncryptionParams::from_json_value(value).map_err(anyhow::Error::msg)?;
+
+    // Write to a temporary file first, so that a failure doesn't leave a half-written config.
+    let tmp_path = format!("{path}.tmp");
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    serde_json::to_writer(&file, &config)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    println!(
+        "Migrated {path} from version {version} to version {}.",
+        schema::CURRENT_VERSION
+    );
+    Ok(())
+}
+
+/// Generate a new config file, prompting for all the keys.
+fn generate() -> anyhow::Result<()> {
     use dialoguer::*;
     println!("This tool will generate a new config file for the boot menu.");
     let theme = ColorfulTheme::default();
@@ -32,30 +80,60 @@ fn main() -> anyhow::Result<()> {
     }
 
     println!("Reading keyfile...");
-    let keyfile_bytes = match std::fs::OpenOptions::new()
-        .read(true)
-        .create(false)
-        .open("keyfile.secret")
-    {
-        Err(why) => {
-            println!("Failed to open `keyfile.secret`: {why}");
-            println!("The file `keyfile.secret` must contain a keyfile that has been enrolled into cryptsetup.");
-            println!("Check README.md for details.");
-            return Ok(());
-        }
-        Ok(mut file) => {
-            let mut out: Vec<u8> = vec![];
-            file.read_to_end(&mut out)?;
-            println!("Read {} bytes!", out.len());
-            out
-        }
+    let Some(keyfile_bytes) = read_keyfile("keyfile.secret")? else {
+        return Ok(());
     };
-    let keyfile_bytes = Secret::new(keyfile_bytes);
 
     println!("Encrypting keyfile...");
     let (encrypted_keyfile, kek) = EncryptedKeyfile::new(keyfile_bytes);
+    let mut volumes = vec![EncryptedVolume {
+        name: "root".to_string(),
+        device: None,
+        options: vec![],
+        keyfile: encrypted_keyfile,
+    }];
+
+    println!(
+        "Keyfile encrypted! This is the volume named by `cryptdevice=` on the kernel cmdline."
+    );
+    println!(
+        "Other volumes, like a data disk or encrypted swap, can be unlocked with the same login."
+    );
+    while Confirm::with_theme(&theme)
+        .with_prompt("Do you want to add another volume?")
+        .default(false)
+        .interact()?
+    {
+        let name: String = Input::with_theme(&theme)
+            .with_prompt("Name for /dev/mapper")
+            .interact_text()?;
+        let device: String = Input::with_theme(&theme)
+            .with_prompt("Device (like UUID=..., PARTLABEL=... or /dev/...)")
+            .interact_text()?;
+        let options: String = Input::with_theme(&theme)
+            .with_prompt("Options, separated by commas (like allow-discards)")
+            .allow_empty(true)
+            .interact_text()?;
+        let keyfile_path: String = Input::with_theme(&theme)
+            .with_prompt("Path to this volume's keyfile")
+            .interact_text()?;
+        let Some(keyfile_bytes) = read_keyfile(&keyfile_path)? else {
+            continue;
+        };
+
+        volumes.push(EncryptedVolume {
+            name,
+            device: Some(device),
+            options: options
+                .split(',')
+                .filter(|o| !o.is_empty())
+                .map(str::to_string)
+                .collect(),
+            keyfile: EncryptedKeyfile::new_with_kek(keyfile_bytes, &kek),
+        });
+    }
 
-    println!("Keyfile encrypted! Now building decryption methods:");
+    println!("Keyfiles encrypted! Now building decryption methods:");
 
     println!("Password:");
     let password = Password::with_theme(&theme)
@@ -70,8 +148,7 @@ fn main() -> anyhow::Result<()> {
 
     println!("Yubikey challenge-response:");
     println!("For this step, please make sure that this computer has exactly one Yubikey plugged into it,");
-    println!("that its slot 1 is configured for challenge-response auth,");
-    println!("and that the `ykchalresp` program is available.");
+    println!("and that one of its slots is configured for HMAC-SHA1 challenge-response auth.");
     println!("Alternatively, you can skip this step and not register a Yubikey:");
     println!("if a Yubikey is inserted at boot, you will still be asked for a PIN,");
     println!("but it will not unlock the disk.");
@@ -82,53 +159,49 @@ fn main() -> anyhow::Result<()> {
         .interact()?
     {
         println!("Yubikey will not be used");
-        yk_params = YubikeyAuthParams { slots: vec![] };
+        yk_params = YubikeyAuthParams {
+            slots: vec![],
+            otp_slot: yubikey::Slot::One,
+        };
     } else {
         let pin = Password::with_theme(&theme)
             .with_prompt("Please enter the PIN (short password) to use at boot with Yubikey")
             .with_confirmation("Repeat PIN", "Error: the PINs don't match.")
             .interact()?;
+        let otp_slot = if Select::with_theme(&theme)
+            .with_prompt("Which slot of the Yubikey is configured for challenge-response?")
+            .items(&["Slot 1", "Slot 2"])
+            .default(0)
+            .interact()?
+            == 0
+        {
+            yubikey::Slot::One
+        } else {
+            yubikey::Slot::Two
+        };
         let slots = 16;
         println!("We will enroll {slots} slots. You may need to hold down the Yubikey button.");
         let chalresp = |data: [u8; 32]| -> Option<[u8; 20]> {
-            let data = hex_string::HexString::from_bytes(&data.to_vec());
-            println!("Trying to perform challenge-response with ykchalresp and value={data:?}...");
-            let child = std::process::Command::new("ykchalresp")
-                .arg("-x")
-                .arg(&data.as_string())
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("Failed to launch ykchalresp");
-            let output = child
-                .wait_with_output()
-                .expect("Failed to wait for child to return");
-
-            if !output.status.success() {
-                println!("ykchalresp returned status: {:?}", output.status);
-                return None;
+            println!("Sending a challenge to the Yubikey...");
+            match yubikey::hmac_challenge_response(otp_slot, &data, &mut || {
+                println!("Touch the Yubikey to continue...")
+            }) {
+                Ok(response) => Some(response),
+                Err(why) => {
+                    println!("{why}");
+                    None
+                }
             }
-            let outdata = String::from_utf8_lossy(&output.stdout);
-            let outdata = hex_string::HexString::from_string(
-                &outdata
-                    .strip_suffix("\n")
-                    .expect("ykchalresp returned empty string?"),
-            )
-            .expect("ykchalresp returned non-hex string?");
-            let outdata: [u8; 20] = outdata
-                .as_bytes()
-                .try_into()
-                .expect("ykchalresp returned not 20 bytes?");
-
-            Some(outdata)
         };
-        yk_params = YubikeyAuthParams::new_with_slots(slots, Secret::new(pin), chalresp, &kek);
+        yk_params =
+            YubikeyAuthParams::new_with_slots(slots, otp_slot, Secret::new(pin), chalresp, &kek);
         println!("Done!");
     }
 
     println!("Writing config file...");
 
     let config = EncryptionParams {
-        keyfile: encrypted_keyfile,
+        volumes,
         password_auth: pw_params,
         yubikey_auth: yk_params,
     };
@@ -140,3 +213,28 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Read a keyfile that has been enrolled into cryptsetup.
+/// If it can't be opened, this explains why and returns `None`.
+fn read_keyfile(path: &str) -> anyhow::Result<Option<SecretVec<u8>>> {
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .create(false)
+        .open(path)
+    {
+        Err(why) => {
+            println!("Failed to open `{path}`: {why}");
+            println!(
+                "The file `{path}` must contain a keyfile that has been enrolled into cryptsetup."
+            );
+            println!("Check README.md for details.");
+            Ok(None)
+        }
+        Ok(mut file) => {
+            let mut out: Vec<u8> = vec![];
+            file.read_to_end(&mut out)?;
+            println!("Read {} bytes!", out.len());
+            Ok(Some(Secret::new(out)))
+        }
+    }
+}
@@ -1,13 +1,36 @@
 use serde::{Deserialize, Serialize};
 use serde_with::{base64::Base64, serde_as};
-#[derive(Serialize, Deserialize, Clone)]
+
+use crate::yubikey::Slot;
+#[derive(Clone)]
 /// This structure stores the parameters for decrypting the disk.
+/// It is stored on disk in a versioned format; see the `schema` module.
 pub struct EncryptionParams {
-    pub(crate) keyfile: EncryptedKeyfile,
+    pub(crate) volumes: Vec<EncryptedVolume>,
     pub(crate) password_auth: PasswordAuthParameters,
     pub(crate) yubikey_auth: YubikeyAuthParams,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+/// A LUKS volume, together with its keyfile.
+/// The keyfiles of all volumes are encrypted with the same KEK,
+/// so one login unlocks all of them.
+pub struct EncryptedVolume {
+    /// The name to show for this volume, and the dm-crypt mapper name to open it as.
+    pub name: String,
+
+    /// Where the volume is, in the same syntax as the kernel cmdline (`UUID=...`, `PARTLABEL=...` or a path).
+    /// If this is not set, this is the volume named on the kernel cmdline,
+    /// and the mapper name from there is used.
+    pub device: Option<String>,
+
+    /// Options for opening the volume, like `allow-discards`.
+    #[serde(default)]
+    pub options: Vec<String>,
+
+    pub(crate) keyfile: EncryptedKeyfile,
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Clone)]
 /// This is the encrypted disk keyfile.
@@ -49,6 +72,9 @@ pub struct PasswordAuthParameters {
 /// The data for decrypting the keyfile with the Yubikey
 pub struct YubikeyAuthParams {
     pub slots: Vec<YubikeyAuthSlot>,
+
+    /// The slot of the Yubikey that is configured for challenge-response.
+    pub otp_slot: Slot,
 }
 
 #[serde_as]
@@ -0,0 +1,279 @@
+//! The on-disk format of `EncryptionParams`.
+//!
+//! Every format that has ever been written is listed here,
+//! so that configs generated by older versions can still be read.
+//! Files are always written in the current version.
+//!
+//! - Version 1 has a single keyfile, for the volume named on the kernel cmdline.
+//!   Files in this version were written without a `version` field.
+//! - Version 2 has a list of volumes, whose keyfiles are encrypted with the same KEK.
+//! - Version 3 says which slot of the Yubikey to send the challenge to. Older versions always used slot 1.
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    params::{
+        EncryptedKeyfile, EncryptedVolume, EncryptionParams, PasswordAuthParameters,
+        YubikeyAuthParams, YubikeyAuthSlot,
+    },
+    yubikey::Slot,
+};
+
+/// The version that files are written in.
+pub const CURRENT_VERSION: u64 = 3;
+
+/// The Yubikey parameters of versions 1 and 2, which didn't choose a slot.
+#[derive(Serialize, Deserialize)]
+struct YubikeyAuthParamsV2 {
+    slots: Vec<YubikeyAuthSlot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptionParamsV1 {
+    keyfile: EncryptedKeyfile,
+    password_auth: PasswordAuthParameters,
+    yubikey_auth: YubikeyAuthParamsV2,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptionParamsV2 {
+    volumes: Vec<EncryptedVolume>,
+    password_auth: PasswordAuthParameters,
+    yubikey_auth: YubikeyAuthParamsV2,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptionParamsV3 {
+    volumes: Vec<EncryptedVolume>,
+    password_auth: PasswordAuthParameters,
+    yubikey_auth: YubikeyAuthParams,
+}
+
+impl From<EncryptionParamsV1> for EncryptionParamsV2 {
+    fn from(v1: EncryptionParamsV1) -> Self {
+        // The single keyfile was always for the volume on the kernel cmdline.
+        Self {
+            volumes: vec![EncryptedVolume {
+                name: "root".to_string(),
+                device: None,
+                options: vec![],
+                keyfile: v1.keyfile,
+            }],
+            password_auth: v1.password_auth,
+            yubikey_auth: v1.yubikey_auth,
+        }
+    }
+}
+
+impl From<EncryptionParamsV2> for EncryptionParamsV3 {
+    fn from(v2: EncryptionParamsV2) -> Self {
+        // `ykchalresp` was run without a slot, which means slot 1.
+        Self {
+            volumes: v2.volumes,
+            password_auth: v2.password_auth,
+            yubikey_auth: YubikeyAuthParams {
+                slots: v2.yubikey_auth.slots,
+                otp_slot: Slot::One,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Versioned<T> {
+    version: u64,
+    #[serde(flatten)]
+    params: T,
+}
+
+/// Find out which version a parsed config file is in.
+/// Files without a `version` field are from version 1, which is recognized by its layout.
+pub fn schema_version(value: &serde_json::Value) -> Result<u64, String> {
+    match value.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| format!("Config version should be a number, not {version}")),
+        None if value.get("keyfile").is_some() => Ok(1),
+        None => Err("Config has no version, and its layout is not recognized".to_string()),
+    }
+}
+
+impl EncryptionParams {
+    /// Read the config from any version, migrating it to the current one.
+    pub fn from_json_value(value: serde_json::Value) -> Result<Self, String> {
+        let version = schema_version(&value)?;
+        let current: EncryptionParamsV3 = match version {
+            1 => EncryptionParamsV2::from(
+                serde_json::from_value::<EncryptionParamsV1>(value)
+                    .map_err(|why| format!("Invalid version 1 config: {why}"))?,
+            )
+            .into(),
+            2 => serde_json::from_value::<EncryptionParamsV2>(value)
+                .map_err(|why| format!("Invalid version 2 config: {why}"))?
+                .into(),
+            3 => serde_json::from_value(value)
+                .map_err(|why| format!("Invalid version 3 config: {why}"))?,
+            other => {
+                return Err(format!(
+                    "Config version {other} is not supported; this build supports up to version {CURRENT_VERSION}"
+                ))
+            }
+        };
+        Ok(Self {
+            volumes: current.volumes,
+            password_auth: current.password_auth,
+            yubikey_auth: current.yubikey_auth,
+        })
+    }
+}
+
+impl Serialize for EncryptionParams {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Versioned {
+            version: CURRENT_VERSION,
+            params: EncryptionParamsV3 {
+                volumes: self.volumes.clone(),
+                password_auth: self.password_auth.clone(),
+                yubikey_auth: self.yubikey_auth.clone(),
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for EncryptionParams {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        Self::from_json_value(value).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use secrecy::{ExposeSecret, Secret};
+
+    use crate::params::{
+        EncryptedKeyfile, EncryptedVolume, EncryptionParams, PasswordAuthParameters,
+    };
+    use crate::yubikey::Slot;
+
+    use super::{
+        schema_version, EncryptionParamsV1, EncryptionParamsV2, EncryptionParamsV3,
+        YubikeyAuthParamsV2, CURRENT_VERSION,
+    };
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    fn make_v1() -> EncryptionParamsV1 {
+        let (keyfile, kek) = EncryptedKeyfile::new(vec![1, 2, 3, 4].into());
+        EncryptionParamsV1 {
+            keyfile,
+            password_auth: PasswordAuthParameters::new(Secret::new(PASSWORD.to_string()), &kek),
+            yubikey_auth: YubikeyAuthParamsV2 { slots: vec![] },
+        }
+    }
+
+    fn make_v2() -> EncryptionParamsV2 {
+        let (root, kek) = EncryptedKeyfile::new(vec![1, 2, 3, 4].into());
+        let data = EncryptedKeyfile::new_with_kek(vec![5, 6, 7, 8].into(), &kek);
+        EncryptionParamsV2 {
+            volumes: vec![
+                EncryptedVolume {
+                    name: "root".to_string(),
+                    device: None,
+                    options: vec![],
+                    keyfile: root,
+                },
+                EncryptedVolume {
+                    name: "data".to_string(),
+                    device: Some("PARTLABEL=data".to_string()),
+                    options: vec!["allow-discards".to_string()],
+                    keyfile: data,
+                },
+            ],
+            password_auth: PasswordAuthParameters::new(Secret::new(PASSWORD.to_string()), &kek),
+            yubikey_auth: YubikeyAuthParamsV2 { slots: vec![] },
+        }
+    }
+
+    /// Load the file, save it again, and check that both copies still unlock the same volumes.
+    /// Returns the saved copy.
+    fn check_round_trip(json: &str, expected: &[(&str, Vec<u8>)]) -> EncryptionParams {
+        let loaded: EncryptionParams = serde_json::from_str(json).unwrap();
+        let saved = serde_json::to_string(&loaded).unwrap();
+        let saved_value: serde_json::Value = serde_json::from_str(&saved).unwrap();
+        assert_eq!(saved_value["version"], CURRENT_VERSION);
+
+        let reloaded: EncryptionParams = serde_json::from_str(&saved).unwrap();
+        for params in [&loaded, &reloaded] {
+            let kek = params
+                .password_auth
+                .decrypt(Secret::new(PASSWORD.to_string()))
+                .unwrap();
+            let volumes: Vec<(&str, Vec<u8>)> = params
+                .volumes
+                .iter()
+                .map(|v| {
+                    let keyfile = v.keyfile.decrypt(&kek).unwrap();
+                    (v.name.as_str(), keyfile.expose_secret().clone())
+                })
+                .collect();
+            assert_eq!(volumes, expected);
+        }
+        reloaded
+    }
+
+    #[test]
+    fn test_v1_unversioned() {
+        let json = serde_json::to_string(&make_v1()).unwrap();
+        assert_eq!(schema_version(&serde_json::from_str(&json).unwrap()), Ok(1));
+        let params = check_round_trip(&json, &[("root", vec![1, 2, 3, 4])]);
+        assert_eq!(params.yubikey_auth.otp_slot, Slot::One);
+    }
+
+    #[test]
+    fn test_v2_versioned() {
+        let mut value = serde_json::to_value(make_v2()).unwrap();
+        value["version"] = 2.into();
+        let params = check_round_trip(
+            &value.to_string(),
+            &[("root", vec![1, 2, 3, 4]), ("data", vec![5, 6, 7, 8])],
+        );
+        assert_eq!(params.yubikey_auth.otp_slot, Slot::One);
+    }
+
+    #[test]
+    fn test_v2_ignores_otp_slot() {
+        let mut value = serde_json::to_value(make_v2()).unwrap();
+        value["version"] = 2.into();
+        value["yubikey_auth"]["otp_slot"] = 2.into();
+        let params: EncryptionParams = serde_json::from_value(value).unwrap();
+        assert_eq!(params.yubikey_auth.otp_slot, Slot::One);
+    }
+
+    #[test]
+    fn test_v3_keeps_otp_slot() {
+        let mut v3 = EncryptionParamsV3::from(make_v2());
+        v3.yubikey_auth.otp_slot = Slot::Two;
+        let mut value = serde_json::to_value(v3).unwrap();
+        value["version"] = 3.into();
+        let params = check_round_trip(
+            &value.to_string(),
+            &[("root", vec![1, 2, 3, 4]), ("data", vec![5, 6, 7, 8])],
+        );
+        assert_eq!(params.yubikey_auth.otp_slot, Slot::Two);
+    }
+
+    #[test]
+    fn test_unversioned_v2_is_rejected() {
+        let value = serde_json::to_value(make_v2()).unwrap();
+        assert!(schema_version(&value).is_err());
+    }
+
+    #[test]
+    fn test_future_version_is_rejected() {
+        let mut value = serde_json::to_value(make_v2()).unwrap();
+        value["version"] = (CURRENT_VERSION + 1).into();
+        assert!(serde_json::from_value::<EncryptionParams>(value).is_err());
+    }
+}
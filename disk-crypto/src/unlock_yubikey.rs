@@ -6,11 +6,13 @@ use sha2::Sha256;
 use crate::{
     keyfile::KeyEncryptionKey,
     params::{YubikeyAuthParams, YubikeyAuthSlot},
+    yubikey::Slot,
 };
 
 impl YubikeyAuthParams {
     pub fn new_with_slots<F>(
         how_many: usize,
+        otp_slot: Slot,
         pin: SecretString,
         mut chalresp: F,
         kek: &KeyEncryptionKey,
@@ -23,7 +25,7 @@ impl YubikeyAuthParams {
             let slot = YubikeyAuthSlot::new(&pin, &mut chalresp, kek);
             slots.push(slot);
         }
-        Self { slots }
+        Self { slots, otp_slot }
     }
 
     pub fn decrypt<F>(&self, pin: SecretString, mut chalresp: F) -> Result<KeyEncryptionKey, ()>
@@ -132,7 +134,7 @@ impl YubikeyAuthSlot {
 mod test {
     use secrecy::{ExposeSecret, Secret};
 
-    use crate::{keyfile::KeyEncryptionKey, params::YubikeyAuthParams};
+    use crate::{keyfile::KeyEncryptionKey, params::YubikeyAuthParams, yubikey::Slot};
 
     #[test]
     fn test_yubikey_round_trip() {
@@ -155,8 +157,13 @@ mod test {
 
         let pin = String::from("1234");
 
-        let params =
-            YubikeyAuthParams::new_with_slots(10, Secret::new(pin.clone()), mock_chalresp, &kek);
+        let params = YubikeyAuthParams::new_with_slots(
+            10,
+            Slot::Two,
+            Secret::new(pin.clone()),
+            mock_chalresp,
+            &kek,
+        );
 
         // ...
 
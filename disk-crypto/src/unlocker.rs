@@ -0,0 +1,186 @@
+use secrecy::SecretString;
+
+use crate::{keyfile::KeyEncryptionKey, params::EncryptionParams, yubikey};
+
+/// What a login prompt asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputKind {
+    /// Something typed in, which is hidden while typing.
+    Secret,
+
+    /// Nothing: the method only needs the user to confirm, and is tried with an empty input.
+    None,
+}
+
+/// How the login prompt for an unlocker looks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Prompt {
+    pub input: InputKind,
+
+    /// The title of the prompt, like "Please enter password to continue...".
+    pub title: &'static str,
+
+    /// Shown while the input is being checked.
+    pub verifying: &'static str,
+
+    /// Shown when the input didn't unlock the KEK.
+    pub failed: &'static str,
+}
+
+/// A way of getting the KEK back from what the user provides.
+/// The boot menu builds its login prompts from the list of these,
+/// and shows the prompt of the first one that is available.
+pub trait Unlocker: Send + Sync {
+    /// A name that is unique among the unlockers. The boot menu uses it to tell which prompt is shown.
+    fn name(&self) -> &'static str;
+
+    fn prompt(&self) -> Prompt;
+
+    /// Whether this method can be used right now, like whether its device is plugged in.
+    /// This is checked several times a second, so it should be quick.
+    fn is_available(&self) -> bool;
+
+    /// Recover the KEK from the config with the user's input, or say why that didn't work.
+    /// `notify` shows a message while this is running, like asking to touch the Yubikey.
+    fn recover_kek(
+        &self,
+        params: &EncryptionParams,
+        input: SecretString,
+        notify: &dyn Fn(&'static str),
+    ) -> Result<KeyEncryptionKey, String>;
+}
+
+/// The unlockers, most preferred first. The last one is always available, so there's always a prompt to show.
+pub fn registered_unlockers() -> Vec<Box<dyn Unlocker>> {
+    vec![Box::new(YubikeyUnlocker), Box::new(PasswordUnlocker)]
+}
+
+/// Unlocking with the password.
+pub struct PasswordUnlocker;
+
+impl Unlocker for PasswordUnlocker {
+    fn name(&self) -> &'static str {
+        "password"
+    }
+
+    fn prompt(&self) -> Prompt {
+        Prompt {
+            input: InputKind::Secret,
+            title: "Please enter password to continue...",
+            verifying: "Verifying password...",
+            failed: "Failed to unlock with password",
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn recover_kek(
+        &self,
+        params: &EncryptionParams,
+        input: SecretString,
+        _notify: &dyn Fn(&'static str),
+    ) -> Result<KeyEncryptionKey, String> {
+        params
+            .password_auth
+            .decrypt(input)
+            .map_err(|_| "Wrong password".to_string())
+    }
+}
+
+/// Unlocking with the Yubikey's challenge-response and a PIN.
+pub struct YubikeyUnlocker;
+
+impl Unlocker for YubikeyUnlocker {
+    fn name(&self) -> &'static str {
+        "yubikey"
+    }
+
+    fn prompt(&self) -> Prompt {
+        Prompt {
+            input: InputKind::Secret,
+            title: "Please enter PIN to continue...",
+            verifying: "Verifying PIN code...",
+            failed: "Failed to unlock with Yubikey",
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        yubikey::find_device().is_some()
+    }
+
+    fn recover_kek(
+        &self,
+        params: &EncryptionParams,
+        input: SecretString,
+        notify: &dyn Fn(&'static str),
+    ) -> Result<KeyEncryptionKey, String> {
+        if params.yubikey_auth.slots.is_empty() {
+            return Err("No Yubikey was enrolled in the encryption config".to_string());
+        }
+        let slot = params.yubikey_auth.otp_slot;
+        // The challenge-response is the only part that can fail for a reason other than a wrong PIN.
+        let mut chalresp_error = None;
+        let chalresp = |data: [u8; 32]| {
+            yubikey::hmac_challenge_response(slot, &data, &mut || {
+                notify("Touch your Yubikey to continue...")
+            })
+            .map_err(|why| chalresp_error = Some(why))
+            .ok()
+        };
+        params.yubikey_auth.decrypt(input, chalresp).map_err(|_| {
+            chalresp_error.unwrap_or_else(|| "Wrong PIN, or a different Yubikey".to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use secrecy::{ExposeSecret, Secret};
+
+    use crate::{
+        keyfile::KeyEncryptionKey,
+        params::{EncryptionParams, PasswordAuthParameters, YubikeyAuthParams},
+        yubikey::Slot,
+    };
+
+    use super::{registered_unlockers, PasswordUnlocker, Unlocker};
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    fn make_params() -> (EncryptionParams, KeyEncryptionKey) {
+        let kek = KeyEncryptionKey::generate();
+        let params = EncryptionParams {
+            volumes: vec![],
+            password_auth: PasswordAuthParameters::new(Secret::new(PASSWORD.to_string()), &kek),
+            yubikey_auth: YubikeyAuthParams {
+                slots: vec![],
+                otp_slot: Slot::One,
+            },
+        };
+        (params, kek)
+    }
+
+    #[test]
+    fn test_password_unlocker() {
+        let (params, kek) = make_params();
+        let recovered = PasswordUnlocker
+            .recover_kek(&params, Secret::new(PASSWORD.to_string()), &|_| {})
+            .unwrap();
+        assert_eq!(recovered.key.expose_secret(), kek.key.expose_secret());
+
+        let wrong =
+            PasswordUnlocker.recover_kek(&params, Secret::new("wrong".to_string()), &|_| {});
+        assert_eq!(wrong.err(), Some("Wrong password".to_string()));
+    }
+
+    #[test]
+    fn test_fallback_is_last() {
+        // The boot menu shows the first available prompt, and relies on the last one always being available.
+        let unlockers = registered_unlockers();
+        let last = unlockers.last().unwrap();
+        assert_eq!(last.name(), "password");
+        assert!(last.is_available());
+    }
+}
@@ -0,0 +1,495 @@
+//! The Yubikey's HMAC-SHA1 challenge-response, spoken directly over the feature reports
+//! of its keyboard interface in `/dev/hidraw*`.
+//! This is the same protocol that `ykchalresp` speaks through libusb.
+//!
+//! A request is a 70-byte frame: 64 bytes of payload, the command, a CRC of the payload and 3 bytes of padding.
+//! It is sent as 10 feature reports of 7 bytes each, plus a byte with the sequence number.
+//! The response is read back the same way, a feature report at a time.
+
+use std::{
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The USB vendor ID of Yubico.
+pub const YUBICO_VENDOR_ID: u32 = 0x1050;
+
+const HIDRAW_CLASS: &str = "/sys/class/hidraw";
+
+const FEATURE_REPORT_SIZE: usize = 8;
+const REPORT_DATA_SIZE: usize = FEATURE_REPORT_SIZE - 1;
+const SLOT_DATA_SIZE: usize = 64;
+const FRAME_SIZE: usize = SLOT_DATA_SIZE + 6;
+
+/// In the last byte of a report the Yubikey sends: it is still busy with the last report that was written.
+const SLOT_WRITE_FLAG: u8 = 0x80;
+/// In the last byte of a report the Yubikey sends: this report is part of the response.
+const RESP_PENDING_FLAG: u8 = 0x40;
+/// In the last byte of a report the Yubikey sends: it is waiting for its button to be touched.
+const RESP_TIMEOUT_WAIT_FLAG: u8 = 0x20;
+const SEQUENCE_MASK: u8 = 0x1f;
+/// Written to tell the Yubikey that the response has been read.
+const DUMMY_REPORT_WRITE: u8 = 0x8f;
+
+/// The CRC of a response followed by its CRC, if it arrived intact.
+const CRC_OK_RESIDUAL: u16 = 0xf0b8;
+const HMAC_RESPONSE_SIZE: usize = 20;
+
+/// How long to wait for the Yubikey to become ready to receive a report.
+const WRITE_READY_ATTEMPTS: usize = 20;
+/// How long to wait for the response. The Yubikey itself gives up waiting for a touch after 15 seconds.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// `HIDIOCSFEATURE` and `HIDIOCGFEATURE` from `linux/hidraw.h`, for a report number and a feature report.
+const HIDIOCSFEATURE: usize = 0xc000_4806 | ((FEATURE_REPORT_SIZE + 1) << 16);
+const HIDIOCGFEATURE: usize = 0xc000_4807 | ((FEATURE_REPORT_SIZE + 1) << 16);
+
+/// Only one challenge is sent at a time, from every thread in the process,
+/// because the Yubikey can't interleave two of them.
+static DEVICE_LOCK: Mutex<()> = Mutex::new(());
+
+/// One of the two OTP slots of the Yubikey. The one used for unlocking must be configured for HMAC-SHA1 challenge-response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Slot {
+    #[default]
+    One,
+    Two,
+}
+
+impl Slot {
+    /// The command that sends an HMAC-SHA1 challenge to this slot.
+    fn hmac_command(self) -> u8 {
+        match self {
+            Slot::One => 0x30,
+            Slot::Two => 0x38,
+        }
+    }
+}
+
+impl TryFrom<u8> for Slot {
+    type Error = String;
+
+    fn try_from(number: u8) -> Result<Self, String> {
+        match number {
+            1 => Ok(Slot::One),
+            2 => Ok(Slot::Two),
+            other => Err(format!("Yubikey slot should be 1 or 2, not {other}")),
+        }
+    }
+}
+
+impl From<Slot> for u8 {
+    fn from(slot: Slot) -> u8 {
+        match slot {
+            Slot::One => 1,
+            Slot::Two => 2,
+        }
+    }
+}
+
+/// Something that feature reports can be exchanged with: the hidraw device, or a mock of it in tests.
+pub trait HidTransport {
+    fn get_feature(&mut self) -> Result<[u8; FEATURE_REPORT_SIZE], String>;
+    fn set_feature(&mut self, report: &[u8; FEATURE_REPORT_SIZE]) -> Result<(), String>;
+}
+
+/// The keyboard interface of a Yubikey, opened through hidraw.
+pub struct Hidraw {
+    file: File,
+}
+
+impl Hidraw {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|why| format!("Failed to open {}: {why}", path.display()))?;
+        Ok(Self { file })
+    }
+}
+
+impl HidTransport for Hidraw {
+    fn get_feature(&mut self) -> Result<[u8; FEATURE_REPORT_SIZE], String> {
+        // The first byte is the report number, which is 0 because the Yubikey doesn't number its reports.
+        let mut buf = [0u8; FEATURE_REPORT_SIZE + 1];
+        unsafe {
+            syscalls::syscall!(
+                syscalls::Sysno::ioctl,
+                self.file.as_raw_fd() as usize,
+                HIDIOCGFEATURE,
+                buf.as_mut_ptr() as usize
+            )
+        }
+        .map_err(|why| format!("Failed to read a feature report from the Yubikey: {why}"))?;
+        Ok(buf[1..].try_into().unwrap())
+    }
+
+    fn set_feature(&mut self, report: &[u8; FEATURE_REPORT_SIZE]) -> Result<(), String> {
+        let mut buf = [0u8; FEATURE_REPORT_SIZE + 1];
+        buf[1..].copy_from_slice(report);
+        unsafe {
+            syscalls::syscall!(
+                syscalls::Sysno::ioctl,
+                self.file.as_raw_fd() as usize,
+                HIDIOCSFEATURE,
+                buf.as_ptr() as usize
+            )
+        }
+        .map_err(|why| format!("Failed to write a feature report to the Yubikey: {why}"))?;
+        Ok(())
+    }
+}
+
+/// The CRC-16 that the Yubikey uses, which is the one from ISO 13239 without the final inversion.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            let carry = crc & 1;
+            crc >>= 1;
+            if carry == 1 {
+                crc ^= 0x8408;
+            }
+        }
+    }
+    crc
+}
+
+/// Build a request frame. Challenges shorter than 64 bytes are padded with zeros, the same way `ykchalresp` does.
+fn frame(command: u8, payload: &[u8]) -> [u8; FRAME_SIZE] {
+    let mut frame = [0u8; FRAME_SIZE];
+    frame[..payload.len()].copy_from_slice(payload);
+    let crc = crc16(&frame[..SLOT_DATA_SIZE]);
+    frame[SLOT_DATA_SIZE] = command;
+    frame[SLOT_DATA_SIZE + 1..SLOT_DATA_SIZE + 3].copy_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+fn wait_ready_to_write(transport: &mut dyn HidTransport) -> Result<(), String> {
+    for _ in 0..WRITE_READY_ATTEMPTS {
+        if transport.get_feature()?[REPORT_DATA_SIZE] & SLOT_WRITE_FLAG == 0 {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Err("Timed out waiting for the Yubikey to become ready".to_string())
+}
+
+fn send_frame(transport: &mut dyn HidTransport, frame: &[u8; FRAME_SIZE]) -> Result<(), String> {
+    let last = FRAME_SIZE / REPORT_DATA_SIZE - 1;
+    for (seq, chunk) in frame.chunks(REPORT_DATA_SIZE).enumerate() {
+        // Reports that are all zeros can be left out, except for the first and the last one.
+        if seq != 0 && seq != last && chunk.iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        let mut report = [0u8; FEATURE_REPORT_SIZE];
+        report[..REPORT_DATA_SIZE].copy_from_slice(chunk);
+        report[REPORT_DATA_SIZE] = SLOT_WRITE_FLAG | seq as u8;
+        wait_ready_to_write(transport)?;
+        transport.set_feature(&report)?;
+    }
+    Ok(())
+}
+
+/// Tell the Yubikey that the response has been read, so that it goes back to waiting for a request.
+fn reset_state(transport: &mut dyn HidTransport) -> Result<(), String> {
+    let mut report = [0u8; FEATURE_REPORT_SIZE];
+    report[REPORT_DATA_SIZE] = DUMMY_REPORT_WRITE;
+    transport.set_feature(&report)
+}
+
+/// Read the response to the frame that was just sent.
+/// `on_touch` is called once if the Yubikey starts waiting for its button to be touched.
+fn read_response(
+    transport: &mut dyn HidTransport,
+    on_touch: &mut dyn FnMut(),
+) -> Result<Vec<u8>, String> {
+    let started = Instant::now();
+    let mut response = vec![];
+    let mut seq = 0;
+    let mut touch_pending = false;
+    loop {
+        let report = transport.get_feature()?;
+        let status = report[REPORT_DATA_SIZE];
+        if status & RESP_PENDING_FLAG != 0 {
+            if status & SEQUENCE_MASK == seq {
+                response.extend_from_slice(&report[..REPORT_DATA_SIZE]);
+                seq += 1;
+            } else if status & SEQUENCE_MASK == 0 {
+                // The sequence started over, so all of the response has been read.
+                reset_state(transport)?;
+                return Ok(response);
+            }
+        } else if status == 0 {
+            // The Yubikey is idle without having responded.
+            if response.is_empty() {
+                return Err("The Yubikey rejected the challenge; is the slot configured for challenge-response?".to_string());
+            }
+            return Err("The Yubikey's response was cut short".to_string());
+        } else {
+            if started.elapsed() > RESPONSE_TIMEOUT {
+                reset_state(transport)?;
+                return Err("Timed out waiting for the Yubikey to respond".to_string());
+            }
+            if status & RESP_TIMEOUT_WAIT_FLAG != 0 {
+                if !touch_pending {
+                    touch_pending = true;
+                    on_touch();
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            } else {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
+
+/// Send an HMAC-SHA1 challenge to a slot, and return the response.
+pub fn challenge_response(
+    transport: &mut dyn HidTransport,
+    slot: Slot,
+    challenge: &[u8],
+    on_touch: &mut dyn FnMut(),
+) -> Result<[u8; HMAC_RESPONSE_SIZE], String> {
+    if challenge.len() > SLOT_DATA_SIZE {
+        return Err(format!(
+            "The challenge is {} bytes, but the Yubikey takes at most {SLOT_DATA_SIZE}",
+            challenge.len()
+        ));
+    }
+    send_frame(transport, &frame(slot.hmac_command(), challenge))?;
+    let response = read_response(transport, on_touch)?;
+    // The response is followed by its CRC, inverted.
+    let Some(checked) = response.get(..HMAC_RESPONSE_SIZE + 2) else {
+        return Err(format!(
+            "The Yubikey's response is {} bytes, which is too short",
+            response.len()
+        ));
+    };
+    if crc16(checked) != CRC_OK_RESIDUAL {
+        return Err("The Yubikey's response has a wrong CRC".to_string());
+    }
+    Ok(checked[..HMAC_RESPONSE_SIZE].try_into().unwrap())
+}
+
+/// Read the vendor and product IDs from the `HID_ID=0003:00001050:00000407` line of a hidraw device's uevent.
+pub fn parse_hid_id(uevent: &str) -> Option<(u32, u32)> {
+    let id = uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("HID_ID="))?;
+    let mut parts = id.split(':').skip(1);
+    let vendor = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let product = u32::from_str_radix(parts.next()?, 16).ok()?;
+    Some((vendor, product))
+}
+
+/// Whether a report descriptor is for a keyboard, which is the interface that the OTP slots are behind.
+/// The Yubikey's other interfaces, like FIDO, don't answer these requests.
+pub fn is_keyboard_descriptor(descriptor: &[u8]) -> bool {
+    // Usage Page (Generic Desktop), Usage (Keyboard)
+    descriptor.starts_with(&[0x05, 0x01, 0x09, 0x06])
+}
+
+/// Find the hidraw device of the keyboard interface of the first Yubikey that is plugged in.
+pub fn find_device() -> Option<PathBuf> {
+    let mut entries: Vec<_> = std::fs::read_dir(HIDRAW_CLASS)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+    entries.into_iter().find_map(|entry| {
+        let uevent = std::fs::read_to_string(entry.join("device/uevent")).ok()?;
+        let (vendor, _) = parse_hid_id(&uevent)?;
+        let descriptor = std::fs::read(entry.join("device/report_descriptor")).ok()?;
+        if vendor != YUBICO_VENDOR_ID || !is_keyboard_descriptor(&descriptor) {
+            return None;
+        }
+        Some(Path::new("/dev").join(entry.file_name()?))
+    })
+}
+
+/// Send an HMAC-SHA1 challenge to the first Yubikey that is plugged in.
+pub fn hmac_challenge_response(
+    slot: Slot,
+    challenge: &[u8],
+    on_touch: &mut dyn FnMut(),
+) -> Result<[u8; HMAC_RESPONSE_SIZE], String> {
+    let _device = DEVICE_LOCK.lock().unwrap();
+    let path = find_device().ok_or("No Yubikey is plugged in")?;
+    let mut hidraw = Hidraw::open(&path)?;
+    challenge_response(&mut hidraw, slot, challenge, on_touch)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::{
+        challenge_response, crc16, is_keyboard_descriptor, parse_hid_id, HidTransport, Slot,
+        CRC_OK_RESIDUAL, DUMMY_REPORT_WRITE, FEATURE_REPORT_SIZE, FRAME_SIZE, HMAC_RESPONSE_SIZE,
+        REPORT_DATA_SIZE, RESP_PENDING_FLAG, RESP_TIMEOUT_WAIT_FLAG, SEQUENCE_MASK, SLOT_DATA_SIZE,
+        SLOT_WRITE_FLAG,
+    };
+
+    /// The device side of the protocol, answering with a made-up response instead of an HMAC.
+    struct MockYubikey {
+        /// The slot that is configured for challenge-response.
+        configured: Slot,
+        /// How many times to report waiting for a touch before responding.
+        touch_polls: usize,
+        request: [u8; FRAME_SIZE],
+        response: VecDeque<[u8; FEATURE_REPORT_SIZE]>,
+        reset: bool,
+    }
+
+    impl MockYubikey {
+        fn new(configured: Slot, touch_polls: usize) -> Self {
+            Self {
+                configured,
+                touch_polls,
+                request: [0; FRAME_SIZE],
+                response: VecDeque::new(),
+                reset: false,
+            }
+        }
+
+        fn fake_hmac(challenge: &[u8]) -> [u8; HMAC_RESPONSE_SIZE] {
+            let mut response = [0; HMAC_RESPONSE_SIZE];
+            for (i, byte) in response.iter_mut().enumerate() {
+                *byte = !challenge[i];
+            }
+            response
+        }
+
+        fn respond(&mut self) {
+            let payload = &self.request[..SLOT_DATA_SIZE];
+            let crc = u16::from_le_bytes([
+                self.request[SLOT_DATA_SIZE + 1],
+                self.request[SLOT_DATA_SIZE + 2],
+            ]);
+            assert_eq!(crc16(payload), crc);
+            if self.request[SLOT_DATA_SIZE] != self.configured.hmac_command() {
+                return;
+            }
+
+            let mut data = Self::fake_hmac(payload).to_vec();
+            data.extend_from_slice(&(!crc16(&data)).to_le_bytes());
+            for (seq, chunk) in data.chunks(REPORT_DATA_SIZE).enumerate() {
+                let mut report = [0; FEATURE_REPORT_SIZE];
+                report[..chunk.len()].copy_from_slice(chunk);
+                report[REPORT_DATA_SIZE] = RESP_PENDING_FLAG | seq as u8;
+                self.response.push_back(report);
+            }
+            let mut done = [0; FEATURE_REPORT_SIZE];
+            done[REPORT_DATA_SIZE] = RESP_PENDING_FLAG;
+            self.response.push_back(done);
+        }
+    }
+
+    impl HidTransport for MockYubikey {
+        fn get_feature(&mut self) -> Result<[u8; FEATURE_REPORT_SIZE], String> {
+            let mut report = [0; FEATURE_REPORT_SIZE];
+            if self.touch_polls > 0 && !self.response.is_empty() {
+                self.touch_polls -= 1;
+                report[REPORT_DATA_SIZE] = RESP_TIMEOUT_WAIT_FLAG;
+            } else if let Some(next) = self.response.front() {
+                report = *next;
+                // The last report is repeated until the reset.
+                if self.response.len() > 1 {
+                    self.response.pop_front();
+                }
+            }
+            Ok(report)
+        }
+
+        fn set_feature(&mut self, report: &[u8; FEATURE_REPORT_SIZE]) -> Result<(), String> {
+            let status = report[REPORT_DATA_SIZE];
+            if status == DUMMY_REPORT_WRITE {
+                self.response.clear();
+                self.reset = true;
+                return Ok(());
+            }
+            assert_ne!(status & SLOT_WRITE_FLAG, 0);
+            let seq = (status & SEQUENCE_MASK) as usize;
+            if seq == 0 {
+                self.request = [0; FRAME_SIZE];
+            }
+            let start = seq * REPORT_DATA_SIZE;
+            self.request[start..start + REPORT_DATA_SIZE]
+                .copy_from_slice(&report[..REPORT_DATA_SIZE]);
+            if seq == FRAME_SIZE / REPORT_DATA_SIZE - 1 {
+                self.respond();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_crc16() {
+        let data = b"123456789";
+        let mut checked = data.to_vec();
+        checked.extend_from_slice(&(!crc16(data)).to_le_bytes());
+        assert_eq!(crc16(&checked), CRC_OK_RESIDUAL);
+    }
+
+    #[test]
+    fn test_challenge_response() {
+        let challenge: Vec<u8> = (1..=32).collect();
+        for slot in [Slot::One, Slot::Two] {
+            let mut yubikey = MockYubikey::new(slot, 0);
+            let mut touched = false;
+            let response =
+                challenge_response(&mut yubikey, slot, &challenge, &mut || touched = true).unwrap();
+            assert_eq!(response, MockYubikey::fake_hmac(&challenge));
+            assert!(!touched);
+            assert!(yubikey.reset);
+        }
+    }
+
+    #[test]
+    fn test_touch_pending() {
+        let challenge = [0xaa; 32];
+        let mut yubikey = MockYubikey::new(Slot::Two, 3);
+        let mut touches = 0;
+        let response =
+            challenge_response(&mut yubikey, Slot::Two, &challenge, &mut || touches += 1).unwrap();
+        assert_eq!(response, MockYubikey::fake_hmac(&challenge));
+        assert_eq!(touches, 1);
+    }
+
+    #[test]
+    fn test_unconfigured_slot() {
+        let mut yubikey = MockYubikey::new(Slot::One, 0);
+        assert!(challenge_response(&mut yubikey, Slot::Two, &[1; 32], &mut || ()).is_err());
+    }
+
+    #[test]
+    fn test_slot_serde() {
+        assert_eq!(serde_json::to_string(&Slot::Two).unwrap(), "2");
+        assert_eq!(serde_json::from_str::<Slot>("1").unwrap(), Slot::One);
+        assert!(serde_json::from_str::<Slot>("3").is_err());
+    }
+
+    #[test]
+    fn test_find_device() {
+        assert_eq!(
+            parse_hid_id("DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_NAME=Yubico YubiKey OTP+FIDO+CCID\n"),
+            Some((0x1050, 0x407))
+        );
+        assert_eq!(parse_hid_id("DRIVER=hid-generic\n"), None);
+        assert!(is_keyboard_descriptor(&[
+            0x05, 0x01, 0x09, 0x06, 0xa1, 0x01
+        ]));
+        // FIDO
+        assert!(!is_keyboard_descriptor(&[0x06, 0xd0, 0xf1, 0x09, 0x01]));
+    }
+}